@@ -0,0 +1,481 @@
+use atrium_api::com::atproto;
+use atrium_api::xrpc::XrpcClient;
+use std::error::Error;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::identity;
+use crate::logging::{Action, Event};
+use crate::oauth::{self, DpopKey, OAuthSession};
+use crate::session::{self, SavedSession};
+use crate::xrpc_client::{XrpcHttpClient, XrpcReqwestClient};
+
+pub const DEFAULT_XRPC_HOST: &str = "https://bsky.social";
+
+/// The Bluesky account to post by, which is authenticated by the saved
+/// session if any, or by the password.
+pub struct Account {
+    dry_run: bool,
+    identifier: String,
+    password_opt: Option<String>,
+    /// The XRPC host given, which the PDS of the account is found otherwise
+    xrpc_host_opt: Option<String>,
+    discovered_xrpc_host: OnceLock<String>,
+    session_path_opt: Option<String>,
+    /// The session authenticated in this process, which the bridges sharing
+    /// the account reuse
+    current_session: Arc<Mutex<Option<SavedSession>>>,
+}
+
+impl Account {
+    /// The account of the identifier, which is a handle, a DID or an email.
+    pub fn new(dry_run: bool, identifier: impl Into<String>) -> Self {
+        Self {
+            dry_run,
+            identifier: identifier.into(),
+            password_opt: None,
+            xrpc_host_opt: None,
+            discovered_xrpc_host: OnceLock::new(),
+            session_path_opt: None,
+            current_session: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The password, which is only needed when no session is saved.
+    pub fn with_password(mut self, password: impl Into<String>) -> Self {
+        self.password_opt = Some(password.into());
+        self
+    }
+
+    pub fn with_xrpc_host(mut self, xrpc_host: impl Into<String>) -> Self {
+        self.xrpc_host_opt = Some(xrpc_host.into());
+        self
+    }
+
+    /// Where the session is saved across runs, so that the password is only
+    /// sent when the session cannot be refreshed.
+    pub fn with_session_path(mut self, session_path: impl Into<String>) -> Self {
+        self.session_path_opt = Some(session_path.into());
+        self
+    }
+
+    pub fn identifier(&self) -> &str {
+        &self.identifier
+    }
+
+    /// The XRPC host given, or the PDS of the account found by its identifier,
+    /// which is found once. The default host is used when it is not found.
+    pub async fn xrpc_host(&self, reqwest_client: &reqwest::Client) -> String {
+        if let Some(xrpc_host) = self
+            .xrpc_host_opt
+            .as_ref()
+            .or(self.discovered_xrpc_host.get())
+        {
+            return xrpc_host.to_string();
+        }
+        if self.dry_run {
+            Event::info(
+                Action::DryRun,
+                format!("Dry run: find the PDS of {}", self.identifier),
+            )
+            .emit();
+            return DEFAULT_XRPC_HOST.to_string();
+        }
+        match identity::resolve_pds(reqwest_client, &self.identifier).await {
+            Ok(Some(pds)) => self.discovered_xrpc_host.get_or_init(|| pds).to_string(),
+            Ok(None) => DEFAULT_XRPC_HOST.to_string(),
+            Err(err) => {
+                Event::warn(format!(
+                    "Failed to find the PDS of {}, using {DEFAULT_XRPC_HOST}",
+                    self.identifier
+                ))
+                .error(err)
+                .emit();
+                DEFAULT_XRPC_HOST.to_string()
+            }
+        }
+    }
+
+    /// Authenticate by the saved session if any, refreshing it when the access
+    /// token expires, and create a new session only when it fails.
+    pub(crate) async fn authenticate(
+        &self,
+        client: &mut XrpcReqwestClient,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.dry_run {
+            Event::info(
+                Action::DryRun,
+                format!("Dry run: authenticate by {}", self.identifier),
+            )
+            .emit();
+            return Ok(());
+        }
+
+        // The session is refreshed by the client if it has expired since.
+        let current_opt = self.current_session.lock().unwrap().clone();
+        if let Some(current) = current_opt.filter(|current| current.host == client.host()) {
+            return self.use_session(client, current);
+        }
+
+        let saved_opt = self.session_path_opt.as_deref().and_then(|session_path| {
+            session::load_session(session_path, client.host(), &self.identifier)
+        });
+        if let Some(saved) = saved_opt {
+            match self.resume_session(client, saved).await {
+                Ok(()) => return Ok(()),
+                Err(err) => Event::warn("Failed to resume the saved session")
+                    .error(err)
+                    .emit(),
+            }
+        }
+        self.create_session(client).await
+    }
+
+    /// Create a new session by the password and save it.
+    async fn create_session(&self, client: &mut XrpcReqwestClient) -> Result<(), Box<dyn Error>> {
+        use atproto::server::create_session::{self, CreateSession};
+
+        let password = self.password_opt.as_deref().ok_or_else(|| {
+            format!(
+                "Failed to authenticate {}: not logged in, and no password is given",
+                self.identifier
+            )
+        })?;
+        let session = client
+            .create_session(create_session::Input {
+                identifier: self.identifier.to_string(),
+                password: password.to_string(),
+            })
+            .await?;
+        let saved = self.save_session(
+            client.host(),
+            &session.did,
+            &session.access_jwt,
+            &session.refresh_jwt,
+            None,
+        )?;
+        self.use_session(client, saved)
+    }
+
+    /// Use the access token of the saved session if the server accepts it, or
+    /// refresh the session by the refresh token. An OAuth session is refreshed
+    /// by its authorization server.
+    async fn resume_session(
+        &self,
+        client: &mut XrpcReqwestClient,
+        saved: SavedSession,
+    ) -> Result<(), Box<dyn Error>> {
+        use atproto::server::{get_session::GetSession, refresh_session::RefreshSession};
+
+        if saved.access_usable() {
+            self.use_session(client, saved.clone())?;
+            if client.get_session().await.is_ok() {
+                *self.current_session.lock().unwrap() = Some(saved);
+                return Ok(());
+            }
+        }
+        if !saved.refresh_usable() {
+            Err("the refresh token is expired")?;
+        }
+        let session = match saved.oauth_opt {
+            Some(oauth_session) => {
+                let tokens = oauth::refresh(
+                    client.reqwest_client(),
+                    &oauth_session,
+                    &saved.did,
+                    &saved.refresh_jwt,
+                )
+                .await?;
+                self.save_session(
+                    client.host(),
+                    &tokens.did,
+                    &tokens.access_token,
+                    &tokens.refresh_token,
+                    Some(oauth_session),
+                )?
+            }
+            None => {
+                // The refresh token is sent in place of the access token.
+                client.set_session(saved.refresh_jwt, saved.did);
+                client.set_dpop_key(None);
+                let session = client.refresh_session().await?;
+                self.save_session(
+                    client.host(),
+                    &session.did,
+                    &session.access_jwt,
+                    &session.refresh_jwt,
+                    None,
+                )?
+            }
+        };
+        self.use_session(client, session)
+    }
+
+    /// Send the requests by the session, with the DPoP proofs for an OAuth
+    /// session.
+    fn use_session(
+        &self,
+        client: &mut XrpcReqwestClient,
+        session: SavedSession,
+    ) -> Result<(), Box<dyn Error>> {
+        let dpop_key_opt = session
+            .oauth_opt
+            .as_ref()
+            .map(|oauth_session| DpopKey::from_base64(&oauth_session.dpop_key))
+            .transpose()?;
+        client.set_session(session.access_jwt, session.did.to_string());
+        client.set_dpop_key(dpop_key_opt);
+        self.set_session_refresh(client, &session.did, session.refresh_jwt, session.oauth_opt);
+        Ok(())
+    }
+
+    /// Refresh the session when the access token expires in the middle of a
+    /// run, saving the new tokens for the next run.
+    fn set_session_refresh(
+        &self,
+        client: &mut XrpcReqwestClient,
+        did: &str,
+        refresh_jwt: String,
+        oauth_opt: Option<OAuthSession>,
+    ) {
+        let host = client.host().to_string();
+        let identifier = self.identifier.to_string();
+        let did = did.to_string();
+        let session_path_opt = self.session_path_opt.clone();
+        let current_session = self.current_session.clone();
+        client.set_session_refresh(
+            refresh_jwt,
+            oauth_opt.clone(),
+            Box::new(move |access_jwt, refresh_jwt| {
+                let session = SavedSession {
+                    host: host.to_string(),
+                    identifier: identifier.to_string(),
+                    did: did.to_string(),
+                    access_jwt: access_jwt.to_string(),
+                    refresh_jwt: refresh_jwt.to_string(),
+                    oauth_opt: oauth_opt.clone(),
+                };
+                *current_session.lock().unwrap() = Some(session.clone());
+                let Some(session_path) = &session_path_opt else {
+                    return;
+                };
+                if let Err(err) = session::save_session(session_path, &session) {
+                    Event::warn(err.to_string()).emit();
+                }
+            }),
+        );
+    }
+
+    fn save_session(
+        &self,
+        host: &str,
+        did: &str,
+        access_jwt: &str,
+        refresh_jwt: &str,
+        oauth_opt: Option<OAuthSession>,
+    ) -> Result<SavedSession, Box<dyn Error>> {
+        let session = SavedSession {
+            host: host.to_string(),
+            identifier: self.identifier.to_string(),
+            did: did.to_string(),
+            access_jwt: access_jwt.to_string(),
+            refresh_jwt: refresh_jwt.to_string(),
+            oauth_opt,
+        };
+        *self.current_session.lock().unwrap() = Some(session.clone());
+        if let Some(session_path) = &self.session_path_opt {
+            session::save_session(session_path, &session)?;
+        }
+        Ok(session)
+    }
+
+    /// Create a new session by the password and save it, so that later runs
+    /// need no password.
+    pub async fn login(&self, reqwest_client: reqwest::Client) -> Result<(), Box<dyn Error>> {
+        if self.session_path_opt.is_none() {
+            Err("Failed to log in: no session path is given")?;
+        }
+        let xrpc_host = self.xrpc_host(&reqwest_client).await;
+        let mut client = XrpcReqwestClient::new(xrpc_host, reqwest_client, self.dry_run);
+        if self.dry_run {
+            Event::info(
+                Action::DryRun,
+                format!("Dry run: log in by {}", self.identifier),
+            )
+            .emit();
+            return Ok(());
+        }
+        self.create_session(&mut client).await?;
+        println!(
+            "Logged in as {} on {}",
+            client.current_did().unwrap_or(""),
+            client.host()
+        );
+        Ok(())
+    }
+
+    /// Authorize by OAuth in the browser instead of the password, and save
+    /// the session bound to a new DPoP key.
+    pub async fn login_oauth(&self, reqwest_client: reqwest::Client) -> Result<(), Box<dyn Error>> {
+        if self.session_path_opt.is_none() {
+            Err("Failed to log in: no session path is given")?;
+        }
+        if self.dry_run {
+            Event::info(
+                Action::DryRun,
+                format!("Dry run: log in by {} with OAuth", self.identifier),
+            )
+            .emit();
+            return Ok(());
+        }
+        let (did, pds) = identity::resolve_identity(&reqwest_client, &self.identifier)
+            .await?
+            .ok_or_else(|| {
+                format!(
+                    "Failed to log in with OAuth: {} is not a handle or a DID",
+                    self.identifier
+                )
+            })?;
+        let xrpc_host = match &self.xrpc_host_opt {
+            Some(xrpc_host) => xrpc_host.to_string(),
+            None => self.discovered_xrpc_host.get_or_init(|| pds).to_string(),
+        };
+        let (oauth_session, tokens) =
+            oauth::authorize(&reqwest_client, &did, &self.identifier, &xrpc_host).await?;
+        self.save_session(
+            &xrpc_host,
+            &tokens.did,
+            &tokens.access_token,
+            &tokens.refresh_token,
+            Some(oauth_session),
+        )?;
+        println!("Logged in as {did} on {xrpc_host} with OAuth");
+        Ok(())
+    }
+
+    /// Authenticate as `authenticate` and print whom the session is of, to
+    /// check the credentials without posting.
+    pub async fn whoami(&self, reqwest_client: reqwest::Client) -> Result<(), Box<dyn Error>> {
+        use atproto::repo::describe_repo;
+        use atproto::server::get_session::GetSession;
+
+        /// `describe_repo::Output` without the DID document, which atrium-api
+        /// fails to parse.
+        #[derive(serde::Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct RepoDescription {
+            handle_is_correct: bool,
+            collections: Vec<String>,
+        }
+
+        let xrpc_host = self.xrpc_host(&reqwest_client).await;
+        if self.dry_run {
+            Event::info(
+                Action::DryRun,
+                format!("Dry run: check the authentication of {}", self.identifier),
+            )
+            .emit();
+            return Ok(());
+        }
+        let mut client = XrpcReqwestClient::new(xrpc_host, reqwest_client, self.dry_run);
+        self.authenticate(&mut client).await?;
+        let session = client
+            .get_session()
+            .await
+            .map_err(|err| format!("Failed to get session: {err}"))?;
+        let body = client
+            .send::<describe_repo::Error>(
+                atrium_api::xrpc::http::Method::GET,
+                "com.atproto.repo.describeRepo",
+                Some(serde_urlencoded::to_string(describe_repo::Parameters {
+                    repo: session.did.to_string(),
+                })?),
+                None,
+                None,
+            )
+            .await
+            .map_err(|err| format!("Failed to describe repo: {err}"))?;
+        let repo: RepoDescription = serde_json::from_slice(&body)
+            .map_err(|err| format!("Failed to describe repo: {err}"))?;
+        let auth = match self.current_session.lock().unwrap().as_ref() {
+            Some(current) if current.oauth_opt.is_some() => "OAuth",
+            _ => "password",
+        };
+
+        println!("DID: {}", session.did);
+        if repo.handle_is_correct {
+            println!("Handle: {}", session.handle);
+        } else {
+            println!("Handle: {} (not verified)", session.handle);
+        }
+        println!("PDS: {}", client.host());
+        println!("Authentication: {auth}");
+        println!("Collections: {}", repo.collections.join(", "));
+        Ok(())
+    }
+
+    /// Authenticate as `authenticate` and get the session, to check that the
+    /// session is valid. The DID of the session is returned.
+    pub async fn check_session(
+        &self,
+        reqwest_client: reqwest::Client,
+    ) -> Result<String, Box<dyn Error>> {
+        use atproto::server::get_session::GetSession;
+
+        let xrpc_host = self.xrpc_host(&reqwest_client).await;
+        let mut client = XrpcReqwestClient::new(xrpc_host, reqwest_client, self.dry_run);
+        self.authenticate(&mut client).await?;
+        let session = client
+            .get_session()
+            .await
+            .map_err(|err| format!("Failed to get session: {err}"))?;
+        Ok(session.did)
+    }
+
+    /// Revoke the saved session and remove it. The session is removed even if
+    /// it cannot be revoked, such as when it is expired already.
+    pub async fn logout(&self, reqwest_client: reqwest::Client) -> Result<(), Box<dyn Error>> {
+        use atproto::server::delete_session::DeleteSession;
+
+        let session_path = self
+            .session_path_opt
+            .as_deref()
+            .ok_or("Failed to log out: no session path is given")?;
+        let xrpc_host = self.xrpc_host(&reqwest_client).await;
+        let saved = match session::load_session(session_path, &xrpc_host, &self.identifier) {
+            Some(saved) => saved,
+            None => Err(format!(
+                "Not logged in as {} on {xrpc_host}",
+                self.identifier
+            ))?,
+        };
+        if self.dry_run {
+            Event::info(
+                Action::DryRun,
+                format!("Dry run: log out {}", self.identifier),
+            )
+            .emit();
+            return Ok(());
+        }
+
+        let revoked = match &saved.oauth_opt {
+            Some(oauth_session) => {
+                oauth::revoke(&reqwest_client, oauth_session, &saved.refresh_jwt).await
+            }
+            None => {
+                // The refresh token is sent in place of the access token.
+                let mut client =
+                    XrpcReqwestClient::new(xrpc_host.to_string(), reqwest_client, self.dry_run);
+                client.set_session(saved.refresh_jwt, saved.did);
+                client.delete_session().await
+            }
+        };
+        if let Err(err) = revoked {
+            Event::warn("Failed to revoke the session")
+                .error(err)
+                .emit();
+        }
+        session::remove_session(session_path, &xrpc_host, &self.identifier)?;
+        println!("Logged out {}", self.identifier);
+        Ok(())
+    }
+}
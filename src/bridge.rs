@@ -0,0 +1,2667 @@
+use atrium_api::app::bsky;
+use atrium_api::com::atproto;
+use atrium_api::com::atproto::repo::{delete_record, get_record};
+use chrono::{DateTime, Utc};
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::marker::Sync;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::Instrument;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::account::Account;
+use crate::content_warning::{self, CwMode, SelfLabel};
+use crate::embed::{self, BuiltEmbed, EmbedImage, ImageOptions, PostEmbed};
+use crate::hashtags;
+use crate::image_alt;
+use crate::journal::{Journal, JournalAction};
+use crate::lang_detect;
+use crate::local_only;
+use crate::logging::{Action, Event};
+use crate::media_hosts::{self, MediaHostPolicy};
+use crate::media_policy::{AnimationPolicy, LargeImagePolicy};
+use crate::mentions::{self, MentionMapping};
+use crate::post_record::{
+    self, CreatePost, CreatePostInput, CustomEmbed, GetRecordRef, PostRecord, PutPost,
+    PutPostInput, RecordRef, SelfLabels,
+};
+use crate::rate_limit;
+use crate::redis_store::RedisStateStore;
+use crate::rewrite::{self, RewriteRule};
+use crate::richtext::{self, ContentFormat, RichText, RichTextSegment};
+use crate::rss_ext;
+use crate::s3_state::{self, RemoteDb, S3Config};
+use crate::sqlite_store::{self, SqliteStateStore};
+use crate::state_store::{
+    canonical_link, DbBackend, DbNamespace, EditPolicy, FileStateStore, FsyncPolicy, PostedRecord,
+    StateStore,
+};
+use crate::stats;
+use crate::template::{LinkPlacement, PostTemplate, TemplateValues};
+use crate::tracking_params;
+use crate::truncate::{self, TruncateStrategy};
+use crate::xrpc_client::{GetAuthorFeedJson, UploadTypedBlob, XrpcHttpClient, XrpcReqwestClient};
+
+/// The error of a run which stops before the account exceeds its daily
+/// budget of the records, leaving the rest of the items to the next run.
+#[derive(Debug)]
+pub struct Deferred {
+    pub message: String,
+}
+
+impl std::fmt::Display for Deferred {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for Deferred {}
+
+/// The stage at which a run fails, which tells the exit status.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailedStage {
+    /// The account fails to authenticate
+    Auth,
+    /// The feed fails to be fetched or parsed
+    Feed,
+    /// Another run holds the lock of the DB
+    Lock,
+    /// Some items fail after the others are posted
+    Partial,
+}
+
+/// The error of a run failing at a stage.
+#[derive(Debug)]
+pub struct RunFailure {
+    pub stage: FailedStage,
+    pub message: String,
+}
+
+impl RunFailure {
+    pub fn new(stage: FailedStage, err: impl std::fmt::Display) -> Self {
+        RunFailure {
+            stage,
+            message: err.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for RunFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for RunFailure {}
+
+/// What a run has done.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RunSummary {
+    /// The items posted or edited
+    pub posted: usize,
+}
+
+/// A bridge from a Mastodon RSS feed to a Bluesky account.
+pub struct Bridge {
+    dry_run: bool,
+    feed_url: String,
+    account: Arc<Account>,
+    /// The longest wait for the rate limit of the XRPC host
+    max_rate_limit_wait: Duration,
+    tls_config: TlsConfig,
+    db_config: DbConfig,
+    post_config: PostConfig,
+}
+
+impl Bridge {
+    pub fn builder() -> BridgeBuilder {
+        BridgeBuilder::default()
+    }
+
+    /// Fetch the feed and post the new items once.
+    pub async fn run_once(&self) -> Result<RunSummary, Box<dyn Error>> {
+        let reqwest_client = new_reqwest_client()?;
+
+        let items = fetch_items(self.dry_run, &reqwest_client, self.feed_url.to_string())
+            .await
+            .map_err(|err| RunFailure::new(FailedStage::Feed, err))?;
+
+        let mut client = self
+            .new_xrpc_client(&reqwest_client)
+            .await?
+            .with_remote_content_policy(
+                Duration::from_secs(self.post_config.media_timeout_secs),
+                self.post_config.media_retries,
+            )
+            .with_remote_content_max_bytes(self.post_config.media_max_bytes)
+            .with_remote_content_client(
+                media_hosts::new_media_client(
+                    reqwest_client_builder(),
+                    &self.post_config.media_hosts,
+                    self.post_config.media_max_redirects,
+                )?,
+                self.post_config.media_hosts.clone(),
+            );
+
+        let result = async {
+            self.account
+                .authenticate(&mut client)
+                .await
+                .map_err(|err| RunFailure::new(FailedStage::Auth, err))?;
+            post_items(
+                self.dry_run,
+                &client,
+                &self.feed_url,
+                &items,
+                &self.db_config,
+                &self.post_config,
+            )
+            .await
+        }
+        .await;
+        self.save_rate_limit(&client);
+        result
+    }
+
+    /// Delete the Bluesky posts of the items deleted from Mastodon, which are
+    /// gone from the feed and whose original links respond 404 or 410.
+    pub async fn sync_deletes(&self) -> Result<(), Box<dyn Error>> {
+        let reqwest_client = new_reqwest_client()?;
+
+        let items = fetch_items(self.dry_run, &reqwest_client, self.feed_url.to_string()).await?;
+
+        let mut client = self.new_xrpc_client(&reqwest_client).await?;
+
+        let result = async {
+            self.account.authenticate(&mut client).await?;
+            sync_deleted_items(
+                self.dry_run,
+                &client,
+                &reqwest_client,
+                &self.feed_url,
+                &items,
+                &self.db_config,
+            )
+            .await
+        }
+        .await;
+        self.save_rate_limit(&client);
+        result
+    }
+
+    /// Record the items in the feed as posted without posting them, so that a
+    /// new deployment posts only the items after now.
+    pub async fn mark_posted(&self) -> Result<(), Box<dyn Error>> {
+        let reqwest_client = new_reqwest_client()?;
+
+        let items = fetch_items(self.dry_run, &reqwest_client, self.feed_url.to_string()).await?;
+
+        let mut client = self.new_xrpc_client(&reqwest_client).await?;
+        // The DID namespaces the links by the account.
+        if self.db_config.namespace_opt == Some(DbNamespace::Account) {
+            let result = self.account.authenticate(&mut client).await;
+            self.save_rate_limit(&client);
+            result?;
+        }
+
+        mark_items_posted(
+            self.dry_run,
+            client.current_did(),
+            &self.feed_url,
+            &items,
+            &self.db_config,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// The XRPC client of the PDS of the account, which gets remote contents
+    /// by the given client.
+    async fn new_xrpc_client(
+        &self,
+        reqwest_client: &reqwest::Client,
+    ) -> Result<XrpcReqwestClient, Box<dyn Error>> {
+        let xrpc_host = self.account.xrpc_host(reqwest_client).await;
+        let client = XrpcReqwestClient::new(
+            xrpc_host,
+            new_xrpc_reqwest_client(&self.tls_config)?,
+            self.dry_run,
+        )
+        .with_remote_content_client(reqwest_client.clone(), Default::default())
+        .with_retries(self.post_config.xrpc_retries)
+        .with_rate_limit(
+            self.max_rate_limit_wait,
+            rate_limit::load(&self.db_config.rate_limit_path),
+        );
+        Ok(client)
+    }
+
+    /// Save the budget of the requests left, for the next run.
+    fn save_rate_limit(&self, client: &XrpcReqwestClient) {
+        if let Some(rate_limit) = client.rate_limit() {
+            if let Err(err) = rate_limit::save(&self.db_config.rate_limit_path, &rate_limit) {
+                Event::warn(err.to_string()).feed(&self.feed_url).emit();
+            }
+        }
+    }
+
+    /// Run the bridge every interval. Failed runs are logged and retried at the
+    /// next interval.
+    pub async fn run_forever(&self, interval: Duration) {
+        loop {
+            if let Err(err) = self.run_once().await {
+                Event::failed("Failed to run the bridge")
+                    .feed(&self.feed_url)
+                    .error(err)
+                    .emit();
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+/// Build a `Bridge`. The feed URL, the credentials and the state store are
+/// required, and the rest defaults to the defaults of the command line.
+#[derive(Default)]
+pub struct BridgeBuilder {
+    dry_run: bool,
+    feed_url: Option<String>,
+    xrpc_host: Option<String>,
+    identifier: Option<String>,
+    password: Option<String>,
+    account: Option<Arc<Account>>,
+    session_path: Option<String>,
+    max_rate_limit_wait: Option<Duration>,
+    tls_config: TlsConfig,
+    db_config: Option<DbConfig>,
+    post_config: Option<PostConfig>,
+}
+
+impl BridgeBuilder {
+    /// Do not fetch the feed and post anything, only log what would be done.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// The RSS feed of the Mastodon account.
+    pub fn feed_url(mut self, feed_url: impl Into<String>) -> Self {
+        self.feed_url = Some(feed_url.into());
+        self
+    }
+
+    /// The XRPC host of the Bluesky account, which defaults to the PDS found by
+    /// the identifier of the account.
+    pub fn xrpc_host(mut self, xrpc_host: impl Into<String>) -> Self {
+        self.xrpc_host = Some(xrpc_host.into());
+        self
+    }
+
+    /// The identifier and the password of the Bluesky account.
+    pub fn credentials(
+        mut self,
+        identifier: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.identifier = Some(identifier.into());
+        self.password = Some(password.into());
+        self
+    }
+
+    /// The identifier of the Bluesky account without the password, which is
+    /// authenticated by the session saved by `login`.
+    pub fn identifier(mut self, identifier: impl Into<String>) -> Self {
+        self.identifier = Some(identifier.into());
+        self
+    }
+
+    /// Where the session is saved across runs, so that the password is only
+    /// sent when the session cannot be refreshed.
+    pub fn session_path(mut self, session_path: impl Into<String>) -> Self {
+        self.session_path = Some(session_path.into());
+        self
+    }
+
+    /// The longest wait for the rate limit of the XRPC host, over which the
+    /// request fails. It defaults to 5 minutes.
+    pub fn max_rate_limit_wait(mut self, max_wait: Duration) -> Self {
+        self.max_rate_limit_wait = Some(max_wait);
+        self
+    }
+
+    /// The TLS options of the connections to the PDS, for the PDS behind an
+    /// internal PKI or mutual TLS.
+    pub fn tls_config(mut self, tls_config: TlsConfig) -> Self {
+        self.tls_config = tls_config;
+        self
+    }
+
+    /// Where the posted links are saved.
+    pub fn state_store(mut self, db_config: DbConfig) -> Self {
+        self.db_config = Some(db_config);
+        self
+    }
+
+    /// The account shared with other bridges, which authenticate once for
+    /// all of them. The credentials, the XRPC host and the session path are
+    /// not used then.
+    pub fn account(mut self, account: Arc<Account>) -> Self {
+        self.account = Some(account);
+        self
+    }
+
+    /// How items are composed into posts.
+    pub fn post_config(mut self, post_config: PostConfig) -> Self {
+        self.post_config = Some(post_config);
+        self
+    }
+
+    pub fn build(self) -> Result<Bridge, Box<dyn Error>> {
+        let feed_url = match self.feed_url {
+            Some(feed_url) => feed_url,
+            None => Err("The feed URL is not given.")?,
+        };
+        let account = match (self.account, self.identifier) {
+            (Some(account), _) => account,
+            (None, Some(identifier)) => {
+                let mut account = Account::new(self.dry_run, identifier);
+                if let Some(password) = self.password {
+                    account = account.with_password(password);
+                }
+                if let Some(xrpc_host) = self.xrpc_host {
+                    account = account.with_xrpc_host(xrpc_host);
+                }
+                if let Some(session_path) = self.session_path {
+                    account = account.with_session_path(session_path);
+                }
+                Arc::new(account)
+            }
+            (None, None) => Err("The credentials are not given.")?,
+        };
+        let db_config = match self.db_config {
+            Some(db_config) => db_config,
+            None => Err("The state store is not given.")?,
+        };
+        let post_config = self.post_config.unwrap_or_default();
+        post_config.validate()?;
+
+        Ok(Bridge {
+            dry_run: self.dry_run,
+            feed_url,
+            account,
+            max_rate_limit_wait: self.max_rate_limit_wait.unwrap_or(Duration::from_secs(300)),
+            tls_config: self.tls_config,
+            db_config,
+            post_config,
+        })
+    }
+}
+
+pub struct DbConfig {
+    /// The DB file, which the runs lock by the lock file beside it
+    pub db_path: String,
+    pub stats_path: String,
+    /// The append-only journal of the actions of runs
+    pub journal_path: String,
+    /// The budget of the requests left to the XRPC host by the last run
+    pub rate_limit_path: String,
+    pub min_save_posts: usize,
+    pub retention_days_opt: Option<u32>,
+    pub fsync_policy: FsyncPolicy,
+    /// Keep the links of each feed or account apart in a shared DB
+    pub namespace_opt: Option<DbNamespace>,
+    /// The age of the remote lock of the S3 backend after which it is stale
+    pub stale_lock_secs: u64,
+    pub backend: DbBackend,
+    /// The URL of the Redis server for the Redis backend
+    pub redis_url_opt: Option<String>,
+    /// The object storage of the S3 backend
+    pub s3_opt: Option<S3Config>,
+}
+
+impl DbConfig {
+    /// The config with the DB file, saving the stats next to the DB.
+    pub fn new(db_path: impl Into<String>) -> Self {
+        let db_path = db_path.into();
+        Self {
+            stats_path: format!("{db_path}.stats"),
+            journal_path: format!("{db_path}.journal"),
+            rate_limit_path: format!("{db_path}.rate-limit"),
+            db_path,
+            min_save_posts: 50,
+            retention_days_opt: None,
+            fsync_policy: FsyncPolicy::PerPost,
+            namespace_opt: None,
+            stale_lock_secs: 3600,
+            backend: DbBackend::File,
+            redis_url_opt: None,
+            s3_opt: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PostConfig {
+    pub original_link_prefix: String,
+    pub post_text_limit: usize,
+    pub truncate_strategy: TruncateStrategy,
+    pub preserve_first_link: bool,
+    pub thread_long_posts: bool,
+    pub blog_mode: bool,
+    pub max_images: usize,
+    pub image_max_dimension: u32,
+    pub strip_exif: bool,
+    pub upload_concurrency: usize,
+    pub large_image_threshold: usize,
+    pub large_image_policy: LargeImagePolicy,
+    pub animation_policy: AnimationPolicy,
+    pub media_timeout_secs: u64,
+    pub media_retries: u32,
+    /// The retries of XRPC requests failed by a server error or the connection
+    pub xrpc_retries: u32,
+    pub media_max_bytes: usize,
+    pub media_hosts: MediaHostPolicy,
+    pub media_max_redirects: usize,
+    pub post_without_failed_media: bool,
+    pub video_max_bytes: usize,
+    pub video_max_duration: u32,
+    pub cw_mode: CwMode,
+    pub cw_label: SelfLabel,
+    pub cw_from_title: bool,
+    pub sensitive_media_label_opt: Option<SelfLabel>,
+    pub adult_media_label_opt: Option<SelfLabel>,
+    pub post_local_only: bool,
+    pub tracking_params_opt: Option<Vec<String>>,
+    pub keep_hashtags_opt: Option<Vec<String>>,
+    pub rewrite_rules: Vec<RewriteRule>,
+    pub mention_mappings: Vec<MentionMapping>,
+    pub content_format: ContentFormat,
+    pub html_options: richtext::HtmlOptions,
+    pub link_placement: LinkPlacement,
+    pub link_preview: bool,
+    pub inline_images_opt: Option<usize>,
+    pub edit_policy: EditPolicy,
+    /// Skip items whose normalized text is posted in these days under another link
+    pub content_dedup_days_opt: Option<u32>,
+    /// The most items posted when the DB is empty, against posting the whole history
+    pub initial_max: usize,
+    pub allow_initial_backfill: bool,
+    /// Look up the links of new items in these recent posts of the account,
+    /// which are posted by a previous installation whose DB is lost
+    pub remote_dedup_posts_opt: Option<usize>,
+    /// The most records which the account creates in the last 24 hours
+    pub daily_record_budget_opt: Option<usize>,
+    pub post_langs: Vec<String>,
+    pub lang_detect_threshold_opt: Option<f64>,
+    pub fallback_langs: Vec<String>,
+    pub post_template: PostTemplate,
+}
+
+impl Default for PostConfig {
+    fn default() -> Self {
+        Self {
+            original_link_prefix: String::from("[マストドン投稿から]:"),
+            post_text_limit: 300,
+            truncate_strategy: TruncateStrategy::Hard,
+            preserve_first_link: false,
+            thread_long_posts: false,
+            blog_mode: false,
+            max_images: embed::IMAGES_LIMIT,
+            image_max_dimension: 2000,
+            strip_exif: true,
+            upload_concurrency: 4,
+            large_image_threshold: 10_000_000,
+            large_image_policy: LargeImagePolicy::Resize,
+            animation_policy: AnimationPolicy::Video,
+            media_timeout_secs: 30,
+            media_retries: 2,
+            xrpc_retries: 3,
+            media_max_bytes: 100_000_000,
+            media_hosts: Default::default(),
+            media_max_redirects: 5,
+            post_without_failed_media: false,
+            video_max_bytes: embed::VIDEO_BYTES_LIMIT,
+            video_max_duration: embed::VIDEO_DURATION_LIMIT,
+            cw_mode: CwMode::Prepend,
+            cw_label: SelfLabel::GraphicMedia,
+            cw_from_title: false,
+            sensitive_media_label_opt: None,
+            adult_media_label_opt: None,
+            post_local_only: false,
+            tracking_params_opt: None,
+            keep_hashtags_opt: None,
+            rewrite_rules: vec![],
+            mention_mappings: vec![],
+            content_format: ContentFormat::Html,
+            html_options: Default::default(),
+            link_placement: LinkPlacement::Append,
+            link_preview: false,
+            inline_images_opt: None,
+            edit_policy: EditPolicy::Update,
+            content_dedup_days_opt: None,
+            initial_max: 5,
+            allow_initial_backfill: false,
+            remote_dedup_posts_opt: None,
+            daily_record_budget_opt: None,
+            post_langs: vec![],
+            lang_detect_threshold_opt: Some(0.5),
+            fallback_langs: vec![],
+            post_template: LinkPlacement::Append.default_template(),
+        }
+    }
+}
+
+impl PostConfig {
+    /// The chars left for the content, after the layout and the truncation marker.
+    /// The content is cut by chars, so the bytes left are taken as the chars of
+    /// the most bytes as well.
+    fn content_limit(&self, template_values: &TemplateValues) -> Result<usize, Box<dyn Error>> {
+        let layout = self.post_template.render(&vec![], template_values)?;
+        let reserved_count = richtext_char_count(&layout) + 3;
+        let reserved_bytes = richtext_byte_count(&layout) + 3;
+        let limit_count = self.post_text_limit.checked_sub(reserved_count).ok_or_else(|| {
+            format!(
+                "The post text limit {} is too small: the layout takes {} chars without the content, link={}",
+                self.post_text_limit, reserved_count, template_values.link,
+            )
+        })?;
+        let limit_bytes = POST_BYTES_LIMIT.checked_sub(reserved_bytes).ok_or_else(|| {
+            format!(
+                "The layout is too long: it takes {} bytes without the content, over the {} bytes of a post, link={}",
+                reserved_bytes, POST_BYTES_LIMIT, template_values.link,
+            )
+        })?;
+        Ok(limit_count.min(limit_bytes / CHAR_BYTES_MAX))
+    }
+
+    /// Check the config before fetching items.
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.post_langs.len() > POST_LANGS_LIMIT || self.fallback_langs.len() > POST_LANGS_LIMIT
+        {
+            Err(format!(
+                "Too many post languages: at most {POST_LANGS_LIMIT} are allowed."
+            ))?;
+        }
+        if self.max_images > embed::IMAGES_LIMIT {
+            Err(format!(
+                "Too many images: at most {} are allowed.",
+                embed::IMAGES_LIMIT
+            ))?;
+        }
+        if self.image_max_dimension == 0 {
+            Err("The maximum image dimension must be positive.")?;
+        }
+        if self.media_max_bytes == 0 {
+            Err("The maximum media size must be positive.")?;
+        }
+
+        if self.video_max_bytes > embed::VIDEO_BYTES_LIMIT
+            || self.video_max_duration > embed::VIDEO_DURATION_LIMIT
+        {
+            Err(format!(
+                "Too large videos: at most {} bytes and {} seconds are allowed.",
+                embed::VIDEO_BYTES_LIMIT,
+                embed::VIDEO_DURATION_LIMIT
+            ))?;
+        }
+
+        self.content_limit(&TemplateValues {
+            link: "",
+            link_prefix: &self.original_link_prefix,
+            title: "",
+            pub_date: "",
+            author: "",
+        })?;
+        Ok(())
+    }
+}
+
+/// Advertise the version to the servers.
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+fn reqwest_client_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder().user_agent(USER_AGENT)
+}
+
+pub fn new_reqwest_client() -> Result<reqwest::Client, Box<dyn Error>> {
+    let client = reqwest_client_builder()
+        .build()
+        .map_err(|err| format!("Failed to build HTTP client: {err}"))?;
+    Ok(client)
+}
+
+/// The TLS options of the connections to the PDS.
+#[derive(Clone, Default)]
+pub struct TlsConfig {
+    /// The PEM files of the CA certificates trusted in addition to the system
+    pub ca_cert_paths: Vec<String>,
+    /// The PEM file of the client certificate, which may have the key too
+    pub client_cert_path_opt: Option<String>,
+    /// The PEM file of the key of the client certificate
+    pub client_key_path_opt: Option<String>,
+}
+
+/// The HTTP client of XRPC requests, which connects to the PDS by the TLS
+/// options. Feeds and media are fetched by `new_reqwest_client` instead, so
+/// that the client certificate is only sent to the PDS.
+pub fn new_xrpc_reqwest_client(tls_config: &TlsConfig) -> Result<reqwest::Client, Box<dyn Error>> {
+    let mut builder = reqwest_client_builder();
+    for ca_cert_path in &tls_config.ca_cert_paths {
+        let pem = std::fs::read(ca_cert_path)
+            .map_err(|err| format!("Failed to read CA certificate {ca_cert_path}: {err}"))?;
+        let cert = reqwest::Certificate::from_pem(&pem)
+            .map_err(|err| format!("Failed to parse CA certificate {ca_cert_path}: {err}"))?;
+        builder = builder.add_root_certificate(cert);
+    }
+    if let Some(client_cert_path) = &tls_config.client_cert_path_opt {
+        let mut pem = std::fs::read(client_cert_path)
+            .map_err(|err| format!("Failed to read client certificate: {err}"))?;
+        if let Some(client_key_path) = &tls_config.client_key_path_opt {
+            let key_pem = std::fs::read(client_key_path)
+                .map_err(|err| format!("Failed to read client key: {err}"))?;
+            pem.push(b'\n');
+            pem.extend_from_slice(&key_pem);
+        }
+        let identity = reqwest::Identity::from_pem(&pem)
+            .map_err(|err| format!("Failed to parse client certificate: {err}"))?;
+        builder = builder.identity(identity);
+    }
+    let client = builder
+        .build()
+        .map_err(|err| format!("Failed to build HTTP client: {err}"))?;
+    Ok(client)
+}
+
+const POST_BYTES_LIMIT: usize = 3000;
+
+/// The most bytes of a char in UTF-8.
+const CHAR_BYTES_MAX: usize = 4;
+
+const POST_LANGS_LIMIT: usize = 3;
+
+/// The note of posts whose item has audio, which is in the link card.
+const AUDIO_NOTE: &str = "🎵 audio attached";
+
+async fn fetch_items(
+    dry_run: bool,
+    client: &reqwest::Client,
+    feed_url: String,
+) -> Result<Vec<rss::Item>, Box<dyn Error>> {
+    if dry_run {
+        Ok(vec![])
+    } else {
+        let channel = fetch_channel(client, feed_url).await?;
+        Ok(channel.items)
+    }
+}
+
+async fn post_items<Client>(
+    dry_run: bool,
+    client: &Client,
+    feed_url: &str,
+    items: &[rss::Item],
+    db_config: &DbConfig,
+    post_config: &PostConfig,
+) -> Result<RunSummary, Box<dyn Error>>
+where
+    Client: XrpcHttpClient
+        + CreatePost
+        + PutPost
+        + delete_record::DeleteRecord
+        + UploadTypedBlob
+        + GetAuthorFeedJson
+        + GetRecordRef
+        + Sync,
+{
+    if dry_run {
+        Event::info(Action::DryRun, "Dry run: lock and post items.")
+            .feed(feed_url)
+            .emit();
+        return Ok(RunSummary::default());
+    }
+    let _db_lock = lock_db(db_config)?;
+    let remote_db_opt = pull_remote_db(db_config, true).await?;
+    let result = post_locked_items(client, feed_url, items, db_config, post_config).await;
+    push_remote_db(remote_db_opt).await?;
+    result
+}
+
+async fn post_locked_items<Client>(
+    client: &Client,
+    feed_url: &str,
+    items: &[rss::Item],
+    db_config: &DbConfig,
+    post_config: &PostConfig,
+) -> Result<RunSummary, Box<dyn Error>>
+where
+    Client: XrpcHttpClient
+        + CreatePost
+        + PutPost
+        + delete_record::DeleteRecord
+        + UploadTypedBlob
+        + GetAuthorFeedJson
+        + GetRecordRef
+        + Sync,
+{
+    let DbConfig {
+        stats_path,
+        journal_path,
+        ..
+    } = db_config;
+
+    let mut state_store = open_run_state_store(db_config, feed_url, client.current_did())?;
+    let new_links: Vec<String> = items
+        .iter()
+        .filter_map(|item| item.link.as_deref())
+        .map(|link| state_store.link_key(link))
+        .filter(|link_key| !state_store.contains(link_key))
+        .collect();
+    let remote_records = match post_config.remote_dedup_posts_opt {
+        Some(max_posts) if !new_links.is_empty() => {
+            fetch_remote_records(client, feed_url, max_posts).await?
+        }
+        _ => HashMap::new(),
+    };
+    let item_count = new_links
+        .iter()
+        .filter(|link_key| !remote_records.contains_key(*link_key))
+        .count();
+    tracing::debug!(
+        feed = feed_url,
+        items = items.len(),
+        new = new_links.len(),
+        posted_remotely = new_links.len() - item_count,
+        "Found the new items"
+    );
+    if state_store.links().is_empty()
+        && item_count > post_config.initial_max
+        && !post_config.allow_initial_backfill
+    {
+        Err(format!(
+            "Refused to post {item_count} items to the empty DB over --initial-max {}: mark them by mark-posted, or give --allow-initial-backfill",
+            post_config.initial_max
+        ))?;
+    }
+    let mut journal = Journal::open(journal_path)?;
+    journal.record(JournalAction::RunStarted {
+        feed_url: feed_url.to_string(),
+    })?;
+
+    let account = client.current_did().unwrap_or("").to_string();
+    let mut new_stats: Vec<stats::PostStat> = vec![];
+    let mut deferred_count = 0;
+    for item in items.iter().rev() {
+        let remote_record_opt = item
+            .link
+            .as_deref()
+            .map(|link| state_store.link_key(link))
+            .filter(|link_key| !state_store.contains(link_key))
+            .and_then(|link_key| {
+                let record = remote_records.get(&link_key)?;
+                Some((link_key, record))
+            });
+        if let Some((link, record)) = remote_record_opt {
+            Event::info(
+                Action::AlreadyPosted,
+                format!(
+                    "Already posted to Bluesky by a previous installation: uri={}",
+                    record.uri
+                ),
+            )
+            .feed(feed_url)
+            .link(&link)
+            .emit();
+            state_store.record_posted(&link, Some(record))?;
+            journal.record(JournalAction::AlreadyPosted {
+                link: link.to_string(),
+                guid: item.guid.as_ref().map(|guid| guid.value.to_string()),
+            })?;
+            continue;
+        }
+        let item_span =
+            tracing::debug_span!("item", link = item.link.as_deref().unwrap_or_default());
+        // The edits of the posted items are not held back by the budget.
+        let new_item = item
+            .link
+            .as_deref()
+            .is_some_and(|link| !state_store.contains(&state_store.link_key(link)));
+        if new_item
+            && item_span.in_scope(|| budget_used_up(state_store.as_mut(), &account, post_config))?
+        {
+            item_span.in_scope(|| tracing::debug!("Deferred by the daily budget"));
+            deferred_count += 1;
+            continue;
+        }
+        let item_post = match post_item(client, item, state_store.as_mut(), post_config)
+            .instrument(item_span.clone())
+            .await
+        {
+            Ok(item_post) => item_post,
+            Err(err) => {
+                journal.record(JournalAction::Failed {
+                    link_opt: item.link.clone(),
+                    error: err.to_string(),
+                    guid: item.guid.as_ref().map(|guid| guid.value.to_string()),
+                })?;
+                // The stats of the items posted before are kept as their links
+                // are, without hiding the error of the item.
+                if let Err(save_err) = stats::save_stats(stats_path, &new_stats) {
+                    Event::warn("Failed to save the stats")
+                        .feed(feed_url)
+                        .error(save_err)
+                        .emit();
+                }
+                if !new_stats.is_empty() {
+                    return Err(RunFailure::new(FailedStage::Partial, err))?;
+                }
+                return Err(err);
+            }
+        };
+        match &item_post.status {
+            ItemPostStatus::AlreadyPosted => {
+                item_post
+                    .event(Action::AlreadyPosted, "Already posted to Bluesky.")
+                    .feed(feed_url)
+                    .emit();
+                journal.record(JournalAction::AlreadyPosted {
+                    link: item_post.orig_link.to_string(),
+                    guid: item_post.guid.clone(),
+                })?;
+            }
+            ItemPostStatus::Skipped { reason } => {
+                item_post
+                    .event(Action::Skipped, format!("Skipped: {reason}"))
+                    .feed(feed_url)
+                    .emit();
+                // The link is recorded without a post, so that the item is not
+                // considered again in every run.
+                if !state_store.contains(&item_post.orig_link) {
+                    state_store.record_posted(&item_post.orig_link, None)?;
+                }
+                journal.record(JournalAction::Skipped {
+                    link: item_post.orig_link.to_string(),
+                    reason: reason.to_string(),
+                    guid: item_post.guid.clone(),
+                })?;
+            }
+            ItemPostStatus::Posted {
+                post: bsky_post,
+                text_length,
+                media_count,
+                content_hash,
+                dedup_hash_opt,
+                truncated,
+                edited,
+                created_count,
+            } => {
+                let (action, verb) = if *edited {
+                    (Action::Edited, "Posted an edit")
+                } else {
+                    (Action::Posted, "Posted")
+                };
+                item_post
+                    .event(
+                        action,
+                        format!(
+                            "{verb} to Bluesky: cid={}, uri={}",
+                            bsky_post.cid, bsky_post.uri
+                        ),
+                    )
+                    .feed(feed_url)
+                    .emit();
+                state_store.record_created(&account, *created_count)?;
+                new_stats.push(stats::PostStat {
+                    posted_at: Utc::now(),
+                    account: client.current_did().unwrap_or("").to_string(),
+                    text_length: *text_length,
+                    media_count: *media_count,
+                });
+                let record = PostedRecord {
+                    uri: bsky_post.uri.to_string(),
+                    cid: bsky_post.cid.to_string(),
+                    posted_at_opt: Some(Utc::now()),
+                    content_hash_opt: Some(content_hash.to_string()),
+                    dedup_hash_opt: dedup_hash_opt.clone(),
+                    feed_url_opt: Some(feed_url.to_string()),
+                    pub_date_opt: item
+                        .pub_date
+                        .as_deref()
+                        .and_then(|pub_date| DateTime::parse_from_rfc2822(pub_date).ok())
+                        .map(|pub_date| pub_date.with_timezone(&Utc)),
+                    truncated_opt: Some(*truncated),
+                    media_count_opt: Some(*media_count),
+                };
+                let link = item_post.orig_link.to_string();
+                let uri = record.uri.to_string();
+                let cid = record.cid.to_string();
+                let guid = item_post.guid.clone();
+                // The root of a thread is recorded as soon as it is created.
+                if state_store.contains(&item_post.orig_link) {
+                    state_store.update_record(&item_post.orig_link, &record)?;
+                } else {
+                    state_store.record_posted(&item_post.orig_link, Some(&record))?;
+                }
+                if *edited {
+                    journal.record(JournalAction::Edited {
+                        link,
+                        uri,
+                        cid,
+                        guid,
+                    })?;
+                } else {
+                    journal.record(JournalAction::Posted {
+                        link,
+                        uri,
+                        cid,
+                        guid,
+                    })?;
+                }
+            }
+        }
+    }
+
+    state_store.commit()?;
+
+    stats::save_stats(stats_path, &new_stats)?;
+
+    if deferred_count > 0 {
+        journal.record(JournalAction::Deferred {
+            items: deferred_count,
+        })?;
+    }
+    journal.record(JournalAction::RunFinished)?;
+    if deferred_count > 0 {
+        Err(Deferred {
+            message: format!(
+                "Deferred {deferred_count} items to the next run: {account} has used up the daily budget of {} records.",
+                post_config.daily_record_budget_opt.unwrap_or_default()
+            ),
+        })?;
+    }
+    Ok(RunSummary {
+        posted: new_stats.len(),
+    })
+}
+
+/// Whether the account has created the records of the daily budget in the
+/// last 24 hours.
+fn budget_used_up(
+    state_store: &mut dyn StateStore,
+    account: &str,
+    post_config: &PostConfig,
+) -> Result<bool, Box<dyn Error>> {
+    let Some(budget) = post_config.daily_record_budget_opt else {
+        return Ok(false);
+    };
+    let since = Utc::now() - chrono::Duration::days(1);
+    let created = state_store.created_records_since(account, since)?;
+    tracing::debug!(
+        account,
+        created,
+        budget,
+        "Counted the records created in 24 hours"
+    );
+    Ok(created >= budget)
+}
+
+/// The records of the links in the recent posts of the account, up to
+/// `max_posts`, by the link facets and the link cards.
+async fn fetch_remote_records<Client>(
+    client: &Client,
+    feed_url: &str,
+    max_posts: usize,
+) -> Result<HashMap<String, PostedRecord>, Box<dyn Error>>
+where
+    Client: XrpcHttpClient + GetAuthorFeedJson + Sync,
+{
+    use bsky::feed::get_author_feed;
+
+    let did = client
+        .current_did()
+        .ok_or("Failed to look up remote posts: not logged in")?;
+    let mut records = HashMap::new();
+    let mut cursor_opt = None;
+    let mut post_count = 0;
+    while post_count < max_posts {
+        let output = client
+            .get_author_feed_json(&get_author_feed::Parameters {
+                actor: did.to_string(),
+                cursor: cursor_opt,
+                limit: Some(i32::try_from((max_posts - post_count).min(100))?),
+            })
+            .await
+            .map_err(|err| format!("Failed to look up remote posts: {err}"))?;
+        let feed = output["feed"].as_array().cloned().unwrap_or_default();
+        if feed.is_empty() {
+            break;
+        }
+        post_count += feed.len();
+        for feed_post in &feed {
+            let post = &feed_post["post"];
+            // Reposts are of the other accounts.
+            if feed_post.get("reason").is_some() || post["author"]["did"] != did {
+                continue;
+            }
+            let (Some(uri), Some(cid)) = (post["uri"].as_str(), post["cid"].as_str()) else {
+                continue;
+            };
+            let facet_links = post["record"]["facets"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .flat_map(|facet| facet["features"].as_array().into_iter().flatten())
+                .filter(|feature| feature["$type"] == "app.bsky.richtext.facet#link")
+                .filter_map(|feature| feature["uri"].as_str());
+            let card_link_opt = post["record"]["embed"]["external"]["uri"].as_str();
+            for link in facet_links.chain(card_link_opt) {
+                // The feed is from the newest, and the newest post is kept.
+                records
+                    .entry(canonical_link(link))
+                    .or_insert_with(|| PostedRecord {
+                        uri: uri.to_string(),
+                        cid: cid.to_string(),
+                        posted_at_opt: None,
+                        content_hash_opt: None,
+                        dedup_hash_opt: None,
+                        feed_url_opt: Some(feed_url.to_string()),
+                        pub_date_opt: None,
+                        truncated_opt: None,
+                        media_count_opt: None,
+                    });
+            }
+        }
+        cursor_opt = output["cursor"].as_str().map(str::to_string);
+        if cursor_opt.is_none() {
+            break;
+        }
+    }
+    Ok(records)
+}
+
+/// Lock the DB by an advisory lock on the lock file beside it, which is held
+/// until the returned file is dropped. The lock file is never replaced, unlike
+/// the DB files rewritten by a rename under the lock: a lock on the DB file
+/// would stay on the replaced file, and the next run would lock the new one
+/// while the holder still writes. Runs, `migrate-db`, and the db subcommands
+/// changing the DB take this lock. The lock of a dead process is released by
+/// the OS, so it is never left stale.
+pub fn lock_db(db_config: &DbConfig) -> Result<File, Box<dyn Error>> {
+    let lock_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(lock_path(&db_config.db_path))
+        .map_err(|err| format!("Failed to open lock file: {err}"))?;
+    match lock_file.try_lock() {
+        Ok(()) => Ok(lock_file),
+        Err(TryLockError::WouldBlock) => Err(RunFailure::new(
+            FailedStage::Lock,
+            "Failed to get lock: locked by another run",
+        ))?,
+        Err(TryLockError::Error(err)) => Err(format!("Failed to get lock: {err}"))?,
+    }
+}
+
+/// The path of the lock file of the DB.
+pub fn lock_path(db_path: &str) -> String {
+    format!("{db_path}.lock")
+}
+
+/// Pull the DB files from the object storage for the S3 backend, taking the
+/// remote lock if `lock`. The DB files are the local copy of the remote DB.
+pub async fn pull_remote_db(
+    db_config: &DbConfig,
+    lock: bool,
+) -> Result<Option<RemoteDb>, Box<dyn Error>> {
+    if db_config.backend != DbBackend::S3 {
+        return Ok(None);
+    }
+    let s3_config = db_config
+        .s3_opt
+        .as_ref()
+        .ok_or("The S3 backend needs an S3 bucket and credentials.")?;
+    let db_path = &db_config.db_path;
+    let paths = [
+        db_path.to_string(),
+        format!("{db_path}.records"),
+        format!("{db_path}.blobs"),
+        format!("{db_path}.created"),
+        db_config.stats_path.to_string(),
+        db_config.journal_path.to_string(),
+    ];
+    let remote_db = s3_state::pull(
+        new_reqwest_client()?,
+        s3_config,
+        &paths,
+        lock,
+        db_config.stale_lock_secs,
+    )
+    .await?;
+    Ok(Some(remote_db))
+}
+
+/// Push the pulled DB files back, even after a failure, as the links posted
+/// before it are appended to them already.
+pub async fn push_remote_db(remote_db_opt: Option<RemoteDb>) -> Result<(), Box<dyn Error>> {
+    match remote_db_opt {
+        Some(remote_db) => remote_db.push().await,
+        None => Ok(()),
+    }
+}
+
+/// Open the state store of the backend, seeing the links in the namespace if any.
+pub fn open_state_store(
+    db_config: &DbConfig,
+    namespace_opt: Option<String>,
+) -> Result<Box<dyn StateStore>, Box<dyn Error>> {
+    match db_config.backend {
+        // The S3 backend works on the pulled DB files.
+        DbBackend::File | DbBackend::S3 => {
+            let state_store = FileStateStore::open(
+                &db_config.db_path,
+                db_config.fsync_policy,
+                db_config.min_save_posts,
+                db_config.retention_days_opt,
+            )?;
+            Ok(Box::new(state_store.with_namespace(namespace_opt)))
+        }
+        DbBackend::Redis => {
+            let redis_url = db_config
+                .redis_url_opt
+                .as_deref()
+                .ok_or("The Redis backend needs a Redis URL.")?;
+            Ok(Box::new(RedisStateStore::open(
+                redis_url,
+                namespace_opt.as_deref(),
+                db_config.min_save_posts,
+                db_config.retention_days_opt,
+            )?))
+        }
+        DbBackend::Sqlite => Ok(Box::new(SqliteStateStore::open(
+            &sqlite_store::sqlite_path(&db_config.db_path),
+            namespace_opt.as_deref(),
+            db_config.fsync_policy,
+            db_config.min_save_posts,
+            db_config.retention_days_opt,
+        )?)),
+    }
+}
+
+/// Open the state store for a run under the lock, warning first when `db check`
+/// finds issues in the DB files. The files with a line cut by a crash are
+/// repaired, as the next line appended would be joined to it. The other issues
+/// are left to `db check --repair`, which replaces the files, rather than
+/// repaired behind the run. The links are namespaced by the feed URL or the
+/// DID of the account if configured.
+fn open_run_state_store(
+    db_config: &DbConfig,
+    feed_url: &str,
+    did_opt: Option<&str>,
+) -> Result<Box<dyn StateStore>, Box<dyn Error>> {
+    let namespace_opt = match db_config.namespace_opt {
+        Some(DbNamespace::Feed) => Some(feed_url.to_string()),
+        Some(DbNamespace::Account) => Some(
+            did_opt
+                .ok_or("Failed to namespace DB: not logged in")?
+                .to_string(),
+        ),
+        None => None,
+    };
+
+    if matches!(db_config.backend, DbBackend::File | DbBackend::S3) {
+        let issues = FileStateStore::check(&db_config.db_path)?;
+        for issue in &issues {
+            Event::warn(format!("Found an issue in the DB: {issue}"))
+                .feed(feed_url)
+                .emit();
+        }
+        if !issues.is_empty() && FileStateStore::has_torn_tail(&db_config.db_path)? {
+            let repaired = FileStateStore::repair(&db_config.db_path)?;
+            Event::warn(format!(
+                "Repaired {} issues in the DB cut by a crash",
+                repaired.len()
+            ))
+            .feed(feed_url)
+            .emit();
+        } else if !issues.is_empty() {
+            Event::warn("Run `db check --repair` to repair the DB")
+                .feed(feed_url)
+                .emit();
+        }
+        FileStateStore::recover(&db_config.db_path)?;
+    }
+    open_state_store(db_config, namespace_opt)
+}
+
+async fn sync_deleted_items<Client>(
+    dry_run: bool,
+    client: &Client,
+    reqwest_client: &reqwest::Client,
+    feed_url: &str,
+    items: &[rss::Item],
+    db_config: &DbConfig,
+) -> Result<(), Box<dyn Error>>
+where
+    Client: XrpcHttpClient + delete_record::DeleteRecord + Sync,
+{
+    if dry_run {
+        Event::info(Action::DryRun, "Dry run: lock and sync deleted items.")
+            .feed(feed_url)
+            .emit();
+        return Ok(());
+    }
+    let _db_lock = lock_db(db_config)?;
+    let remote_db_opt = pull_remote_db(db_config, true).await?;
+    let result =
+        sync_locked_deleted_items(client, reqwest_client, feed_url, items, db_config).await;
+    push_remote_db(remote_db_opt).await?;
+    result
+}
+
+/// The posted records of the feed in the store, which other feeds may share.
+/// The records of no known feed, posted before the feeds were recorded, are
+/// taken as the feed's own.
+fn feed_posted_records(
+    state_store: &dyn StateStore,
+    feed_url: &str,
+) -> Vec<(String, PostedRecord)> {
+    state_store
+        .posted_records()
+        .into_iter()
+        .filter(|(_, record)| {
+            record
+                .feed_url_opt
+                .as_deref()
+                .is_none_or(|record_feed_url| record_feed_url == feed_url)
+        })
+        .collect()
+}
+
+async fn sync_locked_deleted_items<Client>(
+    client: &Client,
+    reqwest_client: &reqwest::Client,
+    feed_url: &str,
+    items: &[rss::Item],
+    db_config: &DbConfig,
+) -> Result<(), Box<dyn Error>>
+where
+    Client: XrpcHttpClient + delete_record::DeleteRecord + Sync,
+{
+    let mut state_store = open_run_state_store(db_config, feed_url, client.current_did())?;
+    let mut journal = Journal::open(&db_config.journal_path)?;
+    journal.record(JournalAction::RunStarted {
+        feed_url: feed_url.to_string(),
+    })?;
+
+    for (link, record) in feed_posted_records(state_store.as_ref(), feed_url) {
+        // Items still in the feed are not deleted.
+        let link_key = canonical_link(&link);
+        if items
+            .iter()
+            .filter_map(|item| item.link.as_deref())
+            .any(|item_link| canonical_link(item_link) == link_key)
+        {
+            continue;
+        }
+        // Mastodon responds 410 for deleted statuses, and 404 for the others.
+        let status = match reqwest_client.get(&link).send().await {
+            Ok(res) => res.status(),
+            Err(err) => {
+                Event::warn("Failed to check the original link")
+                    .feed(feed_url)
+                    .link(&link)
+                    .error(err)
+                    .emit();
+                continue;
+            }
+        };
+        if status != reqwest::StatusCode::NOT_FOUND && status != reqwest::StatusCode::GONE {
+            continue;
+        }
+        if let Err(err) = delete_post(client, &record).await {
+            journal.record(JournalAction::Failed {
+                link_opt: Some(link.to_string()),
+                error: err.to_string(),
+                guid: None,
+            })?;
+            return Err(err);
+        }
+        Event::info(
+            Action::Deleted,
+            format!("Deleted from Bluesky: uri={}", record.uri),
+        )
+        .feed(feed_url)
+        .link(&link)
+        .emit();
+        state_store.forget_record(&link)?;
+        journal.record(JournalAction::Deleted {
+            uri: record.uri.to_string(),
+            link,
+        })?;
+    }
+
+    state_store.commit()?;
+
+    journal.record(JournalAction::RunFinished)?;
+    Ok(())
+}
+
+async fn mark_items_posted(
+    dry_run: bool,
+    did_opt: Option<&str>,
+    feed_url: &str,
+    items: &[rss::Item],
+    db_config: &DbConfig,
+) -> Result<(), Box<dyn Error>> {
+    if dry_run {
+        Event::info(Action::DryRun, "Dry run: lock and mark items as posted.")
+            .feed(feed_url)
+            .emit();
+        return Ok(());
+    }
+    let _db_lock = lock_db(db_config)?;
+    let remote_db_opt = pull_remote_db(db_config, true).await?;
+    let result = mark_locked_items_posted(did_opt, feed_url, items, db_config);
+    push_remote_db(remote_db_opt).await?;
+    result
+}
+
+fn mark_locked_items_posted(
+    did_opt: Option<&str>,
+    feed_url: &str,
+    items: &[rss::Item],
+    db_config: &DbConfig,
+) -> Result<(), Box<dyn Error>> {
+    let mut state_store = open_run_state_store(db_config, feed_url, did_opt)?;
+    let mut journal = Journal::open(&db_config.journal_path)?;
+    journal.record(JournalAction::RunStarted {
+        feed_url: feed_url.to_string(),
+    })?;
+
+    let mut marked_count = 0;
+    for item in items.iter().rev() {
+        let link = match &item.link {
+            Some(link) => state_store.link_key(link),
+            None => continue,
+        };
+        if state_store.contains(&link) {
+            continue;
+        }
+        state_store.record_posted(&link, None)?;
+        journal.record(JournalAction::Marked {
+            link: link.to_string(),
+        })?;
+        marked_count += 1;
+    }
+
+    state_store.commit()?;
+    Event::info(
+        Action::Marked,
+        format!("Marked {marked_count} items as posted."),
+    )
+    .feed(feed_url)
+    .emit();
+
+    journal.record(JournalAction::RunFinished)?;
+    Ok(())
+}
+
+/// The key of the record in its AT URI.
+fn record_rkey(record: &PostedRecord) -> Result<String, Box<dyn Error>> {
+    match record.uri.rsplit('/').next() {
+        Some(rkey) if !rkey.is_empty() => Ok(rkey.to_string()),
+        _ => Err(format!("Invalid record URI: {}", record.uri))?,
+    }
+}
+
+async fn delete_post<Client>(client: &Client, record: &PostedRecord) -> Result<(), Box<dyn Error>>
+where
+    Client: XrpcHttpClient + delete_record::DeleteRecord + Sync,
+{
+    let repo = match client.current_did() {
+        Some(did) => did.to_string(),
+        None => Err(Box::<dyn Error>::from(
+            "Expected an authenticated session of the given client.",
+        ))?,
+    };
+    client
+        .delete_record(delete_record::Input {
+            collection: String::from("app.bsky.feed.post"),
+            repo,
+            rkey: record_rkey(record)?,
+            swap_commit: None,
+            swap_record: Some(record.cid.to_string()),
+        })
+        .await
+        .map_err(|err| format!("Failed to delete {}: {err}", record.uri))?;
+    Ok(())
+}
+
+/// The hash of the normalized text of the item, to find the same content under
+/// another link. Items without any text have no hash.
+fn item_dedup_hash(
+    item: &rss::Item,
+    content_format: ContentFormat,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let description = item.description.as_deref().unwrap_or("");
+    let text = match content_format {
+        ContentFormat::Html => {
+            richtext::to_plain_text(&richtext::from_html(description, &Default::default())?)
+        }
+        ContentFormat::Markdown | ContentFormat::Plain => description.to_string(),
+    };
+    let title = item.title.as_deref().unwrap_or("");
+    let normalized = format!("{title}\n{text}")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    if normalized.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(format!("{:x}", Sha256::digest(normalized.as_bytes()))))
+}
+
+/// The hash of the item content which is posted, to detect edits.
+fn item_content_hash(item: &rss::Item) -> String {
+    let mut hasher = Sha256::new();
+    for part in [&item.title, &item.description] {
+        hasher.update(part.as_deref().unwrap_or("").as_bytes());
+        hasher.update([0]);
+    }
+    for media in rss_ext::get_medias(item) {
+        hasher.update(media.url.as_bytes());
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+async fn fetch_channel(
+    client: &reqwest::Client,
+    url: String,
+) -> Result<rss::Channel, Box<dyn Error>> {
+    async {
+        let request = client.get(&url).send().await?;
+        tracing::info!(status = %request.status(), "GET");
+        let content_bytes = request.bytes().await?;
+        let channel = rss::Channel::read_from(&content_bytes[..])?;
+        tracing::info!(items = channel.items.len(), "parsed");
+        Ok(channel)
+    }
+    .instrument(tracing::info_span!("fetch", url = url.as_str()))
+    .await
+}
+
+#[derive(Debug)]
+struct ItemPost {
+    orig_link: String,
+    guid: Option<String>,
+    status: ItemPostStatus,
+}
+
+impl ItemPost {
+    /// The event of the item, by its keys.
+    fn event(&self, action: Action, message: impl Into<String>) -> Event<'_> {
+        Event::info(action, message)
+            .link(&self.orig_link)
+            .guid(self.guid.as_deref())
+    }
+}
+
+#[derive(Debug)]
+enum ItemPostStatus {
+    AlreadyPosted,
+    Skipped {
+        reason: String,
+    },
+    Posted {
+        post: BskyPost,
+        text_length: usize,
+        media_count: usize,
+        content_hash: String,
+        dedup_hash_opt: Option<String>,
+        /// Whether the text is cut, which a thread is not
+        truncated: bool,
+        /// Whether the post replaces the one of the item before the edit
+        edited: bool,
+        /// The records created, which are the posts of a thread, or none when
+        /// the post is replaced in place
+        created_count: usize,
+    },
+}
+
+async fn post_item<Client>(
+    client: &Client,
+    item: &rss::Item,
+    state_store: &mut dyn StateStore,
+    post_config: &PostConfig,
+) -> Result<ItemPost, Box<dyn Error>>
+where
+    Client: XrpcHttpClient
+        + CreatePost
+        + PutPost
+        + delete_record::DeleteRecord
+        + UploadTypedBlob
+        + GetAuthorFeedJson
+        + GetRecordRef
+        + Sync,
+{
+    let PostConfig {
+        original_link_prefix,
+        post_text_limit: _,
+        truncate_strategy,
+        preserve_first_link,
+        thread_long_posts,
+        blog_mode,
+        max_images,
+        image_max_dimension,
+        strip_exif,
+        upload_concurrency,
+        large_image_threshold,
+        large_image_policy,
+        animation_policy,
+        media_timeout_secs: _,
+        media_retries: _,
+        xrpc_retries: _,
+        media_max_bytes: _,
+        media_hosts: _,
+        media_max_redirects: _,
+        post_without_failed_media,
+        video_max_bytes,
+        video_max_duration,
+        cw_mode,
+        cw_label,
+        cw_from_title,
+        sensitive_media_label_opt,
+        adult_media_label_opt,
+        post_local_only,
+        tracking_params_opt,
+        keep_hashtags_opt,
+        rewrite_rules,
+        mention_mappings,
+        content_format,
+        html_options,
+        link_placement,
+        link_preview,
+        inline_images_opt,
+        edit_policy,
+        content_dedup_days_opt,
+        initial_max: _,
+        allow_initial_backfill: _,
+        remote_dedup_posts_opt: _,
+        daily_record_budget_opt: _,
+        post_langs,
+        lang_detect_threshold_opt,
+        fallback_langs,
+        post_template,
+    } = post_config;
+
+    // Blog items may have only a title.
+    let description = match &item.description {
+        Some(content) => content.as_str(),
+        None if *blog_mode => "",
+        None => Err(Box::<dyn Error>::from(
+            "Failed to get any descriptions of the given RSS item.",
+        ))?,
+    };
+    let item_link = match &item.link {
+        Some(content) => content,
+        None => Err(Box::<dyn Error>::from(
+            "Failed to get any links of the given RSS item.",
+        ))?,
+    };
+    let item_guid = item.guid.as_ref().map(|guid| guid.value.to_string());
+
+    let link_key = state_store.link_key(item_link);
+
+    let content_hash = item_content_hash(item);
+    // The post of the item before the edit, which is replaced.
+    let mut edited_opt: Option<PostedRecord> = None;
+    if state_store.contains(&link_key) {
+        match state_store.posted_record(&link_key) {
+            // Records without a hash are posted before edits are tracked.
+            Some(record)
+                if *edit_policy != EditPolicy::Ignore
+                    && record
+                        .content_hash_opt
+                        .as_ref()
+                        .is_some_and(|hash| *hash != content_hash) =>
+            {
+                tracing::debug!(
+                    old_hash = record.content_hash_opt.as_deref().unwrap_or_default(),
+                    new_hash = content_hash.as_str(),
+                    "Found an edit"
+                );
+                edited_opt = Some(record);
+            }
+            _ => {
+                return Ok(ItemPost {
+                    orig_link: link_key.to_string(),
+                    guid: item_guid.clone(),
+                    status: ItemPostStatus::AlreadyPosted,
+                });
+            }
+        }
+    }
+
+    let dedup_hash_opt = item_dedup_hash(item, *content_format)?;
+    if let (Some(days), Some(dedup_hash), None) =
+        (content_dedup_days_opt, &dedup_hash_opt, &edited_opt)
+    {
+        let since = Utc::now() - chrono::Duration::days(i64::from(*days));
+        let same_opt = state_store
+            .posted_records()
+            .into_iter()
+            .find(|(_, record)| {
+                record.dedup_hash_opt.as_ref() == Some(dedup_hash)
+                    && record
+                        .posted_at_opt
+                        .is_some_and(|posted_at| posted_at >= since)
+            });
+        if let Some((link, record)) = same_opt {
+            return Ok(ItemPost {
+                orig_link: link_key.to_string(),
+                guid: item_guid.clone(),
+                status: ItemPostStatus::Skipped {
+                    reason: format!("The same content is posted for {link}: uri={}", record.uri),
+                },
+            });
+        }
+    }
+
+    let (mut cw_opt, description) = match content_format {
+        ContentFormat::Html => content_warning::split_content_warning(description)?,
+        ContentFormat::Markdown | ContentFormat::Plain => (None, description),
+    };
+    if cw_opt.is_none() && *cw_from_title && !*blog_mode {
+        cw_opt = content_warning::title_content_warning(item);
+    }
+    if cw_opt.is_some() && *cw_mode == CwMode::Skip {
+        return Ok(ItemPost {
+            orig_link: link_key.to_string(),
+            guid: item_guid.clone(),
+            status: ItemPostStatus::Skipped {
+                reason: String::from("The item has a content warning."),
+            },
+        });
+    }
+
+    let media_label_opt = |media: &rss_ext::Media| match media.rating {
+        rss_ext::Rating::NonAdult => None,
+        rss_ext::Rating::Adult => adult_media_label_opt.or(*sensitive_media_label_opt),
+        rss_ext::Rating::Other => *sensitive_media_label_opt,
+    };
+    let (mut images, videos): (Vec<rss_ext::Media>, Vec<rss_ext::Media>) =
+        rss_ext::get_medias(item)
+            .into_iter()
+            .filter(|media| {
+                let keeps = matches!(media.rating, rss_ext::Rating::NonAdult)
+                    || media_label_opt(media).is_some();
+                if !keeps {
+                    Event::warn(format!("Ignore a image might be sensitive: {}", media.url))
+                        .link(&link_key)
+                        .emit();
+                }
+                keeps
+            })
+            .partition(|media| media.is_image());
+    if let Some(inline_images) = inline_images_opt {
+        // Generic feeds have the images only in the content.
+        if images.is_empty() && *content_format == ContentFormat::Html {
+            images = image_alt::find_inline_images(description, item_link)
+                .into_iter()
+                .take(*inline_images)
+                .map(|url| rss_ext::Media {
+                    url,
+                    // Inline images have no size and no type.
+                    file_size: 0,
+                    typ: String::from("image/*"),
+                    rating: rss_ext::Rating::NonAdult,
+                    description: None,
+                    duration_opt: None,
+                    thumbnail_url_opt: None,
+                })
+                .collect();
+        }
+    }
+
+    let mut large_image_card = false;
+    if *large_image_policy != LargeImagePolicy::Resize {
+        images.retain_mut(|media| {
+            // The size is unknown for some enclosures.
+            if media.file_size <= *large_image_threshold {
+                return true;
+            }
+            if let Some(thumbnail_url) = media.thumbnail_url_opt.take() {
+                Event::warn(format!(
+                    "Use the thumbnail of a image over {large_image_threshold} bytes: {} ({} bytes)",
+                    media.url, media.file_size
+                ))
+                .link(&link_key)
+                .emit();
+                media.url = thumbnail_url;
+                return true;
+            }
+            Event::warn(format!(
+                "Ignore a image over {large_image_threshold} bytes: {} ({} bytes)",
+                media.url, media.file_size
+            ))
+            .link(&link_key)
+            .emit();
+            large_image_card = *large_image_policy == LargeImagePolicy::LinkOnly;
+            false
+        });
+    }
+    images.truncate(*max_images);
+    // Bluesky posts cannot have audio, so audio posts have the card of the item.
+    let mut has_audio = images.is_empty()
+        && !videos.iter().any(|media| media.is_video())
+        && videos.iter().any(|media| media.is_audio());
+    let audio_thumbnail_url_opt = videos
+        .iter()
+        .find(|media| media.is_audio())
+        .and_then(|media| media.thumbnail_url_opt.clone());
+    // Bluesky posts cannot have both images and a video.
+    let mut video_opt = videos
+        .into_iter()
+        .find(|media| media.is_video())
+        .filter(|_| images.is_empty());
+
+    let blog_title_opt = item
+        .title
+        .as_deref()
+        .map(str::trim)
+        .filter(|title| *blog_mode && !title.is_empty());
+    let mut text = match blog_title_opt {
+        Some(title) => vec![RichTextSegment::PlainText {
+            text: title.to_string(),
+        }],
+        None => richtext::from_content(description, *content_format, html_options)?,
+    };
+    if *blog_mode {
+        // The link card is the embed of blog posts.
+        images.clear();
+        video_opt = None;
+        has_audio = false;
+    }
+
+    if !*post_local_only && local_only::is_local_only(&text) {
+        return Ok(ItemPost {
+            orig_link: link_key.to_string(),
+            guid: item_guid.clone(),
+            status: ItemPostStatus::Skipped {
+                reason: String::from("The item is marked as local-only."),
+            },
+        });
+    }
+    text = mentions::link_mentions(text, mention_mappings);
+    if let Some(keep_hashtags) = keep_hashtags_opt {
+        text = hashtags::strip_hashtags(text, keep_hashtags);
+    }
+    if !rewrite_rules.is_empty() {
+        text = rewrite::apply_rewrite_rules(text, rewrite_rules);
+    }
+    let mut self_labels = vec![];
+    if let Some(cw) = cw_opt {
+        let cw_text = RichTextSegment::PlainText {
+            text: format!("CW: {cw}\n\n"),
+        };
+        match cw_mode {
+            CwMode::Prepend => {
+                text.insert(0, cw_text);
+            }
+            CwMode::SpoilerOnly => {
+                text = vec![cw_text];
+                images.clear();
+                video_opt = None;
+                has_audio = false;
+            }
+            CwMode::Skip => {
+                // already skipped
+            }
+            CwMode::Label => {
+                self_labels.push(cw_label.as_str().to_string());
+            }
+        }
+    }
+    for media_label in images
+        .iter()
+        .chain(video_opt.iter())
+        .filter_map(media_label_opt)
+    {
+        let label = media_label.as_str().to_string();
+        if !self_labels.contains(&label) {
+            self_labels.push(label);
+        }
+    }
+
+    let mut post_link = item_link.to_string();
+    if let Some(tracking_params) = tracking_params_opt {
+        post_link = tracking_params::strip_tracking_params(&post_link, tracking_params);
+        for seg in text.iter_mut() {
+            if let RichTextSegment::Link { link, .. } = seg {
+                *link = tracking_params::strip_tracking_params(link, tracking_params);
+            }
+        }
+    }
+
+    richtext::trim_end(&mut text);
+    if has_audio {
+        text.push(RichTextSegment::PlainText {
+            text: format!("\n{AUDIO_NOTE}"),
+        });
+    }
+
+    let langs = if !post_langs.is_empty() {
+        post_langs.clone()
+    } else {
+        match lang_detect_threshold_opt.and_then(|threshold| {
+            lang_detect::detect_lang(&richtext::to_plain_text(&text), threshold)
+        }) {
+            Some(lang) => vec![lang],
+            None => fallback_langs.clone(),
+        }
+    };
+    tracing::debug!(langs = ?langs, "Chose the languages");
+    let attrs = PostAttrs { langs, self_labels };
+
+    let mut template_values = TemplateValues {
+        link: &post_link,
+        link_prefix: original_link_prefix,
+        title: item.title.as_deref().unwrap_or(""),
+        pub_date: item.pub_date.as_deref().unwrap_or(""),
+        author: item.author.as_deref().unwrap_or(""),
+    };
+    if *link_placement == LinkPlacement::Omit {
+        template_values.link = "";
+        template_values.link_prefix = "";
+    }
+
+    let video_fits = |video: &rss_ext::Media| {
+        video.file_size <= *video_max_bytes
+            && video
+                .duration_opt
+                .is_none_or(|duration| duration <= f64::from(*video_max_duration))
+    };
+    let image_options = ImageOptions {
+        max_dimension: *image_max_dimension,
+        upload_concurrency: *upload_concurrency,
+        strip_exif: *strip_exif,
+    };
+    let media_alt = |media: &rss_ext::Media| {
+        media
+            .description
+            .clone()
+            .or_else(|| image_alt::find_image_alt(description, &media.url))
+    };
+    let mut embed_opt = if !images.is_empty() {
+        Some(PostEmbed::Images {
+            images: images
+                .iter()
+                .map(|media| EmbedImage {
+                    url: media.url.to_string(),
+                    alt: media_alt(media).unwrap_or_default(),
+                    still_frame: false,
+                })
+                .collect(),
+            options: image_options,
+        })
+    } else {
+        match (&video_opt, animation_policy) {
+            (Some(video), AnimationPolicy::Video) if video_fits(video) => Some(PostEmbed::Video {
+                url: video.url.to_string(),
+                alt: media_alt(video),
+                max_bytes: *video_max_bytes,
+            }),
+            (Some(video), AnimationPolicy::Video) => {
+                Event::warn(format!(
+                    "Attach the link card for a video over the limits: {}",
+                    video.url
+                ))
+                .link(&link_key)
+                .emit();
+                None
+            }
+            (Some(video), AnimationPolicy::Still) => Some(PostEmbed::Images {
+                images: vec![EmbedImage {
+                    url: video.url.to_string(),
+                    alt: media_alt(video).unwrap_or_default(),
+                    still_frame: true,
+                }],
+                options: image_options,
+            }),
+            (Some(_), AnimationPolicy::Card) | (None, _) => None,
+        }
+    };
+    let video_card = match &video_opt {
+        Some(video) => match animation_policy {
+            AnimationPolicy::Video => !video_fits(video),
+            AnimationPolicy::Still => false,
+            AnimationPolicy::Card => true,
+        },
+        None => false,
+    };
+
+    // A post can have only one embed, so media take precedence over the card.
+    if (*link_placement == LinkPlacement::Card
+        || *blog_mode
+        || video_card
+        || large_image_card
+        || has_audio)
+        && embed_opt.is_none()
+    {
+        template_values.link = "";
+        template_values.link_prefix = "";
+        // The card shows a still of the media, which the page may not have.
+        let thumb_opt = match &video_opt {
+            Some(video) if video_card => Some(match &video.thumbnail_url_opt {
+                Some(thumbnail_url) => EmbedImage {
+                    url: thumbnail_url.to_string(),
+                    alt: String::new(),
+                    still_frame: false,
+                },
+                None => EmbedImage {
+                    url: video.url.to_string(),
+                    alt: String::new(),
+                    still_frame: true,
+                },
+            }),
+            _ if has_audio => audio_thumbnail_url_opt.map(|url| EmbedImage {
+                url,
+                alt: String::new(),
+                still_frame: false,
+            }),
+            _ => None,
+        };
+        embed_opt = Some(PostEmbed::External {
+            uri: post_link.to_string(),
+            thumb_opt,
+            options: image_options,
+        });
+    }
+    let item_card = matches!(embed_opt, Some(PostEmbed::External { .. }));
+
+    let mut link_preview_card = false;
+    if *link_preview && embed_opt.is_none() {
+        if let Some(link) = first_external_link(&text) {
+            link_preview_card = true;
+            embed_opt = Some(PostEmbed::External {
+                uri: link,
+                thumb_opt: None,
+
+                options: image_options,
+            });
+        }
+    }
+
+    let embed_opt = match embed_opt {
+        Some(embed) => match embed::build_embed(client, state_store, embed).await {
+            Ok(built_opt) => built_opt,
+            Err(err) if link_preview_card => {
+                Event::warn("Post without the link preview which failed to build")
+                    .link(&link_key)
+                    .error(err)
+                    .emit();
+                None
+            }
+            // The original link is appended to the text instead, as without
+            // the card the post would not link to the item.
+            Err(err) if item_card => {
+                Event::warn("Append the original link instead of the card which failed to build")
+                    .link(&link_key)
+                    .error(err)
+                    .emit();
+                template_values.link = &post_link;
+                template_values.link_prefix = original_link_prefix;
+                None
+            }
+            Err(err) if *post_without_failed_media => {
+                Event::warn("Post without the media which failed to upload")
+                    .link(&link_key)
+                    .error(err)
+                    .emit();
+                None
+            }
+            Err(err) => Err(err)?,
+        },
+        None => None,
+    };
+
+    let limit_count = post_config.content_limit(&template_values)?;
+    tracing::debug!(
+        graphemes = richtext_grapheme_count(&text),
+        limit = limit_count,
+        "Counted the graphemes"
+    );
+
+    // The thread is split by graphemes, so it is triggered by them as well.
+    if *thread_long_posts && richtext_grapheme_count(&text) > limit_count {
+        tracing::debug!("Posted as a thread over the limit");
+        if *link_placement == LinkPlacement::WhenTruncated {
+            template_values.link = "";
+            template_values.link_prefix = "";
+        }
+        let text = post_template.render(&text, &template_values)?;
+        let text_length = richtext_char_count(&text);
+        let media_count = embed_media_count(&embed_opt);
+        // A thread cannot replace a post in place.
+        if let Some(edited) = &edited_opt {
+            delete_post(client, edited).await?;
+        }
+        let record_root = |root: &BskyPost| {
+            let record = PostedRecord {
+                uri: root.uri.to_string(),
+                cid: root.cid.to_string(),
+                posted_at_opt: Some(Utc::now()),
+                content_hash_opt: Some(content_hash.to_string()),
+                dedup_hash_opt: None,
+                feed_url_opt: None,
+                pub_date_opt: None,
+                truncated_opt: None,
+                media_count_opt: None,
+            };
+            // An edited item is recorded already.
+            if state_store.contains(&link_key) {
+                state_store.update_record(&link_key, &record)
+            } else {
+                state_store.record_posted(&link_key, Some(&record))
+            }
+        };
+        let (result, created_count) =
+            post_thread_to_bsky(client, record_root, &text, post_config, embed_opt, &attrs).await?;
+        return Ok(ItemPost {
+            orig_link: link_key.to_string(),
+            guid: item_guid.clone(),
+            status: ItemPostStatus::Posted {
+                post: result,
+                text_length,
+                media_count,
+                content_hash,
+                dedup_hash_opt,
+                truncated: false,
+                edited: edited_opt.is_some(),
+                created_count,
+            },
+        });
+    }
+
+    let (mut text, need_truncate) = if *preserve_first_link {
+        truncate::truncate_richtext_preserving_link(text, limit_count, *truncate_strategy)
+    } else {
+        truncate::truncate_richtext(text, limit_count, *truncate_strategy)
+    };
+    if need_truncate {
+        tracing::debug!("Truncated to the limit");
+        richtext::trim_end(&mut text);
+        text.push(RichTextSegment::PlainText {
+            text: String::from("..."),
+        });
+    } else if *link_placement == LinkPlacement::WhenTruncated {
+        template_values.link = "";
+        template_values.link_prefix = "";
+    }
+    let (content, facets) = render_richtext(&post_template.render(&text, &template_values)?);
+
+    let text_length = content.chars().count();
+    let media_count = embed_media_count(&embed_opt);
+    let replaced_opt = match &edited_opt {
+        Some(edited) if *edit_policy == EditPolicy::Repost => {
+            delete_post(client, edited).await?;
+            None
+        }
+        _ => edited_opt.as_ref(),
+    };
+    let result = post_to_bsky(
+        client,
+        content,
+        facets,
+        embed_opt,
+        None,
+        &attrs,
+        replaced_opt,
+    )
+    .await?;
+
+    Ok(ItemPost {
+        orig_link: link_key.to_string(),
+        guid: item_guid,
+        status: ItemPostStatus::Posted {
+            post: result,
+            text_length,
+            media_count,
+            content_hash,
+            dedup_hash_opt,
+            truncated: need_truncate,
+            edited: edited_opt.is_some(),
+            created_count: if replaced_opt.is_some() { 0 } else { 1 },
+        },
+    })
+}
+
+fn embed_media_count(embed_opt: &Option<BuiltEmbed>) -> usize {
+    use bsky::feed::post::RecordEmbedEnum;
+
+    match embed_opt {
+        Some(BuiltEmbed::Record(RecordEmbedEnum::AppBskyEmbedImagesMain(images))) => {
+            images.images.len()
+        }
+        Some(BuiltEmbed::Custom(CustomEmbed::Images(images))) => images.images.len(),
+        Some(BuiltEmbed::Custom(CustomEmbed::Video(_))) => 1,
+        Some(BuiltEmbed::Record(_)) | None => 0,
+    }
+}
+
+/// The first link to an external page, other than hashtags and mentions.
+fn first_external_link(text: &RichText) -> Option<String> {
+    text.iter().find_map(|seg| match seg {
+        RichTextSegment::Link { text, link } if !text.starts_with(['#', '@']) => {
+            Some(link.to_string())
+        }
+        _ => None,
+    })
+}
+
+fn richtext_char_count(text: &RichText) -> usize {
+    text.iter().map(|seg| seg.text().chars().count()).sum()
+}
+
+fn richtext_byte_count(text: &RichText) -> usize {
+    text.iter().map(|seg| seg.text().len()).sum()
+}
+
+/// The number of graphemes, as counted by `richtext::chunk_richtext`.
+fn richtext_grapheme_count(text: &RichText) -> usize {
+    text.iter()
+        .map(|seg| seg.text().graphemes(true).count())
+        .sum()
+}
+
+pub fn render_richtext(text: &RichText) -> (String, Vec<bsky::richtext::facet::Main>) {
+    use bsky::richtext::facet;
+
+    let mut content = String::from("");
+    let mut facets: Vec<facet::Main> = vec![];
+    for seg in text {
+        match seg {
+            RichTextSegment::PlainText { text } => {
+                content.push_str(text);
+            }
+            RichTextSegment::Link { text, .. } | RichTextSegment::Mention { text, .. }
+                if text.is_empty() =>
+            {
+                // An empty range is not a valid facet.
+            }
+            RichTextSegment::Mention { text, did } => {
+                let byte_start = content.len() as i32;
+                content.push_str(text);
+                let byte_end = content.len() as i32;
+                facets.push(facet::Main {
+                    index: facet::ByteSlice {
+                        byte_start,
+                        byte_end,
+                    },
+                    features: vec![facet::MainFeaturesItem::Mention(Box::new(facet::Mention {
+                        did: did.to_string(),
+                    }))],
+                });
+            }
+
+            RichTextSegment::Link { text, link } => {
+                let byte_start = content.len() as i32;
+
+                content.push_str(text);
+                let byte_end = content.len() as i32;
+                facets.push(facet::Main {
+                    index: facet::ByteSlice {
+                        byte_start,
+                        byte_end,
+                    },
+                    features: vec![facet::MainFeaturesItem::Link(Box::new(facet::Link {
+                        uri: link.to_string(),
+                    }))],
+                });
+            }
+        }
+    }
+    (content, facets)
+}
+
+/// The text and the facets of the rich text as `render-html` prints them, which
+/// the fixtures of the conversion are compared with.
+pub fn describe_richtext(text: &RichText) -> String {
+    let (content, facets) = render_richtext(text);
+    let mut description = format!("--- text\n{content}\n--- facets\n");
+    for facet in facets {
+        for feature in facet.features {
+            let (kind, target) = match feature {
+                bsky::richtext::facet::MainFeaturesItem::Link(link) => ("link", link.uri.clone()),
+                bsky::richtext::facet::MainFeaturesItem::Mention(mention) => {
+                    ("mention", mention.did.to_string())
+                }
+            };
+            description.push_str(&format!(
+                "{}..{} {kind} {target}\n",
+                facet.index.byte_start, facet.index.byte_end
+            ));
+        }
+    }
+    description
+}
+
+/// Post the text as a thread. The root is recorded by `record_root` as soon as
+/// it is created, so that a failure in the rest of the thread does not post the
+/// thread again in the next run.
+async fn post_thread_to_bsky<Client>(
+    client: &Client,
+    record_root: impl FnOnce(&BskyPost) -> Result<(), Box<dyn Error>>,
+    text: &RichText,
+    post_config: &PostConfig,
+    mut embed_opt: Option<BuiltEmbed>,
+    attrs: &PostAttrs,
+) -> Result<(BskyPost, usize), Box<dyn Error>>
+where
+    Client: XrpcHttpClient
+        + CreatePost
+        + PutPost
+        + delete_record::DeleteRecord
+        + UploadTypedBlob
+        + GetAuthorFeedJson
+        + GetRecordRef
+        + Sync,
+{
+    use atproto::repo::strong_ref;
+    use bsky::feed::post;
+
+    let chunks = richtext::chunk_richtext(
+        text,
+        richtext::ChunkBudget {
+            max_graphemes: post_config.post_text_limit,
+            max_bytes: POST_BYTES_LIMIT,
+        },
+    );
+
+    let mut record_root = Some(record_root);
+    let mut root_opt: Option<BskyPost> = None;
+    let mut parent_opt: Option<BskyPost> = None;
+    for chunk in chunks.iter() {
+        let (content, facets) = render_richtext(chunk);
+        let reply = match (&root_opt, &parent_opt) {
+            (Some(root), Some(parent)) => Some(post::ReplyRef {
+                root: strong_ref::Main {
+                    cid: root.cid.to_string(),
+                    uri: root.uri.to_string(),
+                },
+                parent: strong_ref::Main {
+                    cid: parent.cid.to_string(),
+                    uri: parent.uri.to_string(),
+                },
+            }),
+            _ => None,
+        };
+        let result = post_to_bsky(
+            client,
+            content,
+            facets,
+            embed_opt.take(),
+            reply,
+            attrs,
+            None,
+        )
+        .await?;
+        if root_opt.is_none() {
+            let root = BskyPost {
+                cid: result.cid.to_string(),
+                uri: result.uri.to_string(),
+            };
+            if let Some(record_root) = record_root.take() {
+                record_root(&root)?;
+            }
+            root_opt = Some(root);
+        } else {
+            Event::info(
+                Action::Posted,
+                format!(
+                    "Posted a thread reply to Bluesky: cid={}, uri={}",
+                    result.cid, result.uri
+                ),
+            )
+            .emit();
+        }
+        parent_opt = Some(result);
+    }
+
+    match root_opt {
+        Some(root) => Ok((root, chunks.len())),
+        None => Err(Box::<dyn Error>::from("Nothing to post as a thread.")),
+    }
+}
+
+#[derive(Debug)]
+struct BskyPost {
+    cid: String,
+    uri: String,
+}
+
+/// Attributes of a post record other than the text and the embed.
+struct PostAttrs {
+    langs: Vec<String>,
+    self_labels: Vec<String>,
+}
+
+async fn post_to_bsky<Client>(
+    client: &Client,
+    text: String,
+    facets: Vec<bsky::richtext::facet::Main>,
+    embed_opt: Option<BuiltEmbed>,
+    reply: Option<bsky::feed::post::ReplyRef>,
+    attrs: &PostAttrs,
+    replaced_opt: Option<&PostedRecord>,
+) -> Result<BskyPost, Box<dyn Error>>
+where
+    Client: XrpcHttpClient
+        + CreatePost
+        + PutPost
+        + delete_record::DeleteRecord
+        + UploadTypedBlob
+        + GetAuthorFeedJson
+        + GetRecordRef
+        + Sync,
+{
+    use bsky::feed::post;
+
+    let target_did = match client.current_did() {
+        Some(did) => did,
+        None => Err(Box::<dyn Error>::from(
+            "Expected an authenticated session of the given client.",
+        ))?,
+    };
+
+    let (embed, custom_embed) = match embed_opt {
+        Some(BuiltEmbed::Record(embed)) => (Some(embed), None),
+        Some(BuiltEmbed::Custom(custom_embed)) => (None, Some(custom_embed)),
+        None => (None, None),
+    };
+
+    let mut record = PostRecord::new(post::Record {
+        created_at: Utc::now().to_rfc3339(),
+        embed,
+        entities: None,
+        facets: Some(facets),
+        reply,
+        text,
+    });
+    if !attrs.langs.is_empty() {
+        record.langs = Some(attrs.langs.clone());
+    }
+    if !attrs.self_labels.is_empty() {
+        record.labels = Some(SelfLabels::new(&attrs.self_labels));
+    }
+    record.custom_embed = custom_embed;
+
+    // An edited item replaces its post in place.
+    if let Some(replaced) = replaced_opt {
+        let result = client
+            .put_post(PutPostInput {
+                collection: String::from("app.bsky.feed.post"),
+                record,
+                repo: String::from(target_did),
+                rkey: record_rkey(replaced)?,
+                swap_record: Some(replaced.cid.to_string()),
+            })
+            .instrument(tracing::info_span!(
+                "put_record",
+                uri = replaced.uri.as_str()
+            ))
+            .await?;
+        return Ok(BskyPost {
+            cid: result.cid,
+            uri: result.uri,
+        });
+    }
+
+    let rkey = post_record::new_tid();
+    let input = CreatePostInput {
+        collection: String::from("app.bsky.feed.post"),
+        record,
+        repo: String::from(target_did),
+        rkey: Some(rkey.to_string()),
+    };
+
+    // The error is not `Send`, so it cannot be kept over the lookup.
+    let err = match client
+        .create_post(input)
+        .instrument(tracing::info_span!("create_record", rkey = rkey.as_str()))
+        .await
+    {
+        Ok(result) => {
+            return Ok(BskyPost {
+                cid: result.cid,
+                uri: result.uri,
+            })
+        }
+        Err(err) => err.to_string(),
+    };
+    // A retried request fails when the first one has created the record.
+    let params = get_record::Parameters {
+        cid: None,
+        collection: String::from("app.bsky.feed.post"),
+        repo: String::from(target_did),
+        rkey,
+    };
+    match client.get_record_ref(&params).await {
+        Ok(RecordRef {
+            uri,
+            cid: Some(cid),
+        }) => Ok(BskyPost { cid, uri }),
+        _ => Err(err)?,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record_of(uri: &str, feed_url_opt: Option<&str>) -> PostedRecord {
+        PostedRecord {
+            uri: uri.to_string(),
+            cid: String::from("cid"),
+            posted_at_opt: Some(Utc::now()),
+            content_hash_opt: None,
+            dedup_hash_opt: None,
+            feed_url_opt: feed_url_opt.map(|feed_url| feed_url.to_string()),
+            pub_date_opt: None,
+            truncated_opt: None,
+            media_count_opt: None,
+        }
+    }
+
+    #[test]
+    fn feed_posted_records_skip_other_feeds() {
+        let path = std::env::temp_dir()
+            .join(format!("bridge-test-{}-feeds", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let mut state_store = FileStateStore::open(&path, FsyncPolicy::PerRun, 5, None).unwrap();
+        let feed_a = "https://a.example.com/feed.rss";
+        let feed_b = "https://b.example.com/feed.rss";
+        let records = [
+            ("https://a.example.com/1", Some(feed_a)),
+            ("https://b.example.com/1", Some(feed_b)),
+            ("https://old.example.com/1", None),
+        ];
+        for (index, (link, feed_url_opt)) in records.iter().enumerate() {
+            let record = record_of(&format!("at://post/{index}"), *feed_url_opt);
+            state_store.record_posted(link, Some(&record)).unwrap();
+        }
+        state_store.commit().unwrap();
+
+        let links_of = |feed_url: &str| {
+            let mut links: Vec<String> = feed_posted_records(&state_store, feed_url)
+                .into_iter()
+                .map(|(link, _)| link)
+                .collect();
+            links.sort();
+            links
+        };
+        assert_eq!(
+            links_of(feed_a),
+            ["https://a.example.com/1", "https://old.example.com/1"]
+        );
+        assert_eq!(
+            links_of(feed_b),
+            ["https://b.example.com/1", "https://old.example.com/1"]
+        );
+
+        for suffix in ["", ".records", ".blobs", ".created"] {
+            let _ = std::fs::remove_file(format!("{path}{suffix}"));
+        }
+    }
+
+    fn values_of<'a>(link: &'a str, link_prefix: &'a str) -> TemplateValues<'a> {
+        TemplateValues {
+            link,
+            link_prefix,
+            title: "",
+            pub_date: "",
+            author: "",
+        }
+    }
+
+    #[test]
+    fn content_limit_reserves_layout_and_marker() {
+        let post_config = PostConfig {
+            original_link_prefix: String::from("via:"),
+            ..Default::default()
+        };
+        post_config.validate().unwrap();
+        // "\nvia:" and the link, and "..." for the truncation.
+        let values = values_of("https://example.com/1", "via:");
+        assert_eq!(
+            post_config.content_limit(&values).unwrap(),
+            300 - (5 + 21) - 3
+        );
+    }
+
+    #[test]
+    fn content_limit_of_zero_limit() {
+        let post_config = PostConfig {
+            post_text_limit: 0,
+            post_template: LinkPlacement::Omit.default_template(),
+            ..Default::default()
+        };
+        assert!(post_config.validate().is_err());
+        assert!(post_config.content_limit(&values_of("", "")).is_err());
+    }
+
+    #[test]
+    fn content_limit_errors_on_link_over_limit() {
+        let post_config = PostConfig {
+            post_text_limit: 30,
+            original_link_prefix: String::from("[マストドン投稿から]:"),
+            ..Default::default()
+        };
+        // The prefix alone fits, but not with a link.
+        post_config.validate().unwrap();
+        let values = values_of("https://example.com/a/long/path", "[マストドン投稿から]:");
+        let err = post_config.content_limit(&values).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("The post text limit 30 is too small"));
+    }
+
+    #[test]
+    fn content_limit_of_omitted_link() {
+        let post_config = PostConfig {
+            post_text_limit: 3,
+            link_placement: LinkPlacement::Omit,
+            post_template: LinkPlacement::Omit.default_template(),
+            ..Default::default()
+        };
+        post_config.validate().unwrap();
+        let values = values_of("https://example.com/1", "via:");
+        assert_eq!(post_config.content_limit(&values).unwrap(), 0);
+    }
+
+    #[test]
+    fn content_limit_of_template_without_content() {
+        let post_config = PostConfig {
+            post_template: "{{link_prefix}}{{link}}".parse().unwrap(),
+            ..Default::default()
+        };
+        post_config.validate().unwrap();
+        let values = values_of("https://example.com/1", "via:");
+        assert_eq!(post_config.content_limit(&values).unwrap(), 300 - 25 - 3);
+    }
+
+    #[test]
+    fn content_limit_by_graphemes_or_bytes() {
+        // Over the limit by chars, but not by bytes.
+        let post_config = PostConfig {
+            original_link_prefix: "a".repeat(298),
+            ..Default::default()
+        };
+        let err = post_config.validate().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("The post text limit 300 is too small"));
+
+        // Over the bytes of a post, but not the limit by chars.
+        let post_config = PostConfig {
+            post_text_limit: 1000,
+            original_link_prefix: "🎉".repeat(750),
+            ..Default::default()
+        };
+        let err = post_config.validate().unwrap_err();
+        assert!(err.to_string().contains("The layout is too long"));
+
+        // The bytes left bound the content, as each char may take 4 bytes.
+        let post_config = PostConfig {
+            post_text_limit: 1000,
+            original_link_prefix: "🎉".repeat(500),
+            ..Default::default()
+        };
+        let values = values_of("", &post_config.original_link_prefix);
+        assert_eq!(
+            post_config.content_limit(&values).unwrap(),
+            (3000 - (1 + 2000) - 3) / 4
+        );
+    }
+
+    #[test]
+    fn open_run_state_store_repairs_torn_tail() {
+        let dir = std::env::temp_dir().join(format!("bridge-test-{}-torn", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("db").to_string_lossy().to_string();
+        let feed_url = "https://example.com/feed.rss";
+        // The records file ends by a record cut by a crash.
+        std::fs::write(&db_path, "https://example.com/1\n").unwrap();
+        std::fs::write(
+            format!("{db_path}.records"),
+            r#"{"link":"https://example.com/1","#,
+        )
+        .unwrap();
+        assert!(FileStateStore::has_torn_tail(&db_path).unwrap());
+
+        let db_config = DbConfig::new(&db_path);
+        let mut state_store = open_run_state_store(&db_config, feed_url, None).unwrap();
+        assert!(FileStateStore::check(&db_path).unwrap().is_empty());
+        state_store
+            .record_posted(
+                "https://example.com/2",
+                Some(&record_of("at://post/2", Some(feed_url))),
+            )
+            .unwrap();
+        state_store.commit().unwrap();
+        drop(state_store);
+
+        let state_store = open_run_state_store(&db_config, feed_url, None).unwrap();
+        assert!(state_store.posted_record("https://example.com/2").is_some());
+        assert!(FileStateStore::check(&db_path).unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
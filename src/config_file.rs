@@ -0,0 +1,234 @@
+use clap::{Arg, ArgAction, Command};
+use std::error::Error;
+use std::ffi::OsString;
+
+/// The environment variable of the config file, as `--config`.
+const CONFIG_ENV: &str = "MSTDN_RSS2BSKY_POST_CONFIG";
+
+/// The command with the `--config` option.
+pub fn with_config_arg(command: Command) -> Command {
+    command.arg(
+        Arg::new("config")
+            .long("config")
+            .env(CONFIG_ENV)
+            .value_name("CONFIG")
+            .help("TOML file of the options by their long names, with a table per subcommand such as [run]. Options given by the command line or the environment take precedence, and \"${VAR}\" in the values is replaced by the environment variable"),
+    )
+}
+
+/// The arguments with the options in the config file given by `--config`,
+/// which are put before the options of the command line. The options given
+/// by the command line or by their environment variables are not taken from
+/// the file.
+pub fn with_config_args(
+    command: &Command,
+    args: Vec<OsString>,
+) -> Result<Vec<OsString>, Box<dyn Error>> {
+    let scanned = scan_args(command, &args);
+    let Some(path) = scanned
+        .config_path_opt
+        .clone()
+        .or_else(|| std::env::var_os(CONFIG_ENV))
+    else {
+        return Ok(args);
+    };
+    let path = path.to_string_lossy().to_string();
+    let content = std::fs::read_to_string(&path)
+        .map_err(|err| format!("Failed to read config {path}: {err}"))?;
+    let table: toml::value::Table =
+        toml::from_str(&content).map_err(|err| format!("Failed to parse config {path}: {err}"))?;
+
+    let mut global_args = vec![];
+    let mut subcommand_args = vec![];
+    for (key, value) in &table {
+        if let (Some(subcommand), toml::Value::Table(subtable)) =
+            (command.find_subcommand(key), value)
+        {
+            for (subkey, subvalue) in subtable {
+                let arg = find_arg(subcommand, subkey)
+                    .ok_or_else(|| format!("Unknown option {key}.{subkey} in config {path}"))?;
+                let invoked = scanned.subcommand_opt.as_ref().is_some_and(|(_, name)| {
+                    subcommand.get_name() == name.as_str()
+                        || subcommand
+                            .get_all_aliases()
+                            .any(|alias| alias == name.as_str())
+                });
+                if invoked && !scanned.given(arg) {
+                    push_option_args(&mut subcommand_args, arg, subvalue)
+                        .map_err(|err| format!("Invalid {key}.{subkey} in config {path}: {err}"))?;
+                }
+            }
+            continue;
+        }
+        let arg = find_arg(command, key)
+            .ok_or_else(|| format!("Unknown option {key} in config {path}"))?;
+        if !scanned.given(arg) {
+            push_option_args(&mut global_args, arg, value)
+                .map_err(|err| format!("Invalid {key} in config {path}: {err}"))?;
+        }
+    }
+
+    // The options of the subcommand follow its name.
+    let subcommand_end = match &scanned.subcommand_opt {
+        Some((index, _)) => index + 1,
+        None => args.len(),
+    };
+    let mut merged = vec![];
+    merged.extend(args.iter().take(1).cloned());
+    merged.extend(global_args.into_iter().map(OsString::from));
+    merged.extend(args.iter().take(subcommand_end).skip(1).cloned());
+    merged.extend(subcommand_args.into_iter().map(OsString::from));
+    merged.extend(args.iter().skip(subcommand_end).cloned());
+    Ok(merged)
+}
+
+/// What the command line gives, scanned before parsing it, which may fail
+/// without the options in the config file.
+struct ScannedArgs {
+    config_path_opt: Option<OsString>,
+    /// The index and the name of the subcommand
+    subcommand_opt: Option<(usize, String)>,
+    /// The long and the short options given
+    options: Vec<String>,
+}
+
+impl ScannedArgs {
+    /// Whether the option is given by the command line or its environment
+    /// variable.
+    fn given(&self, arg: &Arg) -> bool {
+        let by_long = arg
+            .get_long()
+            .is_some_and(|long| self.options.iter().any(|option| *option == long));
+        let by_short = arg.get_short().is_some_and(|short| {
+            self.options
+                .iter()
+                .any(|option| *option == short.to_string())
+        });
+        let by_env = arg
+            .get_env()
+            .is_some_and(|env| std::env::var_os(env).is_some());
+        by_long || by_short || by_env
+    }
+}
+
+fn scan_args(command: &Command, args: &[OsString]) -> ScannedArgs {
+    let mut scanned = ScannedArgs {
+        config_path_opt: None,
+        subcommand_opt: None,
+        options: vec![],
+    };
+    let mut current = command;
+    let mut index = 1;
+    while let Some(token) = args.get(index) {
+        let token = token.to_string_lossy();
+        if token == "--" {
+            break;
+        }
+        if let Some(option) = token.strip_prefix("--") {
+            let (long, inline_value_opt) = match option.split_once('=') {
+                Some((long, value)) => (long, Some(value)),
+                None => (option, None),
+            };
+            let takes_value = find_arg(current, long)
+                .is_some_and(|arg| arg.get_action().takes_values())
+                || long == "config";
+            let value_opt = match inline_value_opt {
+                Some(value) => Some(OsString::from(value)),
+                None if takes_value => {
+                    index += 1;
+                    args.get(index).cloned()
+                }
+                None => None,
+            };
+            if long == "config" && scanned.subcommand_opt.is_none() {
+                scanned.config_path_opt = value_opt;
+            }
+            scanned.options.push(long.to_string());
+        } else if let Some(shorts) = token.strip_prefix('-').filter(|shorts| !shorts.is_empty()) {
+            let mut takes_value = false;
+            for short in shorts.chars() {
+                scanned.options.push(short.to_string());
+                takes_value = current
+                    .get_arguments()
+                    .find(|arg| arg.get_short() == Some(short))
+                    .is_some_and(|arg| arg.get_action().takes_values());
+            }
+            if takes_value && shorts.chars().count() == 1 {
+                index += 1;
+            }
+        } else if scanned.subcommand_opt.is_none() {
+            match command.find_subcommand(token.as_ref()) {
+                Some(subcommand) => {
+                    scanned.subcommand_opt = Some((index, token.to_string()));
+                    current = subcommand;
+                }
+                None => break,
+            }
+        }
+        index += 1;
+    }
+    scanned
+}
+
+fn find_arg<'a>(command: &'a Command, long: &str) -> Option<&'a Arg> {
+    command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(long))
+}
+
+/// Push the option of the value: the flag for `true`, one per element of an
+/// array, and the option with the value otherwise.
+fn push_option_args(
+    args: &mut Vec<String>,
+    arg: &Arg,
+    value: &toml::Value,
+) -> Result<(), Box<dyn Error>> {
+    let long = arg.get_long().unwrap_or_default();
+    match (arg.get_action(), value) {
+        (ArgAction::SetTrue, toml::Value::Boolean(true)) => args.push(format!("--{long}")),
+        (ArgAction::SetTrue, toml::Value::Boolean(false)) => {}
+        (ArgAction::SetTrue, _) => Err("not a boolean")?,
+        (ArgAction::Count, toml::Value::Integer(count)) => {
+            for _ in 0..*count {
+                args.push(format!("--{long}"));
+            }
+        }
+        (_, toml::Value::Array(values)) if matches!(arg.get_action(), ArgAction::Append) => {
+            for value in values {
+                args.push(format!("--{long}={}", option_value(value)?));
+            }
+        }
+        (_, value) => args.push(format!("--{long}={}", option_value(value)?)),
+    }
+    Ok(())
+}
+
+fn option_value(value: &toml::Value) -> Result<String, Box<dyn Error>> {
+    match value {
+        toml::Value::String(s) => interpolate_env(s),
+        toml::Value::Integer(n) => Ok(n.to_string()),
+        toml::Value::Float(x) => Ok(x.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        _ => Err("not a string, a number or a boolean")?,
+    }
+}
+
+/// Replace "${VAR}" in the value by the environment variable, so that the
+/// secrets are not written in the file.
+fn interpolate_env(value: &str) -> Result<String, Box<dyn Error>> {
+    let mut interpolated = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed \"${{\" in {value:?}"))?;
+        let name = &rest[start + 2..start + end];
+        let env_value = std::env::var(name)
+            .map_err(|err| format!("failed to get environment variable {name}: {err}"))?;
+        interpolated.push_str(&rest[..start]);
+        interpolated.push_str(&env_value);
+        rest = &rest[start + end + 1..];
+    }
+    interpolated.push_str(rest);
+    Ok(interpolated)
+}
@@ -0,0 +1,66 @@
+use std::error::Error;
+
+use crate::richtext;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum CwMode {
+    /// Post "CW: <warning>" followed by the content
+    Prepend,
+    /// Post only the warning and the original link
+    SpoilerOnly,
+    /// Do not post items with a content warning
+    Skip,
+    /// Post the content as is with a self-label given by --cw-label
+    Label,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SelfLabel {
+    Sexual,
+    Nudity,
+    Porn,
+    GraphicMedia,
+}
+
+impl SelfLabel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SelfLabel::Sexual => "sexual",
+            SelfLabel::Nudity => "nudity",
+            SelfLabel::Porn => "porn",
+            SelfLabel::GraphicMedia => "graphic-media",
+        }
+    }
+}
+
+/// Split the content warning which Mastodon renders at the head of the RSS
+/// description as `<p><strong>Content warning:</strong> ...</p><hr />`.
+pub fn split_content_warning(description: &str) -> Result<(Option<String>, &str), Box<dyn Error>> {
+    let head_end = match description.find("</p><hr />") {
+        Some(idx) if description.starts_with("<p><strong>") => idx,
+        _ => return Ok((None, description)),
+    };
+    let head = &description[..head_end];
+    let label_end = match head.find("</strong>") {
+        Some(idx) => idx + "</strong>".len(),
+        None => return Ok((None, description)),
+    };
+
+    let warning = richtext::to_plain_text(&richtext::from_html(
+        &head[label_end..],
+        &Default::default(),
+    )?);
+
+    let body = &description[head_end + "</p><hr />".len()..];
+    Ok((Some(warning.trim().to_string()), body))
+}
+
+/// Use the item title as the content warning, as some servers put the spoiler
+/// text there instead of the description.
+pub fn title_content_warning(item: &rss::Item) -> Option<String> {
+    item.title
+        .as_deref()
+        .map(str::trim)
+        .filter(|title| !title.is_empty())
+        .map(str::to_string)
+}
@@ -0,0 +1,352 @@
+use atrium_api::app::bsky;
+use atrium_api::blob::BlobRef;
+use futures_util::stream::{self, StreamExt};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::error::Error;
+
+use crate::image_resize;
+use crate::logging::Event;
+use crate::mime_sniff;
+use crate::og_meta;
+use crate::post_record::{AspectRatio, CustomEmbed, ImagesEmbed, ImagesEmbedImage, VideoEmbed};
+use crate::state_store::{CachedBlob, StateStore};
+use crate::truncate;
+use crate::video_frame;
+use crate::xrpc_client::{UploadTypedBlob, XrpcHttpClient};
+
+/// The maximum number of images in a post.
+pub const IMAGES_LIMIT: usize = 4;
+
+/// The maximum size of a video Bluesky accepts.
+pub const VIDEO_BYTES_LIMIT: usize = 100_000_000;
+
+/// The maximum length of a video Bluesky accepts, in seconds.
+pub const VIDEO_DURATION_LIMIT: u32 = 180;
+
+/// The maximum length of alt texts in graphemes, as the Bluesky app allows.
+const ALT_GRAPHEMES_LIMIT: usize = 2000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ImageOptions {
+    pub max_dimension: u32,
+    /// The number of images downloaded and uploaded at once
+    pub upload_concurrency: usize,
+    /// Remove the EXIF and XMP metadata of JPEG images
+    pub strip_exif: bool,
+}
+
+#[derive(Debug)]
+pub struct EmbedImage {
+    pub url: String,
+    pub alt: String,
+    /// Whether the URL is of a video, whose first frame is the image
+    pub still_frame: bool,
+}
+
+#[derive(Debug)]
+pub enum PostEmbed {
+    Images {
+        images: Vec<EmbedImage>,
+        options: ImageOptions,
+    },
+    Video {
+        url: String,
+        alt: Option<String>,
+        max_bytes: usize,
+    },
+    External {
+        uri: String,
+        /// The thumbnail preferred to the image of the page
+        thumb_opt: Option<EmbedImage>,
+        options: ImageOptions,
+    },
+}
+
+#[derive(Debug)]
+pub enum BuiltEmbed {
+    Record(bsky::feed::post::RecordEmbedEnum),
+    Custom(CustomEmbed),
+}
+
+struct UploadedImage {
+    blob: BlobRef,
+    aspect_ratio_opt: Option<AspectRatio>,
+}
+
+/// Build the embed, which is `None` when no image can be uploaded. Uploaded
+/// blobs are cached in the state store by their URL and content hash.
+pub async fn build_embed<Client>(
+    client: &Client,
+    blob_cache: &mut dyn StateStore,
+    embed: PostEmbed,
+) -> Result<Option<BuiltEmbed>, Box<dyn Error>>
+where
+    Client: XrpcHttpClient + UploadTypedBlob + Sync,
+{
+    use bsky::feed::post;
+
+    // The cache is borrowed only between awaits, so concurrent uploads share it.
+    let blob_cache = RefCell::new(blob_cache);
+    match embed {
+        PostEmbed::Images { images, options } => {
+            let results: Vec<_> = stream::iter(images.into_iter().map(|image| {
+                let blob_cache = &blob_cache;
+                async move {
+                    let result = upload_remote_image_to_bsky(
+                        client,
+                        blob_cache,
+                        &image.url,
+                        options,
+                        image.still_frame,
+                    )
+                    .await;
+                    (image.alt, result)
+                }
+            }))
+            .buffered(options.upload_concurrency.max(1))
+            .collect()
+            .await;
+
+            let mut uploaded = vec![];
+            for (alt, result) in results {
+                // Images which cannot be uploaded are logged and skipped.
+                if let Some(image) = result? {
+                    uploaded.push(ImagesEmbedImage {
+                        alt: truncate::truncate_graphemes(&alt, ALT_GRAPHEMES_LIMIT),
+                        image: image.blob,
+                        aspect_ratio: image.aspect_ratio_opt,
+                    });
+                }
+            }
+            if uploaded.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(BuiltEmbed::Custom(CustomEmbed::Images(
+                ImagesEmbed::new(uploaded),
+            ))))
+        }
+        PostEmbed::Video {
+            url,
+            alt,
+            max_bytes,
+        } => {
+            let alt = alt.map(|alt| truncate::truncate_graphemes(&alt, ALT_GRAPHEMES_LIMIT));
+            let url_key = format!("video:url:{url}");
+
+            if let Some(cached) = blob_cache.borrow().cached_blob(&url_key) {
+                return Ok(Some(BuiltEmbed::Custom(CustomEmbed::Video(
+                    VideoEmbed::new(cached.blob, alt),
+                ))));
+            }
+
+            let remote_content = client.get_remote_content(&url).await?;
+            let content = remote_content.content;
+            let hash_key = format!("video:sha256:{}", content_hash(&content));
+            // Release the borrow before caching the blob.
+            let cached_opt = blob_cache.borrow().cached_blob(&hash_key);
+            if let Some(cached) = cached_opt {
+                blob_cache.borrow_mut().cache_blob(&url_key, &cached)?;
+                return Ok(Some(BuiltEmbed::Custom(CustomEmbed::Video(
+                    VideoEmbed::new(cached.blob, alt),
+                ))));
+            }
+            // The feed may not tell the size of the video.
+            if content.len() > max_bytes {
+                Event::warn(format!(
+                    "Ignore a video over {max_bytes} bytes: {url} ({} bytes)",
+                    content.len()
+                ))
+                .emit();
+                return Ok(None);
+            }
+            let content_type_opt = remote_content.content_type_opt.as_deref();
+            let mime_type = match mime_sniff::video_mime(&content) {
+                Some(mime_type) if mime_sniff::is_content_type_of(content_type_opt, "video") => {
+                    mime_type
+                }
+                _ => {
+                    Event::warn(format!(
+                        "Ignore a video of an unsupported format: {url} ({content_type_opt:?})"
+                    ))
+                    .emit();
+                    return Ok(None);
+                }
+            };
+
+            let output = client
+                .upload_typed_blob(content.to_vec(), mime_type)
+                .await?;
+            let cached = CachedBlob {
+                blob: output.blob.clone(),
+                dimensions_opt: None,
+            };
+            blob_cache.borrow_mut().cache_blob(&url_key, &cached)?;
+            blob_cache.borrow_mut().cache_blob(&hash_key, &cached)?;
+            Ok(Some(BuiltEmbed::Custom(CustomEmbed::Video(
+                VideoEmbed::new(output.blob, alt),
+            ))))
+        }
+        PostEmbed::External {
+            uri,
+            thumb_opt,
+            options,
+        } => {
+            let external = build_external(client, &blob_cache, uri, thumb_opt, options).await?;
+            Ok(Some(BuiltEmbed::Record(
+                post::RecordEmbedEnum::AppBskyEmbedExternalMain(Box::new(
+                    bsky::embed::external::Main { external },
+                )),
+            )))
+        }
+    }
+}
+
+async fn build_external<Client>(
+    client: &Client,
+    blob_cache: &RefCell<&mut dyn StateStore>,
+    uri: String,
+    thumb_opt: Option<EmbedImage>,
+    options: ImageOptions,
+) -> Result<bsky::embed::external::External, Box<dyn Error>>
+where
+    Client: XrpcHttpClient + UploadTypedBlob + Sync,
+{
+    let page = client.get_remote_content(&uri).await?;
+    let meta = og_meta::parse_og_meta(&uri, &String::from_utf8_lossy(&page.content));
+
+    let mut thumb = None;
+    if let Some(image) = thumb_opt {
+        match upload_remote_image_to_bsky(
+            client,
+            blob_cache,
+            &image.url,
+            options,
+            image.still_frame,
+        )
+        .await
+        {
+            Ok(image_opt) => thumb = image_opt.map(|image| image.blob),
+            Err(err) => {
+                Event::warn(format!("Failed to upload the card thumbnail {}", image.url))
+                    .error(err)
+                    .emit();
+            }
+        }
+    }
+    let thumb = match &meta.image {
+        _ if thumb.is_some() => thumb,
+        Some(image_url) => {
+            match upload_remote_image_to_bsky(client, blob_cache, image_url, options, false).await {
+                Ok(image_opt) => image_opt.map(|image| image.blob),
+                Err(err) => {
+                    Event::warn(format!("Failed to upload the card thumbnail {image_url}"))
+                        .error(err)
+                        .emit();
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
+    Ok(bsky::embed::external::External {
+        title: meta.title.unwrap_or_else(|| uri.to_string()),
+        description: meta.description.unwrap_or_default(),
+        thumb,
+        uri,
+    })
+}
+
+async fn upload_remote_image_to_bsky<Client>(
+    client: &Client,
+    blob_cache: &RefCell<&mut dyn StateStore>,
+    image_url: &str,
+    options: ImageOptions,
+    still_frame: bool,
+) -> Result<Option<UploadedImage>, Box<dyn Error>>
+where
+    Client: XrpcHttpClient + UploadTypedBlob + Sync,
+{
+    // The uploaded image depends on the options as well as the source.
+    let key_prefix = format!(
+        "image:{}:{}:{}",
+        options.max_dimension, options.strip_exif, still_frame
+    );
+    let url_key = format!("{key_prefix}:url:{image_url}");
+    if let Some(cached) = blob_cache.borrow().cached_blob(&url_key) {
+        return Ok(Some(UploadedImage::from(cached)));
+    }
+
+    let remote_content = client.get_remote_content(image_url).await?;
+    let content_type_opt = remote_content.content_type_opt.as_deref();
+    let expected_kind = if still_frame { "video" } else { "image" };
+    if !mime_sniff::is_content_type_of(content_type_opt, expected_kind) {
+        Event::warn(format!(
+            "Ignore a non-{expected_kind} content: {image_url} ({content_type_opt:?})"
+        ))
+        .emit();
+        return Ok(None);
+    }
+    let hash_key = format!(
+        "{key_prefix}:sha256:{}",
+        content_hash(&remote_content.content)
+    );
+    // Release the borrow before caching the blob.
+    let cached_opt = blob_cache.borrow().cached_blob(&hash_key);
+    if let Some(cached) = cached_opt {
+        blob_cache.borrow_mut().cache_blob(&url_key, &cached)?;
+        return Ok(Some(UploadedImage::from(cached)));
+    }
+    let mut content = remote_content.content.to_vec();
+    if still_frame {
+        content = video_frame::extract_still_frame(&content)?;
+    }
+
+    let mut content = match image_resize::fit_image(&content, options.max_dimension)? {
+        Some(content) => content,
+        None => {
+            Event::warn(format!(
+                "Ignore an image of an unsupported format: {image_url}"
+            ))
+            .emit();
+            return Ok(None);
+        }
+    };
+    if options.strip_exif {
+        content = image_resize::strip_exif(content);
+    }
+    // The image is converted into one of these formats if needed.
+    let mime_type = match mime_sniff::image_mime(&content) {
+        Some(mime_type) => mime_type,
+        None => {
+            Event::warn(format!("Ignore an image of an unknown format: {image_url}")).emit();
+            return Ok(None);
+        }
+    };
+    let dimensions_opt = image_resize::image_dimensions(&content);
+    let output = client.upload_typed_blob(content, mime_type).await?;
+    let cached = CachedBlob {
+        blob: output.blob,
+        dimensions_opt,
+    };
+    blob_cache.borrow_mut().cache_blob(&url_key, &cached)?;
+    blob_cache.borrow_mut().cache_blob(&hash_key, &cached)?;
+
+    Ok(Some(UploadedImage::from(cached)))
+}
+
+impl From<CachedBlob> for UploadedImage {
+    fn from(cached: CachedBlob) -> Self {
+        UploadedImage {
+            blob: cached.blob,
+            aspect_ratio_opt: cached
+                .dimensions_opt
+                .map(|(width, height)| AspectRatio { width, height }),
+        }
+    }
+}
+
+fn content_hash(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
@@ -0,0 +1,42 @@
+use crate::richtext::{self, RichText, RichTextSegment};
+
+/// Remove hashtag links from the text, except the ones in the allowlist, which
+/// is matched case-insensitively with or without the leading '#'.
+pub fn strip_hashtags(text: RichText, allowlist: &[String]) -> RichText {
+    let is_stripped = |seg: &RichTextSegment| match seg {
+        RichTextSegment::Link { text, .. } => match text.strip_prefix('#') {
+            Some(tag) => !allowlist
+                .iter()
+                .any(|allowed| allowed.trim_start_matches('#').eq_ignore_ascii_case(tag)),
+            None => false,
+        },
+        RichTextSegment::PlainText { .. } | RichTextSegment::Mention { .. } => false,
+    };
+
+    let mut stripped: RichText = vec![];
+    let mut after_stripped = false;
+    for seg in text {
+        if is_stripped(&seg) {
+            after_stripped = true;
+            continue;
+        }
+        match seg {
+            // Do not leave a double space where the hashtag was.
+            RichTextSegment::PlainText { text }
+                if after_stripped
+                    && text.starts_with(' ')
+                    && stripped
+                        .last()
+                        .is_none_or(|last| last.text().ends_with(' ')) =>
+            {
+                stripped.push(RichTextSegment::PlainText {
+                    text: text[1..].to_string(),
+                });
+            }
+            seg => stripped.push(seg),
+        }
+        after_stripped = false;
+    }
+    richtext::trim_end(&mut stripped);
+    stripped
+}
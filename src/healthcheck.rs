@@ -0,0 +1,173 @@
+use chrono::{DateTime, Duration, Utc};
+use std::error::Error;
+use std::fs::{File, TryLockError};
+use std::io::{BufRead, BufReader, ErrorKind};
+
+use crate::bridge::{self, DbConfig};
+use crate::journal::{JournalAction, JournalEntry};
+use crate::s3_state;
+use crate::state_store::{self, DbBackend};
+
+/// What the journal tells about the runs.
+pub struct RunHistory {
+    /// When the last run which has not finished started
+    pub unfinished_since_opt: Option<DateTime<Utc>>,
+    pub last_finished_opt: Option<DateTime<Utc>>,
+}
+
+/// Read the runs in the journal. Broken lines are skipped, as a crashed run
+/// may leave one at the end.
+pub fn read_runs(journal_path: &str) -> Result<RunHistory, Box<dyn Error>> {
+    let mut runs = RunHistory {
+        unfinished_since_opt: None,
+        last_finished_opt: None,
+    };
+    let journal_file = match File::open(journal_path) {
+        Ok(journal_file) => journal_file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(runs),
+        Err(err) => Err(format!("Failed to open journal: {err}"))?,
+    };
+    let mut unfinished_run_opt: Option<(String, DateTime<Utc>)> = None;
+    for line in BufReader::new(journal_file).lines() {
+        let line = line.map_err(|err| format!("Failed to read journal: {err}"))?;
+        let Ok(entry) = serde_json::from_str::<JournalEntry>(&line) else {
+            continue;
+        };
+        match entry.action {
+            JournalAction::RunStarted { .. } => {
+                unfinished_run_opt = Some((entry.run_id, entry.at));
+            }
+            // A failed run stops, and releases the lock.
+            JournalAction::RunFinished | JournalAction::Failed { .. } => {
+                if unfinished_run_opt
+                    .as_ref()
+                    .is_some_and(|(run_id, _)| *run_id == entry.run_id)
+                {
+                    unfinished_run_opt = None;
+                }
+                if matches!(entry.action, JournalAction::RunFinished) {
+                    runs.last_finished_opt = Some(entry.at);
+                }
+            }
+            _ => {}
+        }
+    }
+    runs.unfinished_since_opt = unfinished_run_opt.map(|(_, started_at)| started_at);
+    Ok(runs)
+}
+
+/// Check that no run has held the lock of the DB over the stale lock age. The
+/// lock is tried only when the journal has a run unfinished for so long, so
+/// that the check does not block a run starting.
+pub async fn check_lock(db_config: &DbConfig, runs: &RunHistory) -> Result<String, Box<dyn Error>> {
+    let stale_lock_age = Duration::seconds(i64::try_from(db_config.stale_lock_secs)?);
+    if db_config.backend == DbBackend::S3 {
+        let s3_config = db_config
+            .s3_opt
+            .as_ref()
+            .ok_or("The S3 backend needs an S3 bucket and credentials.")?;
+        return match s3_state::locked_at(bridge::new_reqwest_client()?, s3_config).await? {
+            Some(locked_at) if Utc::now() - locked_at >= stale_lock_age => Err(format!(
+                "The remote lock is held since {}",
+                locked_at.to_rfc3339()
+            ))?,
+            Some(locked_at) => Ok(format!(
+                "The remote lock is held since {}",
+                locked_at.to_rfc3339()
+            )),
+            None => Ok(String::from("The remote lock is free")),
+        };
+    }
+
+    let started_at = match runs.unfinished_since_opt {
+        Some(started_at) if Utc::now() - started_at >= stale_lock_age => started_at,
+        Some(started_at) => {
+            return Ok(format!("A run is going since {}", started_at.to_rfc3339()));
+        }
+        None => return Ok(String::from("No runs are going")),
+    };
+    let lock_file = match File::open(bridge::lock_path(&db_config.db_path)) {
+        Ok(lock_file) => lock_file,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Ok(String::from("No DB to lock"));
+        }
+        Err(err) => Err(format!("Failed to open lock file: {err}"))?,
+    };
+    match lock_file.try_lock_shared() {
+        // The run stopped without finishing, which the last run check tells.
+        Ok(()) => Ok(format!(
+            "The run since {} has released the lock",
+            started_at.to_rfc3339()
+        )),
+        Err(TryLockError::WouldBlock) => Err(format!(
+            "The lock is held by a run since {}",
+            started_at.to_rfc3339()
+        ))?,
+        Err(TryLockError::Error(err)) => Err(format!("Failed to check lock: {err}"))?,
+    }
+}
+
+/// Check that the links in the DB can be read.
+pub fn check_db(db_config: &DbConfig) -> Result<String, Box<dyn Error>> {
+    let links_count = match db_config.backend {
+        // The S3 backend is checked by its local copy, which the last run pulled.
+        DbBackend::File | DbBackend::S3 => state_store::read_links(&db_config.db_path)?.len(),
+        DbBackend::Redis | DbBackend::Sqlite => {
+            bridge::open_state_store(db_config, None)?.links().len()
+        }
+    };
+    Ok(format!("{links_count} links"))
+}
+
+/// Check that the last run has finished within the age.
+pub fn check_last_run(runs: &RunHistory, max_age: Duration) -> Result<String, Box<dyn Error>> {
+    match runs.last_finished_opt {
+        Some(finished_at) if Utc::now() - finished_at > max_age => Err(format!(
+            "The last run finished at {}, over {} seconds ago",
+            finished_at.to_rfc3339(),
+            max_age.num_seconds()
+        ))?,
+        Some(finished_at) => Ok(format!(
+            "The last run finished at {}",
+            finished_at.to_rfc3339()
+        )),
+        None => Err("No runs have finished in the journal")?,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_runs_with_guids() {
+        let path = std::env::temp_dir()
+            .join(format!("healthcheck-test-{}-journal", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        std::fs::write(
+            &path,
+            concat!(
+                r#"{"run_id":"1","at":"2024-01-01T00:00:00Z","action":"run_started","feed_url":"https://example.com/feed"}"#,
+                "\n",
+                r#"{"run_id":"1","at":"2024-01-01T00:00:01Z","action":"posted","link":"https://example.com/1","uri":"at://post/1","cid":"cid","guid":"1"}"#,
+                "\n",
+                r#"{"run_id":"1","at":"2024-01-01T00:00:02Z","action":"run_finished"}"#,
+                "\n",
+                r#"{"run_id":"2","at":"2024-01-02T00:00:00Z","action":"run_started","feed_url":"https://example.com/feed"}"#,
+                "\n",
+                r#"{"run_id":"2","at":"2024-01-02T00:00:01Z","action":"failed","link_opt":"https://example.com/2","error":"Failed","guid":"2"}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+
+        let runs = read_runs(&path).unwrap();
+        assert!(runs.unfinished_since_opt.is_none());
+        assert_eq!(
+            runs.last_finished_opt,
+            Some("2024-01-01T00:00:02Z".parse().unwrap())
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+}
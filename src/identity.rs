@@ -0,0 +1,103 @@
+use serde::Deserialize;
+use std::error::Error;
+
+/// The server which resolves handles for the accounts on any PDS.
+const HANDLE_RESOLVER_HOST: &str = "https://bsky.social";
+
+const PLC_DIRECTORY: &str = "https://plc.directory";
+
+#[derive(Deserialize)]
+struct ResolveHandleOutput {
+    did: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidDocument {
+    #[serde(default)]
+    service: Vec<DidService>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidService {
+    id: String,
+    service_endpoint: String,
+}
+
+/// Find the PDS of the account by its identifier, which is a handle or a DID.
+/// Emails cannot be resolved, and return `None`.
+pub async fn resolve_pds(
+    client: &reqwest::Client,
+    identifier: &str,
+) -> Result<Option<String>, Box<dyn Error>> {
+    Ok(resolve_identity(client, identifier)
+        .await?
+        .map(|(_, pds)| pds))
+}
+
+/// Find the DID and the PDS of the account by its identifier, as
+/// `resolve_pds`.
+pub async fn resolve_identity(
+    client: &reqwest::Client,
+    identifier: &str,
+) -> Result<Option<(String, String)>, Box<dyn Error>> {
+    let did = if identifier.starts_with("did:") {
+        identifier.to_string()
+    } else if identifier.contains('@') {
+        return Ok(None);
+    } else {
+        resolve_handle(client, identifier).await?
+    };
+    let pds = resolve_did_pds(client, &did).await?;
+    Ok(Some((did, pds)))
+}
+
+/// Resolve the handle to its DID by `com.atproto.identity.resolveHandle`,
+/// which sees both the DNS record and the well-known URL of the handle.
+async fn resolve_handle(client: &reqwest::Client, handle: &str) -> Result<String, Box<dyn Error>> {
+    let mut url = reqwest::Url::parse(HANDLE_RESOLVER_HOST)?
+        .join("/xrpc/com.atproto.identity.resolveHandle")?;
+    url.query_pairs_mut().append_pair("handle", handle);
+    let body = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|err| format!("Failed to resolve handle {handle}: {err}"))?
+        .bytes()
+        .await
+        .map_err(|err| format!("Failed to resolve handle {handle}: {err}"))?;
+    let output: ResolveHandleOutput = serde_json::from_slice(&body)
+        .map_err(|err| format!("Failed to resolve handle {handle}: {err}"))?;
+    Ok(output.did)
+}
+
+/// The PDS endpoint in the DID document, of `did:plc` in the PLC directory
+/// or of `did:web` at its well-known URL.
+async fn resolve_did_pds(client: &reqwest::Client, did: &str) -> Result<String, Box<dyn Error>> {
+    let document_url = if did.starts_with("did:plc:") {
+        format!("{PLC_DIRECTORY}/{did}")
+    } else if let Some(host) = did.strip_prefix("did:web:") {
+        format!("https://{host}/.well-known/did.json")
+    } else {
+        Err(format!("Unsupported DID method: {did}"))?
+    };
+    let body = client
+        .get(&document_url)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|err| format!("Failed to get DID document of {did}: {err}"))?
+        .bytes()
+        .await
+        .map_err(|err| format!("Failed to get DID document of {did}: {err}"))?;
+    let document: DidDocument = serde_json::from_slice(&body)
+        .map_err(|err| format!("Failed to get DID document of {did}: {err}"))?;
+    let service = document
+        .service
+        .into_iter()
+        .find(|service| service.id == "#atproto_pds" || service.id == format!("{did}#atproto_pds"))
+        .ok_or_else(|| format!("No PDS in the DID document of {did}"))?;
+    Ok(service.service_endpoint.trim_end_matches('/').to_string())
+}
@@ -0,0 +1,170 @@
+use html5ever::tendril::SliceExt;
+use html5ever::tokenizer::{
+    BufferQueue, Tag, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer,
+};
+
+#[derive(Debug)]
+struct ImageAlt {
+    src: String,
+    alt: String,
+}
+
+struct ImageAltSink {
+    alts: Vec<ImageAlt>,
+    /// The sources of all images in the document order
+    srcs: Vec<String>,
+    figure_srcs: Vec<String>,
+    figcaption_opt: Option<String>,
+    in_figcaption: bool,
+}
+
+impl ImageAltSink {
+    fn process_img(&mut self, tag: &Tag) {
+        let mut src_opt: Option<String> = None;
+        let mut alt_opt: Option<String> = None;
+        for attr in &tag.attrs {
+            match attr.name.local.to_string().as_str() {
+                "src" => {
+                    src_opt = Some(attr.value.to_string());
+                }
+                "alt" => {
+                    alt_opt = Some(attr.value.to_string());
+                }
+                _ => {
+                    // do nothing
+                }
+            }
+        }
+
+        if let Some(src) = src_opt {
+            if let Some(alt) = alt_opt.filter(|alt| !alt.trim().is_empty()) {
+                self.alts.push(ImageAlt {
+                    src: src.to_string(),
+                    alt: alt.trim().to_string(),
+                });
+            }
+            self.srcs.push(src.to_string());
+            self.figure_srcs.push(src);
+        }
+    }
+
+    fn end_figure(&mut self) {
+        let srcs = std::mem::take(&mut self.figure_srcs);
+        let caption = match self.figcaption_opt.take() {
+            Some(caption) if !caption.trim().is_empty() => caption.trim().to_string(),
+            _ => return,
+        };
+        // The caption takes precedence over the alt attribute.
+        for src in srcs {
+            self.alts.insert(
+                0,
+                ImageAlt {
+                    src,
+                    alt: caption.to_string(),
+                },
+            );
+        }
+    }
+}
+
+impl TokenSink for ImageAltSink {
+    type Handle = ();
+
+    fn process_token(&mut self, token: Token, _line_number: u64) -> TokenSinkResult<Self::Handle> {
+        match token {
+            Token::CharacterTokens(bs) if self.in_figcaption => {
+                self.figcaption_opt
+                    .get_or_insert_with(String::new)
+                    .push_str(&bs);
+            }
+            Token::TagToken(tag) => match (tag.kind, tag.name.to_string().as_str()) {
+                (TagKind::StartTag, "img") => {
+                    self.process_img(&tag);
+                }
+                (TagKind::StartTag, "figure") => {
+                    self.figure_srcs.clear();
+                    self.figcaption_opt = None;
+                }
+                (TagKind::EndTag, "figure") => {
+                    self.end_figure();
+                }
+                (TagKind::StartTag, "figcaption") => {
+                    self.in_figcaption = true;
+                }
+                (TagKind::EndTag, "figcaption") => {
+                    self.in_figcaption = false;
+                }
+                _ => {
+                    // do nothing
+                }
+            },
+            _ => {
+                // do nothing
+            }
+        }
+        TokenSinkResult::Continue
+    }
+}
+
+/// The last path segment, to match an image with its copy on another host.
+fn file_name(url: &str) -> &str {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+fn scan_images(html: &str) -> ImageAltSink {
+    let mut tokenizer = Tokenizer::new(
+        ImageAltSink {
+            alts: vec![],
+            srcs: vec![],
+            figure_srcs: vec![],
+            figcaption_opt: None,
+            in_figcaption: false,
+        },
+        Default::default(),
+    );
+
+    let mut queue = BufferQueue::new();
+    queue.push_back(html.to_tendril());
+
+    let _ = tokenizer.feed(&mut queue);
+    tokenizer.end();
+    tokenizer.sink
+}
+
+/// Find the alt text of the image in the HTML content, preferring the caption
+/// of the enclosing `<figure>` over the `alt` attribute.
+pub fn find_image_alt(html: &str, image_url: &str) -> Option<String> {
+    let alts = scan_images(html).alts;
+    alts.iter()
+        .find(|image_alt| image_alt.src == image_url)
+        .or_else(|| {
+            alts.iter().find(|image_alt| {
+                !file_name(image_url).is_empty()
+                    && file_name(&image_alt.src) == file_name(image_url)
+            })
+        })
+        .map(|image_alt| image_alt.alt.to_string())
+}
+
+/// Find the http(s) URLs of the inline `<img>` elements in the HTML content,
+/// resolving relative ones against the base URL.
+pub fn find_inline_images(html: &str, base_url: &str) -> Vec<String> {
+    let base_opt = reqwest::Url::parse(base_url).ok();
+    let mut urls: Vec<String> = vec![];
+    for src in scan_images(html).srcs {
+        let url = match &base_opt {
+            Some(base) => base.join(&src),
+            None => reqwest::Url::parse(&src),
+        };
+        // Data URIs and the like cannot be fetched.
+        let url = match url {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url.to_string(),
+            _ => continue,
+        };
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    urls
+}
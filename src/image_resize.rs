@@ -0,0 +1,121 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::io::Reader as ImageReader;
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use std::error::Error;
+use std::io::Cursor;
+
+/// The maximum size of a blob Bluesky accepts.
+pub const BLOB_BYTES_LIMIT: usize = 976_560;
+
+/// JPEG qualities tried in order until the image fits in the blob limit.
+const JPEG_QUALITIES: &[u8] = &[90, 80, 70, 60, 50, 40];
+
+/// Formats Bluesky clients render, uploaded without conversion.
+const SUPPORTED_FORMATS: &[ImageFormat] = &[
+    ImageFormat::Jpeg,
+    ImageFormat::Png,
+    ImageFormat::WebP,
+    ImageFormat::Gif,
+];
+
+/// Convert the image into a format Bluesky clients render, and downscale and
+/// re-encode it as JPEG when it exceeds the blob limit or the maximum dimension.
+/// Return `None` for formats which cannot be decoded, such as AVIF and HEIC.
+pub fn fit_image(content: &[u8], max_dimension: u32) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let reader = ImageReader::new(Cursor::new(content))
+        .with_guessed_format()
+        .map_err(|err| format!("Failed to guess the image format: {err}"))?;
+    let format = match reader.format() {
+        Some(format) if SUPPORTED_FORMATS.contains(&format) => format,
+        Some(format) if format.reading_enabled() => {
+            return fit_image(&convert_to_png(content)?, max_dimension);
+        }
+
+        _ => return Ok(None),
+    };
+    let (width, height) = ImageReader::with_format(Cursor::new(content), format)
+        .into_dimensions()
+        .map_err(|err| format!("Failed to read the image dimensions: {err}"))?;
+    if content.len() <= BLOB_BYTES_LIMIT && width <= max_dimension && height <= max_dimension {
+        return Ok(Some(content.to_vec()));
+    }
+
+    let image = image::load_from_memory(content)
+        .map_err(|err| format!("Failed to decode the image: {err}"))?;
+    let mut dimension = max_dimension.min(width.max(height));
+    loop {
+        let resized = image.resize(dimension, dimension, FilterType::Lanczos3);
+        for quality in JPEG_QUALITIES {
+            let encoded = encode_jpeg(&resized, *quality)?;
+            if encoded.len() <= BLOB_BYTES_LIMIT {
+                return Ok(Some(encoded));
+            }
+        }
+        if dimension <= 1 {
+            return Err(Box::<dyn Error>::from(
+                "Failed to fit the image in the blob limit.",
+            ));
+        }
+        dimension = dimension * 3 / 4;
+    }
+}
+
+/// The width and the height read from the image header.
+pub fn image_dimensions(content: &[u8]) -> Option<(u32, u32)> {
+    ImageReader::new(Cursor::new(content))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Remove the APP1 segments, which hold the EXIF and XMP metadata such as GPS
+/// positions, from JPEG images. Other images are returned as they are.
+pub fn strip_exif(content: Vec<u8>) -> Vec<u8> {
+    if !content.starts_with(&[0xFF, 0xD8]) {
+        return content;
+    }
+
+    let mut stripped = vec![0xFF, 0xD8];
+    let mut pos = 2;
+    while pos + 4 <= content.len() && content[pos] == 0xFF {
+        let marker = content[pos + 1];
+        // The entropy-coded data follows the start of scan.
+        if marker == 0xDA {
+            break;
+        }
+        let length = usize::from(u16::from_be_bytes([content[pos + 2], content[pos + 3]]));
+        let end = pos + 2 + length;
+        if length < 2 || end > content.len() {
+            // Leave broken images to the server.
+            return content;
+        }
+        if marker != 0xE1 {
+            stripped.extend_from_slice(&content[pos..end]);
+        }
+        pos = end;
+    }
+    stripped.extend_from_slice(&content[pos..]);
+    stripped
+}
+
+fn convert_to_png(content: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let image = image::load_from_memory(content)
+        .map_err(|err| format!("Failed to decode the image: {err}"))?;
+    let mut encoded = Cursor::new(vec![]);
+    image
+        .write_to(&mut encoded, ImageOutputFormat::Png)
+        .map_err(|err| format!("Failed to convert the image to PNG: {err}"))?;
+    Ok(encoded.into_inner())
+}
+
+fn encode_jpeg(image: &DynamicImage, quality: u8) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut encoded = vec![];
+    // JPEG has no alpha channel.
+    image
+        .to_rgb8()
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut encoded, quality))
+        .map_err(|err| format!("Failed to encode the image: {err}"))?;
+    Ok(encoded)
+}
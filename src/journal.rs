@@ -0,0 +1,166 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+
+/// What a run did, as a line of the journal. The entries of an item have its
+/// guid when it has one, which tells apart the items of the same link.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum JournalAction {
+    RunStarted {
+        feed_url: String,
+    },
+    AlreadyPosted {
+        link: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        guid: Option<String>,
+    },
+    Skipped {
+        link: String,
+        reason: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        guid: Option<String>,
+    },
+    Posted {
+        link: String,
+        uri: String,
+        cid: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        guid: Option<String>,
+    },
+    Edited {
+        link: String,
+        uri: String,
+        cid: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        guid: Option<String>,
+    },
+    Deleted {
+        link: String,
+        uri: String,
+    },
+    /// Recorded as posted without posting, by `mark-posted`
+    Marked {
+        link: String,
+    },
+    Failed {
+        link_opt: Option<String>,
+        error: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        guid: Option<String>,
+    },
+    /// The new items left to the next run over the daily budget of the records
+    Deferred {
+        items: usize,
+    },
+    RunFinished,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub run_id: String,
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub action: JournalAction,
+}
+
+/// An append-only journal of the actions of runs, as JSON lines.
+///
+/// Each entry is flushed as it is written, so the journal tells what a run
+/// did until it stopped, even when it crashed.
+pub struct Journal {
+    file: File,
+    run_id: String,
+}
+
+impl Journal {
+    /// Open the journal for a new run, creating the file if it does not exist.
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| format!("Failed to open journal: {err}"))?;
+        let run_id = format!(
+            "{}-{}",
+            Utc::now().format("%Y%m%dT%H%M%SZ"),
+            std::process::id()
+        );
+        Ok(Journal { file, run_id })
+    }
+
+    pub fn record(&mut self, action: JournalAction) -> Result<(), Box<dyn Error>> {
+        let entry = JournalEntry {
+            run_id: self.run_id.to_string(),
+            at: Utc::now(),
+            action,
+        };
+        writeln!(self.file, "{}", serde_json::to_string(&entry)?)
+            .map_err(|err| format!("Failed to write journal: {err}"))?;
+        self.file
+            .flush()
+            .map_err(|err| format!("Failed to flush journal: {err}"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_keep_guids() {
+        let path = std::env::temp_dir()
+            .join(format!("journal-test-{}-guids", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let _ = std::fs::remove_file(&path);
+        let mut journal = Journal::open(&path).unwrap();
+        journal
+            .record(JournalAction::Posted {
+                link: String::from("https://example.com/1"),
+                uri: String::from("at://post/1"),
+                cid: String::from("cid"),
+                guid: Some(String::from("https://example.com/1#guid")),
+            })
+            .unwrap();
+        journal
+            .record(JournalAction::Skipped {
+                link: String::from("https://example.com/2"),
+                reason: String::from("local only"),
+                guid: None,
+            })
+            .unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert!(lines[0].contains(r#""guid":"https://example.com/1#guid""#));
+        // No guid is left out of the line.
+        assert!(!lines[1].contains("guid"));
+        let entries: Vec<JournalEntry> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(matches!(
+            &entries[0].action,
+            JournalAction::Posted { guid: Some(guid), .. } if guid == "https://example.com/1#guid"
+        ));
+        assert!(matches!(
+            &entries[1].action,
+            JournalAction::Skipped { guid: None, .. }
+        ));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn entries_without_guids_are_read() {
+        let line = r#"{"run_id":"20240101T000000Z-1","at":"2024-01-01T00:00:00Z","action":"failed","link_opt":null,"error":"Failed"}"#;
+        let entry: JournalEntry = serde_json::from_str(line).unwrap();
+        assert!(matches!(
+            entry.action,
+            JournalAction::Failed { guid: None, .. }
+        ));
+    }
+}
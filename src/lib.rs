@@ -0,0 +1,39 @@
+pub mod account;
+pub mod bridge;
+pub mod config_file;
+pub mod content_warning;
+mod embed;
+mod hashtags;
+pub mod healthcheck;
+mod identity;
+mod image_alt;
+mod image_resize;
+pub mod journal;
+mod lang_detect;
+mod local_only;
+pub mod logging;
+pub mod media_hosts;
+pub mod media_policy;
+pub mod mentions;
+mod mime_sniff;
+pub mod oauth;
+mod og_meta;
+mod post_record;
+pub mod rate_limit;
+pub mod redis_store;
+pub mod rewrite;
+pub mod richtext;
+mod rss_ext;
+pub mod s3_state;
+pub mod session;
+pub mod sqlite_store;
+pub mod state_store;
+pub mod stats;
+pub mod template;
+pub mod tracking_params;
+pub mod truncate;
+pub mod update_check;
+mod video_frame;
+mod xrpc_client;
+
+pub use bridge::{Bridge, BridgeBuilder, DbConfig, PostConfig};
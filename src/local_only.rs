@@ -0,0 +1,13 @@
+use crate::richtext::{self, RichText};
+
+/// Markers which Mastodon forks append to local-only posts, such as the eye
+/// glyph of glitch-soc.
+const LOCAL_ONLY_MARKERS: &[&str] = &["👁", "👁\u{fe0f}"];
+
+pub fn is_local_only(text: &RichText) -> bool {
+    let plain = richtext::to_plain_text(text);
+    let trimmed = plain.trim_end();
+    LOCAL_ONLY_MARKERS
+        .iter()
+        .any(|marker| trimmed.ends_with(marker))
+}
@@ -0,0 +1,211 @@
+use chrono::Utc;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt::Display;
+use std::io::Write;
+use std::sync::OnceLock;
+use tracing::Level;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+
+/// The logs of the other crates are not shown, as they are too verbose.
+const TARGET_PREFIX: &str = "mstdn_rss2bsky_post";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Lines of text to read
+    #[default]
+    Text,
+    /// A JSON object per line with the timestamp, the level, the feed, the
+    /// item link, the action and the error, to ship to a log store
+    Json,
+}
+
+static FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+fn format() -> LogFormat {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+/// What an event of a run is about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Posted,
+    Edited,
+    AlreadyPosted,
+    Skipped,
+    Deferred,
+    Deleted,
+    Marked,
+    DryRun,
+    Retry,
+    RateLimited,
+    Warning,
+    Failed,
+}
+
+/// An event of a run, printed as a line of text or of JSON by `--log-format`.
+/// The events of `Level::INFO` go to stdout, and the others to stderr.
+/// The text of a warning starts with "Warning: ".
+pub struct Event<'a> {
+    level: Level,
+    action: Action,
+    message: String,
+    feed_opt: Option<&'a str>,
+    link_opt: Option<&'a str>,
+    guid_opt: Option<&'a str>,
+    error_opt: Option<String>,
+}
+
+impl<'a> Event<'a> {
+    pub fn new(level: Level, action: Action, message: impl Into<String>) -> Self {
+        Event {
+            level,
+            action,
+            message: message.into(),
+            feed_opt: None,
+            link_opt: None,
+            guid_opt: None,
+            error_opt: None,
+        }
+    }
+
+    pub fn info(action: Action, message: impl Into<String>) -> Self {
+        Self::new(Level::INFO, action, message)
+    }
+
+    pub fn warn(message: impl Into<String>) -> Self {
+        Self::new(Level::WARN, Action::Warning, message)
+    }
+
+    pub fn failed(message: impl Into<String>) -> Self {
+        Self::new(Level::ERROR, Action::Failed, message)
+    }
+
+    pub fn feed(mut self, feed: &'a str) -> Self {
+        self.feed_opt = Some(feed);
+        self
+    }
+
+    /// The item link, shown as `orig_link=` in the text.
+    pub fn link(mut self, link: &'a str) -> Self {
+        self.link_opt = Some(link);
+        self
+    }
+
+    pub fn guid(mut self, guid_opt: Option<&'a str>) -> Self {
+        self.guid_opt = guid_opt;
+        self
+    }
+
+    /// The error, shown after the message in the text.
+    pub fn error(mut self, err: impl Display) -> Self {
+        self.error_opt = Some(err.to_string());
+        self
+    }
+
+    pub fn emit(self) {
+        let line = match format() {
+            LogFormat::Text => self.text(),
+            LogFormat::Json => serde_json::to_string(&JsonEvent {
+                timestamp: Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+                level: self.level.as_str(),
+                feed: self.feed_opt,
+                link: self.link_opt,
+                guid: self.guid_opt,
+                action: self.action,
+                message: &self.message,
+                error: self.error_opt.as_deref(),
+            })
+            .unwrap_or_default(),
+        };
+        if self.level == Level::INFO {
+            let _ = writeln!(std::io::stdout().lock(), "{line}");
+        } else {
+            let _ = writeln!(std::io::stderr().lock(), "{line}");
+        }
+    }
+
+    fn text(&self) -> String {
+        let mut text = String::new();
+        if self.action == Action::Warning {
+            text.push_str("Warning: ");
+        }
+        if let Some(link) = self.link_opt {
+            text.push_str(&format!("orig_link={link}"));
+            if let Some(guid) = self.guid_opt {
+                text.push_str(&format!(" guid={guid}"));
+            }
+            text.push_str(": ");
+        }
+        text.push_str(&self.message);
+        if let Some(err) = &self.error_opt {
+            text.push_str(&format!(": {err}"));
+        }
+        text
+    }
+}
+
+/// An event as a line of `--log-format json`.
+#[derive(Serialize)]
+struct JsonEvent<'a> {
+    timestamp: String,
+    level: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    feed: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    guid: Option<&'a str>,
+    action: Action,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+/// The logs of this crate by the count of `--debug`: the HTTP requests by
+/// `-d`, the decisions on the items by `-dd`, and the XRPC payloads by `-ddd`.
+fn default_directive(debug: u8) -> String {
+    let level = match debug {
+        0 => "off",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    format!("{TARGET_PREFIX}={level}")
+}
+
+/// Set the subscriber of the logs to stderr, filtered by `RUST_LOG` if set or
+/// by the count of `--debug`, and the format of the logs and the events.
+pub fn init(debug: u8, format: LogFormat) -> Result<(), Box<dyn Error>> {
+    FORMAT
+        .set(format)
+        .map_err(|_| "Failed to set log format: already set")?;
+    let filter = match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(directives) => EnvFilter::try_new(directives),
+        Err(_) => EnvFilter::try_new(default_directive(debug)),
+    }
+    .map_err(|err| format!("Failed to parse log filter: {err}"))?;
+    // The spans are tracked by the registry across the tasks, and logged
+    // with their time when closed to find the slow stages.
+    let layer = fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_span_events(FmtSpan::CLOSE);
+    let layer = match format {
+        LogFormat::Text => layer.compact().boxed(),
+        LogFormat::Json => layer
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(layer.with_filter(filter))
+        .try_init()
+        .map_err(|err| format!("Failed to set logger: {err}"))?;
+    Ok(())
+}
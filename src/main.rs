@@ -1,55 +1,309 @@
-use atrium_api::app::bsky;
-use atrium_api::blob::BlobRef;
-use atrium_api::com::atproto;
 use chrono::Utc;
-use clap::{Parser, Subcommand};
-use file_lock::FileLock;
-use std::collections::HashSet;
-use std::collections::VecDeque;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::error::Error;
-use std::fs::OpenOptions;
-use std::io::{BufRead, BufReader, Write};
-use std::marker::Sync;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::Level;
 
-mod xrpc_client;
-use xrpc_client::{XrpcHttpClient, XrpcReqwestClient};
+use mstdn_rss2bsky_post::account::Account;
+use mstdn_rss2bsky_post::bridge::{
+    self, Bridge, BridgeBuilder, DbConfig, FailedStage, PostConfig, RunFailure, RunSummary,
+    TlsConfig,
+};
+use mstdn_rss2bsky_post::config_file;
+use mstdn_rss2bsky_post::content_warning::{CwMode, SelfLabel};
+use mstdn_rss2bsky_post::healthcheck;
+use mstdn_rss2bsky_post::logging::{self, Action, Event, LogFormat};
+use mstdn_rss2bsky_post::media_hosts::MediaHostPolicy;
+use mstdn_rss2bsky_post::media_policy::{AnimationPolicy, LargeImagePolicy};
+use mstdn_rss2bsky_post::mentions::MentionMapping;
+use mstdn_rss2bsky_post::rewrite::RewriteRule;
+use mstdn_rss2bsky_post::richtext::{self, ContentFormat};
+use mstdn_rss2bsky_post::s3_state::S3Config;
+use mstdn_rss2bsky_post::sqlite_store;
+use mstdn_rss2bsky_post::state_store::{
+    self, DbBackend, DbNamespace, EditPolicy, ExportFormat, FileStateStore, FsyncPolicy,
+};
 
-mod richtext;
-use richtext::RichTextSegment;
+use mstdn_rss2bsky_post::stats;
+use mstdn_rss2bsky_post::template::{LinkPlacement, PostTemplate};
+use mstdn_rss2bsky_post::tracking_params;
+use mstdn_rss2bsky_post::truncate::TruncateStrategy;
+use mstdn_rss2bsky_post::update_check;
 
-mod rss_ext;
+/// The exit status of a run which defers items over the daily budget, as
+/// `EX_TEMPFAIL` of sysexits.
+const DEFERRED_EXIT_CODE: i32 = 75;
+
+/// The exit status of a run which posts nothing, with --detailed-exit-codes.
+const NOTHING_POSTED_EXIT_CODE: i32 = 3;
+
+/// The exit status of a run which fails at the stage. The other failures exit
+/// with 1, and the usage errors with 2.
+fn failed_exit_code(stage: FailedStage) -> i32 {
+    match stage {
+        FailedStage::Partial => 4,
+        FailedStage::Auth => 5,
+        FailedStage::Feed => 6,
+        FailedStage::Lock => 7,
+    }
+}
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Turn debugging information on
+    /// Turn debugging information on: -d for the HTTP requests, -dd for the
+    /// decisions on the items, and -ddd for the XRPC payloads. RUST_LOG
+    /// overrides it by the directives of tracing-subscriber's EnvFilter
     #[arg(short, long, action = clap::ArgAction::Count)]
     debug: u8,
 
-    #[arg(long, default_value_t = String::from("https://bsky.social"), env = "XRPC_HOST")]
-    xrpc_host: String,
+    /// Format of the logs and the events of the runs
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, env = "LOG_FORMAT")]
+    log_format: LogFormat,
 
-    #[arg(long)]
-    filelock_path: String,
+    /// XRPC host of the account, defaults to the PDS found by the identifier
+    #[arg(long, env = "XRPC_HOST")]
+    xrpc_host: Option<String>,
+
+    /// Deprecated and ignored, as the lock file beside the DB is locked
+    #[arg(long, hide = true)]
+    filelock_path: Option<String>,
 
+    /// Path of the DB, defaults to a path in $XDG_STATE_HOME/mstdn-rss2bsky-post
+    /// by the feed URL. Runs, migrate-db and the db subcommands changing the DB
+    /// lock the ".lock" file beside it rather than the DB, which is replaced by
+    /// a rename when rewritten
     #[arg(long)]
-    db_path: String,
+    db_path: Option<String>,
 
     #[arg(long, default_value_t = 50)]
     min_save_posts: usize,
 
+    /// Keep the posted links for these days instead of the last --min-save-posts links
+    #[arg(long)]
+    retention_days: Option<u32>,
+
+    /// When to sync the DB to the disk
+    #[arg(long, value_enum, default_value_t = FsyncPolicy::PerPost)]
+    fsync_policy: FsyncPolicy,
+
+    /// Keep the posted links of each feed or account apart when they share the DB.
+    /// The db subcommands see the links prefixed by their namespace.
+    #[arg(long, value_enum)]
+    db_namespace: Option<DbNamespace>,
+
+    /// Remove the remote lock of the S3 backend after this many seconds, after
+    /// which healthcheck takes the lock as stale
+    #[arg(long, default_value_t = 3600)]
+    stale_lock_secs: u64,
+
+    /// Where to store the posted links
+    #[arg(long, value_enum, default_value_t = DbBackend::File)]
+    db_backend: DbBackend,
+
+    /// URL of the Redis server for the Redis backend, such as "redis://localhost:6379/0",
+    /// or "rediss://" for TLS
+    #[arg(long, env = "REDIS_URL")]
+    redis_url: Option<String>,
+
+    /// Endpoint of the S3-compatible storage for the S3 backend
+    #[arg(long, default_value_t = String::from("https://s3.amazonaws.com"), env = "S3_ENDPOINT")]
+    s3_endpoint: String,
+
+    /// Bucket of the DB files for the S3 backend
+    #[arg(long, env = "S3_BUCKET")]
+    s3_bucket: Option<String>,
+
+    /// Prefix of the object keys of the DB files, such as "mstdn-rss2bsky-post/"
+    #[arg(long, default_value_t = String::new())]
+    s3_prefix: String,
+
+    #[arg(long, default_value_t = String::from("us-east-1"), env = "AWS_REGION")]
+    s3_region: String,
+
+    #[arg(long, env = "AWS_ACCESS_KEY_ID")]
+    s3_access_key_id: Option<String>,
+
+    #[arg(long, env = "AWS_SECRET_ACCESS_KEY")]
+    s3_secret_access_key: Option<String>,
+
+    /// Read the S3 secret access key from the stdout of the shell command,
+    /// such as "pass show s3/secret"
+    #[arg(long, env = "AWS_SECRET_ACCESS_KEY_CMD")]
+    s3_secret_access_key_cmd: Option<String>,
+
+    /// Path of the posting stats, defaults to the DB path with the ".stats" suffix
+    #[arg(long)]
+    stats_path: Option<String>,
+
+    /// Path of the journal of the run actions, defaults to the DB path with the ".journal" suffix
+    #[arg(long)]
+    journal_path: Option<String>,
+
+    /// Path of the saved sessions of the accounts, defaults to the DB path with
+    /// the ".session" suffix, or to the session file in the state directory
+    /// without --db-path. Runs of several feeds can share it to reuse the
+    /// session of their account, as they lock it by the ".lock" file beside it.
+    #[arg(long, env = "SESSION_PATH")]
+    session_path: Option<String>,
+
+    /// Wait for the rate limit of Bluesky up to this many seconds, over which the request fails
+    #[arg(long, default_value_t = 300)]
+    max_rate_limit_wait_secs: u64,
+
+    /// PEM file of a CA certificate to trust for the PDS, in addition to the system ones (repeatable)
+    #[arg(long, env = "TLS_CA_CERT", value_delimiter = ',')]
+    tls_ca_cert: Vec<String>,
+
+    /// PEM file of the client certificate for the PDS requiring mutual TLS
+    #[arg(long, env = "TLS_CLIENT_CERT")]
+    tls_client_cert: Option<String>,
+
+    /// PEM file of the key of --tls-client-cert, if the certificate file does not have it
+    #[arg(long, env = "TLS_CLIENT_KEY", requires = "tls_client_cert")]
+    tls_client_key: Option<String>,
+
     #[arg(long, default_value_t = false)]
     dry_run: bool,
 
+    /// Log at startup when a newer release is published
+    #[arg(long, default_value_t = false, env = "UPDATE_CHECK")]
+    update_check: bool,
+
+    /// Disable the update check even if it is enabled by the environment
+    #[arg(long, default_value_t = false)]
+    no_update_check: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+// The password of the Bluesky account, which is not needed after `login`. The
+// file is preferred to the command and stdin, and all are preferred to the
+// password itself.
+// The doc comment would be taken as the about of the commands by clap.
+#[derive(Args)]
+struct PasswordArgs {
+    /// Read the password of the account from the file
+    #[arg(long, env = "ATPROTO_PASSWORD_FILE")]
+    atproto_password_file: Option<String>,
+
+    /// Read the password of the account from the stdout of the shell command,
+    /// such as "pass show bsky/app"
+    #[arg(long, env = "ATPROTO_PASSWORD_CMD")]
+    atproto_password_cmd: Option<String>,
+
+    /// Read the password of the account from the first line of stdin
+    #[arg(long, default_value_t = false)]
+    atproto_password_stdin: bool,
+
+    /// Password of the account, which other users may see in the process
+    /// list; prefer --atproto-password-file or --atproto-password-stdin
+    #[arg(long, env = "ATPROTO_PASSWORD", hide_env_values = true)]
+    atproto_password: Option<String>,
+}
+
+impl PasswordArgs {
+    /// The password given by any of the options, if any.
+    fn password(&self) -> Result<Option<String>, Box<dyn Error>> {
+        if let Some(path) = &self.atproto_password_file {
+            let content = std::fs::read_to_string(path)
+                .map_err(|err| format!("Failed to read password file: {err}"))?;
+            return Ok(Some(non_empty_password(first_line(&content))?));
+        }
+        if let Some(command) = &self.atproto_password_cmd {
+            return Ok(Some(non_empty_password(&command_secret(command)?)?));
+        }
+        if self.atproto_password_stdin {
+            let mut line = String::new();
+            std::io::stdin()
+                .read_line(&mut line)
+                .map_err(|err| format!("Failed to read password: {err}"))?;
+            return Ok(Some(non_empty_password(first_line(&line))?));
+        }
+        Ok(self.atproto_password.clone())
+    }
+}
+
+/// A feed and the account to post it by, as `FEED_URL=IDENTIFIER`.
+#[derive(Clone, Debug)]
+struct FeedRoute {
+    feed_url: String,
+    identifier: String,
+}
+
+impl FromStr for FeedRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // The feed URL may have '=' in its query, but the identifier does not.
+        let (feed_url, identifier) = match s.rsplit_once('=') {
+            Some(pair) => pair,
+            None => return Err(format!("Missing '=' in the route: {s}")),
+        };
+        if feed_url.is_empty() || identifier.is_empty() {
+            return Err(format!(
+                "Missing the feed URL or the identifier in the route: {s}"
+            ));
+        }
+        Ok(FeedRoute {
+            feed_url: feed_url.to_string(),
+            identifier: identifier.to_string(),
+        })
+    }
+}
+
+fn first_line(content: &str) -> &str {
+    content.lines().next().unwrap_or("")
+}
+
+/// The first line of the stdout of the shell command, such as the CLI of a
+/// password manager. Its stdin and stderr are left to the terminal, so that it
+/// can ask for a passphrase.
+fn command_secret(command: &str) -> Result<String, Box<dyn Error>> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let output = shell
+        .arg(command)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| format!("Failed to run {command:?}: {err}"))?;
+    if !output.status.success() {
+        Err(format!("Failed to run {command:?}: {}", output.status))?;
+    }
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|_| format!("Failed to read the output of {command:?}: not UTF-8"))?;
+    Ok(first_line(&stdout).to_string())
+}
+
+fn non_empty_password(password: &str) -> Result<String, Box<dyn Error>> {
+    if password.is_empty() {
+        Err("Failed to read password: no password is given")?;
+    }
+    Ok(password.to_string())
+}
+
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     Run {
-        #[arg(long)]
-        feed_url: String,
+        #[arg(long, required_unless_present = "route")]
+        feed_url: Option<String>,
 
         #[arg(long, default_value_t = String::from("[マストドン投稿から]:"))]
         original_link_prefix: String,
@@ -57,450 +311,1282 @@ enum Commands {
         #[arg(long, default_value_t = 300)]
         post_text_limit: usize,
 
+        #[arg(long, value_enum, default_value_t = TruncateStrategy::Hard)]
+        truncate_strategy: TruncateStrategy,
+
+        /// Keep the first link when truncation would drop it
+        #[arg(long, default_value_t = false)]
+        preserve_first_link: bool,
+
+        /// Split long posts into a thread instead of truncating them
+        #[arg(long, default_value_t = false)]
+        thread_long_posts: bool,
+
+        /// Post the item title with a link card of the item, for blog feeds
+        #[arg(long, default_value_t = false)]
+        blog_mode: bool,
+
+        /// Maximum number of images attached to a post, up to 4
+        #[arg(long, default_value_t = 4)]
+        max_images: usize,
+
+        /// Maximum width and height of images, downscaled when larger or over the blob size limit
+        #[arg(long, default_value_t = 2000)]
+        image_max_dimension: u32,
+
+        /// Keep the EXIF metadata of JPEG images, such as GPS positions
+        #[arg(long, default_value_t = false)]
+        keep_exif: bool,
+
+        /// Number of images of a post downloaded and uploaded at once
+        #[arg(long, default_value_t = 4)]
+        upload_concurrency: usize,
+
+        /// Size of images in bytes, as told by the feed, over which --large-image-policy applies
+        #[arg(long, default_value_t = 10_000_000)]
+        large_image_threshold: usize,
+
+        /// How to post images over --large-image-threshold
+        #[arg(long, value_enum, default_value_t = LargeImagePolicy::Resize)]
+        large_image_policy: LargeImagePolicy,
+
+        /// How to post videos, such as animated GIFs converted by Mastodon
+        #[arg(long, value_enum, default_value_t = AnimationPolicy::Video)]
+        animation_policy: AnimationPolicy,
+
+        /// Maximum size of videos in bytes, over which the link card is attached instead
+        #[arg(long, default_value_t = 100_000_000)]
+        video_max_bytes: usize,
+
+        /// Maximum length of videos in seconds, over which the link card is attached instead
+        #[arg(long, default_value_t = 180)]
+        video_max_duration: u32,
+
+        /// Timeout of each attempt to download media and link card pages, in seconds
+        #[arg(long, default_value_t = 30)]
+        media_timeout: u64,
+
+        /// Number of retries to download media and link card pages
+        #[arg(long, default_value_t = 2)]
+        media_retries: u32,
+
+        /// Number of retries of Bluesky requests failed by a server error or the connection, waiting 1, 2, 4, ... seconds
+        #[arg(long, default_value_t = 3)]
+        xrpc_retries: u32,
+
+        /// Maximum size of media and link card pages to download, over which the download is aborted
+        #[arg(long, default_value_t = 100_000_000)]
+        media_max_bytes: usize,
+
+        /// Download media and link card pages only from these hosts and their subdomains
+        #[arg(long, value_delimiter = ',')]
+        media_allow_hosts: Vec<String>,
+
+        /// Refuse to download media and link card pages from these hosts and their subdomains
+        #[arg(long, value_delimiter = ',')]
+        media_deny_hosts: Vec<String>,
+
+        /// Allow downloading media and link card pages from loopback and private network addresses
+        #[arg(long, default_value_t = false)]
+        media_allow_private: bool,
+
+        /// Maximum number of redirects followed to download media and link card pages
+        #[arg(long, default_value_t = 5)]
+        media_max_redirects: usize,
+
+        /// Post items without the media when they fail to download or upload, instead of failing the item
+        #[arg(long, default_value_t = false)]
+        post_without_failed_media: bool,
+
+        /// How to post items with a content warning
+        #[arg(long, value_enum, default_value_t = CwMode::Prepend)]
+        cw_mode: CwMode,
+
+        /// Self-label of posts with a content warning in the label CW mode
+        #[arg(long, value_enum, default_value_t = SelfLabel::GraphicMedia)]
+        cw_label: SelfLabel,
+
+        /// Use the item title as the content warning when the description has none
+        #[arg(long, default_value_t = false)]
+        cw_from_title: bool,
+
+        /// Attach media rated as sensitive with this self-label, instead of dropping them
+        #[arg(long, value_enum)]
+        sensitive_media_label: Option<SelfLabel>,
+
+        /// Attach media rated as adult with this self-label, such as porn, instead of --sensitive-media-label
+        #[arg(long, value_enum)]
+        adult_media_label: Option<SelfLabel>,
+
+        /// Post items even if they are marked as local-only
+        #[arg(long, default_value_t = false)]
+        post_local_only: bool,
+
+        /// Remove tracking query parameters from links
+        #[arg(long, default_value_t = false)]
+        strip_tracking_params: bool,
+
+        /// Query parameters removed by --strip-tracking-params, where a trailing '*' matches as a prefix
+        #[arg(long, value_delimiter = ',', default_value = tracking_params::DEFAULT_TRACKING_PARAMS)]
+        tracking_params: Vec<String>,
+
+        /// Remove hashtags from posts
+        #[arg(long, default_value_t = false)]
+        strip_hashtags: bool,
+
+        /// Hashtags kept by --strip-hashtags
+        #[arg(long, value_delimiter = ',')]
+        keep_hashtags: Vec<String>,
+
+        /// Rewrite the content by a regex, as 'PATTERN=>REPLACEMENT' (repeatable, applied in order)
+        #[arg(long)]
+        rewrite: Vec<RewriteRule>,
+
+        /// Mention a Bluesky account for a Fediverse account, as 'user@domain=did:plc:...' (repeatable)
+        #[arg(long)]
+        mention_map: Vec<MentionMapping>,
+
+        /// Format of the item descriptions
+        #[arg(long, value_enum, default_value_t = ContentFormat::Html)]
+        content_format: ContentFormat,
+
+        /// Wrap inline code in backticks
+        #[arg(long, default_value_t = false)]
+        code_backticks: bool,
+
+        /// Collapse runs of spaces and tabs into a space
+        #[arg(long, default_value_t = false)]
+        collapse_whitespace: bool,
+
+        /// Remove spaces and tabs at the start and the end of lines
+        #[arg(long, default_value_t = false)]
+        trim_lines: bool,
+
+        /// Maximum number of consecutive blank lines
+        #[arg(long)]
+        max_blank_lines: Option<usize>,
+
+        /// Where to put the original link
+        #[arg(long, value_enum, default_value_t = LinkPlacement::Append)]
+        link_placement: LinkPlacement,
+
+        /// Attach the card of the first link in the content when the post has no other embed
+        #[arg(long, default_value_t = false)]
+        link_preview: bool,
+
+        /// Attach the first N inline images of the HTML content when the item has no images
+        #[arg(long)]
+        inline_images: Option<usize>,
+
+        /// What to do with the posted items edited on Mastodon
+        #[arg(long, value_enum, default_value_t = EditPolicy::Update)]
+        edit_policy: EditPolicy,
+
+        /// Skip items whose text is the same as an item posted in these days
+        #[arg(long)]
+        content_dedup_days: Option<u32>,
+
+        /// The most items posted when the DB is empty
+        #[arg(long, default_value_t = 5)]
+        initial_max: usize,
+
+        /// Post all the items in the feed even when the DB is empty
+        #[arg(long, default_value_t = false)]
+        allow_initial_backfill: bool,
+
+        /// Look up new items in these recent posts of the account, to not post
+        /// them again after the DB is lost
+        #[arg(long)]
+        remote_dedup_posts: Option<usize>,
+
+        /// Stop posting before the account creates more records than this in
+        /// the last 24 hours, and exit with the status 75 leaving the rest of
+        /// the items to the next run. The records are counted in the DB, so
+        /// the feeds of an account need to share the DB
+        #[arg(long)]
+        daily_record_budget: Option<usize>,
+
+        /// Exit with 3 when nothing is posted, instead of 0. The failures exit
+        /// with 4 when some items or feeds fail after the others are posted, 5
+        /// for the authentication, 6 for the feed, and 7 when another run holds
+        /// the lock of the DB
+        #[arg(long, default_value_t = false)]
+        detailed_exit_codes: bool,
+
+        /// Language of posts, such as "ja" (repeatable)
+        #[arg(long)]
+        post_lang: Vec<String>,
+
+        /// Do not detect the language of posts when --post-lang is not given
+        #[arg(long, default_value_t = false)]
+        no_lang_detect: bool,
+
+        /// Minimum confidence of the language detection, from 0 to 1
+        #[arg(long, default_value_t = 0.5)]
+        lang_detect_threshold: f64,
+
+        /// Language of posts when the detection is not confident (repeatable)
+        #[arg(long)]
+        fallback_lang: Vec<String>,
+
+        /// Layout of posts in Handlebars, with the fields {{content}}, {{link_prefix}}, {{link}}, {{title}}, {{pub_date}} and {{author}}, instead of the layout of --link-placement. Empty fields are false in conditionals such as {{#if title}}...{{/if}}
+        #[arg(long)]
+        post_template: Option<PostTemplate>,
+
+        #[arg(long, env = "ATPROTO_IDENTIFIER", required_unless_present = "route")]
+        atproto_identifier: Option<String>,
+
+        #[command(flatten)]
+        atproto_password: PasswordArgs,
+
+        /// Post the feed by the account, as FEED_URL=IDENTIFIER, in place of --feed-url and --atproto-identifier (repeatable). A feed may be posted by several accounts. The accounts are authenticated by the sessions saved by `login`, or by the password if there is one account.
+        #[arg(long, conflicts_with = "feed_url")]
+        route: Vec<FeedRoute>,
+    },
+    /// Render an HTML file as a post text and facets, to check the conversion
+    RenderHtml {
+        /// Path of the HTML file
+        path: String,
+
+        /// Format of the file
+        #[arg(long, value_enum, default_value_t = ContentFormat::Html)]
+        content_format: ContentFormat,
+
+        /// Wrap inline code in backticks
+        #[arg(long, default_value_t = false)]
+        code_backticks: bool,
+
+        /// Collapse runs of spaces and tabs into a space
+        #[arg(long, default_value_t = false)]
+        collapse_whitespace: bool,
+
+        /// Remove spaces and tabs at the start and the end of lines
+        #[arg(long, default_value_t = false)]
+        trim_lines: bool,
+
+        /// Maximum number of consecutive blank lines
+        #[arg(long)]
+        max_blank_lines: Option<usize>,
+    },
+    /// Delete the Bluesky posts of the items deleted from Mastodon
+    SyncDeletes {
+        #[arg(long)]
+        feed_url: String,
+
+        #[arg(long, env = "ATPROTO_IDENTIFIER")]
+        atproto_identifier: String,
+
+        #[command(flatten)]
+        atproto_password: PasswordArgs,
+    },
+    /// Record the items in the feed as posted without posting them
+    MarkPosted {
+        #[arg(long)]
+        feed_url: String,
+
         #[arg(long, env = "ATPROTO_IDENTIFIER")]
         atproto_identifier: String,
 
-        #[arg(long, env = "ATPROTO_PASSWORD")]
-        atproto_password: String,
+        #[command(flatten)]
+        atproto_password: PasswordArgs,
+    },
+    /// Log in to the Bluesky account and save the session, so that the other
+    /// commands need no password
+    Login {
+        #[arg(long, env = "ATPROTO_IDENTIFIER")]
+        atproto_identifier: String,
+
+        #[command(flatten)]
+        atproto_password: PasswordArgs,
+
+        /// Authorize by OAuth in the browser instead of the password, which
+        /// binds the tokens to a key saved in the session
+        #[arg(long, default_value_t = false)]
+        oauth: bool,
+    },
+    /// Revoke the saved session of the Bluesky account and remove it
+    Logout {
+        #[arg(long, env = "ATPROTO_IDENTIFIER")]
+        atproto_identifier: String,
+    },
+    /// Authenticate to the Bluesky account and show its DID, handle and PDS,
+    /// to check the credentials without posting
+    #[command(alias = "check-auth")]
+    Whoami {
+        #[arg(long, env = "ATPROTO_IDENTIFIER")]
+        atproto_identifier: String,
+
+        #[command(flatten)]
+        atproto_password: PasswordArgs,
+    },
+    /// Check that no run holds the lock over --stale-lock-secs, the DB can be
+    /// read, the last run has finished recently, and the session is valid if
+    /// the account is given, exiting with 1 when any of them fails, as a health
+    /// probe of containers and systemd
+    Healthcheck {
+        /// Fail when the last run finished more than this many seconds ago
+        #[arg(long, default_value_t = 3600)]
+        max_run_age_secs: u64,
+
+        /// Check the session of the account, saved by login so that the probes
+        /// do not create a session every time
+        #[arg(long, env = "ATPROTO_IDENTIFIER")]
+        atproto_identifier: Option<String>,
+
+        #[command(flatten)]
+        atproto_password: PasswordArgs,
     },
+    /// Show the state of the DB
+    Status {
+        /// Show the posting stats per account
+        #[arg(long, default_value_t = false)]
+        stats: bool,
+    },
+    /// Inspect and fix the posted links in the DB
+    Db {
+        #[command(subcommand)]
+        command: DbCommands,
+    },
+    /// Import the links and the posted records of the DB files into the SQLite
+    /// backend, so that `--db-backend sqlite` posts nothing again. The blob
+    /// cache and the created records are not imported.
+    MigrateDb,
+}
+
+#[derive(Subcommand)]
+enum DbCommands {
+    /// List the posted links from the oldest
+    List,
+    /// Show the Bluesky post of the link
+    Show { link: String },
+    /// Remove the link, so that the item is posted again
+    Remove { link: String },
+    /// Print the posted links with their Bluesky posts
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+    },
+    /// Add the exported links which are not in the DB
+    Import {
+        /// Path of the exported file, or "-" for the standard input
+        path: String,
+
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+    },
+    /// Drop the links beyond the retention and rewrite the DB without them
+    Compact,
+    /// Check the DB files for duplicate, broken or orphaned entries
+    Check {
+        /// Rewrite the files without the issues
+        #[arg(long)]
+        repair: bool,
+    },
+}
+
+impl DbCommands {
+    fn changes_db(&self) -> bool {
+        match self {
+            DbCommands::Remove { .. } | DbCommands::Import { .. } | DbCommands::Compact => true,
+            DbCommands::Check { repair } => *repair,
+            DbCommands::List | DbCommands::Show { .. } | DbCommands::Export { .. } => false,
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
+    // The options in the config file are taken as if they were given first.
+    let command = config_file::with_config_arg(Cli::command());
+    let args = config_file::with_config_args(&command, std::env::args_os().collect())?;
+    let cli =
+        Cli::from_arg_matches(&command.get_matches_from(args)).unwrap_or_else(|err| err.exit());
+    logging::init(cli.debug, cli.log_format)?;
+
+    let log_format = cli.log_format;
+    let result = run_command(cli).await;
+    // The error is an event as well, so that the JSON logs have it.
+    if let (Err(err), LogFormat::Json) = (&result, log_format) {
+        Event::failed("Failed to run the command").error(err).emit();
+        std::process::exit(1);
+    }
+    result
+}
+
+async fn run_command(mut cli: Cli) -> Result<(), Box<dyn Error>> {
+    // The secret is read once, as the DB config is made for every feed.
+    if let (DbBackend::S3, Some(command)) = (cli.db_backend, &cli.s3_secret_access_key_cmd) {
+        cli.s3_secret_access_key = Some(command_secret(command)?);
+    }
+
+    if cli.filelock_path.is_some() {
+        Event::warn("--filelock-path is ignored, as the lock file beside the DB is locked now")
+            .emit();
+    }
+
+    // The last check is kept beside the DB, so commands without one do not check.
+    if let (true, false, Ok(db_path)) = (cli.update_check, cli.no_update_check, cli.db_path()) {
+        update_check::check_update(
+            &bridge::new_reqwest_client()?,
+            &format!("{db_path}.update-check"),
+        )
+        .await;
+    }
 
     match &cli.command {
         Commands::Run {
             feed_url,
             atproto_identifier,
             atproto_password,
+            route,
             original_link_prefix,
             post_text_limit,
+            truncate_strategy,
+            preserve_first_link,
+            thread_long_posts,
+            blog_mode,
+            max_images,
+            image_max_dimension,
+            keep_exif,
+            upload_concurrency,
+            large_image_threshold,
+            large_image_policy,
+            animation_policy,
+            video_max_bytes,
+            video_max_duration,
+            media_timeout,
+            media_retries,
+            xrpc_retries,
+            media_max_bytes,
+            media_allow_hosts,
+            media_deny_hosts,
+            media_allow_private,
+            media_max_redirects,
+            post_without_failed_media,
+            cw_mode,
+            cw_label,
+            cw_from_title,
+            sensitive_media_label,
+            adult_media_label,
+            post_local_only,
+            strip_tracking_params,
+            tracking_params,
+            strip_hashtags,
+            keep_hashtags,
+            rewrite,
+            mention_map,
+            content_format,
+            code_backticks,
+            collapse_whitespace,
+            trim_lines,
+            max_blank_lines,
+            link_placement,
+            link_preview,
+            inline_images,
+            edit_policy,
+            content_dedup_days,
+            initial_max,
+            allow_initial_backfill,
+            remote_dedup_posts,
+            daily_record_budget,
+            detailed_exit_codes,
+            post_lang,
+            no_lang_detect,
+            lang_detect_threshold,
+            fallback_lang,
+            post_template,
+        } => {
+            let post_config = PostConfig {
+                original_link_prefix: original_link_prefix.to_string(),
+                post_text_limit: *post_text_limit,
+                truncate_strategy: *truncate_strategy,
+                preserve_first_link: *preserve_first_link,
+                thread_long_posts: *thread_long_posts,
+                blog_mode: *blog_mode,
+                max_images: *max_images,
+                image_max_dimension: *image_max_dimension,
+                strip_exif: !*keep_exif,
+                upload_concurrency: *upload_concurrency,
+                large_image_threshold: *large_image_threshold,
+                large_image_policy: *large_image_policy,
+                animation_policy: *animation_policy,
+                video_max_bytes: *video_max_bytes,
+                video_max_duration: *video_max_duration,
+                media_timeout_secs: *media_timeout,
+                media_retries: *media_retries,
+                xrpc_retries: *xrpc_retries,
+                media_max_bytes: *media_max_bytes,
+                media_hosts: MediaHostPolicy {
+                    allow_hosts: media_allow_hosts.clone(),
+                    deny_hosts: media_deny_hosts.clone(),
+                    allow_private: *media_allow_private,
+                },
+                media_max_redirects: *media_max_redirects,
+                post_without_failed_media: *post_without_failed_media,
+                cw_mode: *cw_mode,
+                cw_label: *cw_label,
+                cw_from_title: *cw_from_title,
+                sensitive_media_label_opt: *sensitive_media_label,
+                adult_media_label_opt: *adult_media_label,
+                post_local_only: *post_local_only,
+                tracking_params_opt: if *strip_tracking_params {
+                    Some(tracking_params.clone())
+                } else {
+                    None
+                },
+                keep_hashtags_opt: if *strip_hashtags {
+                    Some(keep_hashtags.clone())
+                } else {
+                    None
+                },
+                rewrite_rules: rewrite.clone(),
+                mention_mappings: mention_map.clone(),
+                content_format: *content_format,
+                html_options: richtext::HtmlOptions {
+                    code_backticks: *code_backticks,
+                    collapse_whitespace: *collapse_whitespace,
+                    trim_lines: *trim_lines,
+                    max_blank_lines_opt: *max_blank_lines,
+                },
+                link_placement: *link_placement,
+                link_preview: *link_preview,
+                inline_images_opt: *inline_images,
+                edit_policy: *edit_policy,
+                content_dedup_days_opt: *content_dedup_days,
+                initial_max: *initial_max,
+                allow_initial_backfill: *allow_initial_backfill,
+                remote_dedup_posts_opt: *remote_dedup_posts,
+                daily_record_budget_opt: *daily_record_budget,
+                post_langs: post_lang.clone(),
+                lang_detect_threshold_opt: if *no_lang_detect {
+                    None
+                } else {
+                    Some(*lang_detect_threshold)
+                },
+                fallback_langs: fallback_lang.clone(),
+                post_template: post_template
+                    .clone()
+                    .unwrap_or_else(|| link_placement.default_template()),
+            };
+            let routes = match (feed_url, atproto_identifier) {
+                (Some(feed_url), Some(atproto_identifier)) if route.is_empty() => vec![FeedRoute {
+                    feed_url: feed_url.to_string(),
+                    identifier: atproto_identifier.to_string(),
+                }],
+                _ => route.clone(),
+            };
+            match cli.run_routes(&routes, atproto_password, post_config).await {
+                Ok(summary) if summary.posted == 0 && *detailed_exit_codes => {
+                    std::process::exit(NOTHING_POSTED_EXIT_CODE);
+                }
+                Ok(_) => {}
+                Err(err) if err.is::<bridge::Deferred>() => {
+                    Event::new(Level::WARN, Action::Deferred, err.to_string()).emit();
+                    std::process::exit(DEFERRED_EXIT_CODE);
+                }
+                Err(err) => match err.downcast_ref::<RunFailure>() {
+                    Some(failure) => {
+                        Event::failed("Failed to run").error(failure).emit();
+                        std::process::exit(failed_exit_code(failure.stage));
+                    }
+                    None => Err(err)?,
+                },
+            }
+        }
+        Commands::RenderHtml {
+            path,
+            content_format,
+            code_backticks,
+            collapse_whitespace,
+            trim_lines,
+            max_blank_lines,
+        } => command_render_html(
+            path,
+            *content_format,
+            &richtext::HtmlOptions {
+                code_backticks: *code_backticks,
+                collapse_whitespace: *collapse_whitespace,
+                trim_lines: *trim_lines,
+                max_blank_lines_opt: *max_blank_lines,
+            },
+        )?,
+        Commands::SyncDeletes {
+            feed_url,
+            atproto_identifier,
+            atproto_password,
+        } => {
+            let account = cli.account(atproto_identifier, atproto_password.password()?)?;
+            cli.bridge_builder(feed_url, Arc::new(account))?
+                .build()?
+                .sync_deletes()
+                .await?
+        }
+        Commands::MarkPosted {
+            feed_url,
+            atproto_identifier,
+            atproto_password,
+        } => {
+            let account = cli.account(atproto_identifier, atproto_password.password()?)?;
+            cli.bridge_builder(feed_url, Arc::new(account))?
+                .build()?
+                .mark_posted()
+                .await?
+        }
+        Commands::Login {
+            atproto_identifier,
+            oauth: true,
             ..
-        } => command_run(
-            cli.dry_run,
-            feed_url.to_string(),
-            cli.xrpc_host.to_string(),
-            atproto_identifier.to_string(),
-            atproto_password.to_string(),
-            original_link_prefix.to_string(),
-            cli.filelock_path.to_string(),
-            cli.db_path.to_string(),
-            cli.min_save_posts,
-            *post_text_limit,
-        ),
-    }
-    .await?;
+        } => {
+            cli.account(atproto_identifier, None)?
+                .login_oauth(bridge::new_xrpc_reqwest_client(&cli.tls_config())?)
+                .await?
+        }
+        Commands::Login {
+            atproto_identifier,
+            atproto_password,
+            oauth: false,
+        } => {
+            let password = match atproto_password.password()? {
+                Some(password) => password,
+                None => prompt_password(&format!("Password of {atproto_identifier}: "))?,
+            };
+            cli.account(atproto_identifier, Some(password))?
+                .login(bridge::new_xrpc_reqwest_client(&cli.tls_config())?)
+                .await?
+        }
+        Commands::Logout { atproto_identifier } => {
+            cli.account(atproto_identifier, None)?
+                .logout(bridge::new_xrpc_reqwest_client(&cli.tls_config())?)
+                .await?
+        }
+        Commands::Whoami {
+            atproto_identifier,
+            atproto_password,
+        } => {
+            cli.account(atproto_identifier, atproto_password.password()?)?
+                .whoami(bridge::new_xrpc_reqwest_client(&cli.tls_config())?)
+                .await?
+        }
+        Commands::Healthcheck {
+            max_run_age_secs,
+            atproto_identifier,
+            atproto_password,
+        } => {
+            let healthy = cli
+                .command_healthcheck(
+                    *max_run_age_secs,
+                    atproto_identifier.as_deref(),
+                    atproto_password,
+                )
+                .await?;
+            if !healthy {
+                std::process::exit(1);
+            }
+        }
+        Commands::Status { stats } => {
+            let db_config = cli.db_config()?;
+            // The status only reads the DB, which needs no lock.
+            bridge::pull_remote_db(&db_config, false).await?;
+            command_status(&db_config, *stats)?
+        }
+        Commands::Db { command } => {
+            let db_config = cli.db_config()?;
+            // The S3 backend is locked and pushed only when the DB changes.
+            let changes_db = !cli.dry_run && command.changes_db();
+            let remote_db_opt = bridge::pull_remote_db(&db_config, changes_db).await?;
+            let result = command_db(cli.dry_run, &db_config, command);
+            if changes_db {
+                bridge::push_remote_db(remote_db_opt).await?;
+            }
+            result?
+        }
+        Commands::MigrateDb => command_migrate_db(cli.dry_run, &cli.db_config()?)?,
+    }
 
     Ok(())
 }
 
-async fn command_run(
-    dry_run: bool,
-    feed_url: String,
-    xrpc_host: String,
-    atproto_identifier: String,
-    atproto_password: String,
-    original_link_prefix: String,
-    filelock_path: String,
-    db_path: String,
-    min_save_posts: usize,
-    post_text_limit: usize,
-) -> Result<(), Box<dyn Error>> {
-    use atproto::server::create_session;
-    use create_session::CreateSession;
-
-    let reqwest_client = reqwest::Client::new();
-
-    let items = fetch_items(dry_run, &reqwest_client, feed_url).await?;
-
-    let mut client = XrpcReqwestClient::new(xrpc_host, reqwest_client, dry_run);
-    if dry_run {
-        println!("Dry run: authenticate by {atproto_identifier}");
-    } else {
-        let session = client
-            .create_session(create_session::Input {
-                identifier: atproto_identifier,
-                password: atproto_password,
-            })
-            .await?;
-        client.set_session(session.access_jwt, session.did);
-    }
-
-    post_items(
-        dry_run,
-        &client,
-        &items,
-        &original_link_prefix,
-        &filelock_path,
-        &db_path,
-        min_save_posts,
-        post_text_limit,
-    )
-    .await?;
-
-    Ok(())
-}
+impl Cli {
+    fn db_config(&self) -> Result<DbConfig, Box<dyn Error>> {
+        self.feed_db_config(self.feed_url())
+    }
 
-async fn fetch_items(
-    dry_run: bool,
-    client: &reqwest::Client,
-    feed_url: String,
-) -> Result<Vec<rss::Item>, Box<dyn Error>> {
-    if dry_run {
-        Ok(vec![])
-    } else {
-        let channel = fetch_channel(&client, feed_url).await?;
-        Ok(channel.items)
+    fn feed_db_config(&self, feed_url_opt: Option<&str>) -> Result<DbConfig, Box<dyn Error>> {
+        let db_path = self.feed_db_path(feed_url_opt)?;
+        Ok(DbConfig {
+            stats_path: match &self.stats_path {
+                Some(stats_path) => stats_path.to_string(),
+                None => format!("{db_path}.stats"),
+            },
+            journal_path: match &self.journal_path {
+                Some(journal_path) => journal_path.to_string(),
+                None => format!("{db_path}.journal"),
+            },
+            rate_limit_path: format!("{db_path}.rate-limit"),
+            db_path,
+            min_save_posts: self.min_save_posts,
+            retention_days_opt: self.retention_days,
+            fsync_policy: self.fsync_policy,
+            namespace_opt: self.db_namespace,
+            stale_lock_secs: self.stale_lock_secs,
+            backend: self.db_backend,
+            redis_url_opt: self.redis_url.clone(),
+            s3_opt: match (
+                &self.s3_bucket,
+                &self.s3_access_key_id,
+                &self.s3_secret_access_key,
+            ) {
+                (Some(bucket), Some(access_key_id), Some(secret_access_key)) => Some(S3Config {
+                    endpoint: self.s3_endpoint.to_string(),
+                    bucket: bucket.to_string(),
+                    prefix: self.s3_prefix.to_string(),
+                    region: self.s3_region.to_string(),
+                    access_key_id: access_key_id.to_string(),
+                    secret_access_key: secret_access_key.to_string(),
+                }),
+                _ => None,
+            },
+        })
     }
-}
 
-async fn post_items<Client>(
-    dry_run: bool,
-    client: &Client,
-    items: &[rss::Item],
-    original_link_prefix: &str,
-    filelock_path: &str,
-    db_path: &str,
-    min_save_posts: usize,
-    post_text_limit: usize,
-) -> Result<(), Box<dyn Error>>
-where
-    Client: XrpcHttpClient
-        + atproto::repo::create_record::CreateRecord
-        + atproto::repo::upload_blob::UploadBlob
-        + Sync,
-{
-    if dry_run {
-        println!("Dry run: create DB file if not exists.");
-    } else {
-        let mut append_db_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(db_path)
-            .map_err(|err| format!("Failed to open DB: {err}"))?;
-        append_db_file.write(&vec![])?;
+    /// The builder of the bridge of the feed and the account, configured by
+    /// the global options.
+    fn bridge_builder(
+        &self,
+        feed_url: &str,
+        account: Arc<Account>,
+    ) -> Result<BridgeBuilder, Box<dyn Error>> {
+        Ok(Bridge::builder()
+            .dry_run(self.dry_run)
+            .feed_url(feed_url)
+            .account(account)
+            .max_rate_limit_wait(Duration::from_secs(self.max_rate_limit_wait_secs))
+            .tls_config(self.tls_config())
+            .state_store(self.feed_db_config(Some(feed_url))?))
     }
 
-    if dry_run {
-        println!("Dry run: lock and post items.");
-    } else {
-        let mut filelock = FileLock::lock(
-            filelock_path,
-            false,
-            file_lock::FileOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true),
-        )
-        .map_err(|err| format!("Failed to get lock: {err}"))?;
-        writeln!(filelock.file, "{}", Utc::now().to_rfc3339())
-            .map_err(|err| format!("Failed to write lock: {err}"))?;
-
-        let mut links_for_save: Vec<String> = vec![];
-        let done_links = {
-            let mut done_links: HashSet<String> = HashSet::new();
-            let mut done_links_for_save: VecDeque<String> = VecDeque::new();
-            let db_file = OpenOptions::new()
-                .read(true)
-                .open(db_path)
-                .map_err(|err| format!("Failed to open DB: {err}"))?;
-            for done_link in BufReader::new(db_file).lines() {
-                let done_link = done_link?;
-                done_links.insert(done_link.to_string());
-                done_links_for_save.push_back(done_link);
-                if done_links_for_save.len() > min_save_posts {
-                    done_links_for_save.pop_front();
+    /// Print the result of each check of the health, and whether all of them
+    /// pass.
+    async fn command_healthcheck(
+        &self,
+        max_run_age_secs: u64,
+        atproto_identifier_opt: Option<&str>,
+        atproto_password: &PasswordArgs,
+    ) -> Result<bool, Box<dyn Error>> {
+        let db_config = self.db_config()?;
+        let runs_result = healthcheck::read_runs(&db_config.journal_path);
+        let mut checks = vec![];
+        checks.push((
+            "lock",
+            match &runs_result {
+                Ok(runs) => healthcheck::check_lock(&db_config, runs).await,
+                Err(err) => Err(err.to_string().into()),
+            },
+        ));
+        checks.push(("db", healthcheck::check_db(&db_config)));
+        checks.push((
+            "last run",
+            match &runs_result {
+                Ok(runs) => healthcheck::check_last_run(
+                    runs,
+                    chrono::Duration::seconds(i64::try_from(max_run_age_secs)?),
+                ),
+                Err(err) => Err(err.to_string().into()),
+            },
+        ));
+        if let Some(atproto_identifier) = atproto_identifier_opt {
+            let session_result = async {
+                let did = self
+                    .account(atproto_identifier, atproto_password.password()?)?
+                    .check_session(bridge::new_xrpc_reqwest_client(&self.tls_config())?)
+                    .await?;
+                Ok(format!("Authenticated as {did}"))
+            }
+            .await;
+            checks.push(("session", session_result));
+        }
+
+        let mut healthy = true;
+        for (name, result) in checks {
+            match result {
+                Ok(message) => println!("OK {name}: {message}"),
+                Err(err) => {
+                    println!("FAIL {name}: {err}");
+                    healthy = false;
                 }
             }
-            for done_link_for_save in done_links_for_save.iter_mut() {
-                links_for_save.push(done_link_for_save.to_string());
+        }
+        Ok(healthy)
+    }
+
+    /// Post each feed by its accounts, authenticating each account once. The
+    /// links are namespaced by the account unless --db-namespace is given.
+    async fn run_routes(
+        &self,
+        routes: &[FeedRoute],
+        atproto_password: &PasswordArgs,
+        post_config: PostConfig,
+    ) -> Result<RunSummary, Box<dyn Error>> {
+        let password_opt = atproto_password.password()?;
+        let mut accounts: HashMap<&str, Arc<Account>> = HashMap::new();
+        for route in routes {
+            if !accounts.contains_key(route.identifier.as_str()) {
+                let account = self.account(&route.identifier, password_opt.clone())?;
+                accounts.insert(&route.identifier, Arc::new(account));
             }
-            done_links
+        }
+        if password_opt.is_some() && accounts.len() > 1 {
+            Err("The password is given for several accounts; log in to each account instead.")?;
+        }
+        let namespace_opt = match self.db_namespace {
+            Some(namespace) => Some(namespace),
+            None if routes.len() > 1 => Some(DbNamespace::Account),
+            None => None,
         };
+        if namespace_opt == Some(DbNamespace::Feed) {
+            for (i, route) in routes.iter().enumerate() {
+                if routes[..i]
+                    .iter()
+                    .any(|other| other.feed_url == route.feed_url)
+                {
+                    Err(format!(
+                        "{} is posted by several accounts, which needs --db-namespace account.",
+                        route.feed_url
+                    ))?;
+                }
+            }
+        }
 
-        {
-            let mut append_db_file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .append(true)
-                .open(db_path)
-                .map_err(|err| format!("Failed to open DB: {err}"))?;
-            for item in items.iter().rev() {
-                let item_post = post_item(
-                    client,
-                    &item,
-                    original_link_prefix,
-                    &done_links,
-                    post_text_limit,
-                )
-                .await?;
-                match item_post.bsky_post_opt {
-                    None => {
-                        println!(
-                            "orig_link={}: Already posted to Bluesky.",
-                            item_post.orig_link,
-                        );
-                    }
-                    Some(bsky_post) => {
-                        println!(
-                            "orig_link={}: Posted to Bluesky: cid={}, uri={}",
-                            item_post.orig_link, bsky_post.cid, bsky_post.uri,
-                        );
-                        writeln!(append_db_file, "{}", &item_post.orig_link)
-                            .map_err(|err| format!("Failed to write DB: {err}"))?;
-                        append_db_file
-                            .flush()
-                            .map_err(|err| format!("Failed to flush DB: {err}"))?;
-                        links_for_save.push(item_post.orig_link);
-                    }
+        if let [route] = routes {
+            return self
+                .bridge_builder(&route.feed_url, accounts[route.identifier.as_str()].clone())?
+                .post_config(post_config)
+                .build()?
+                .run_once()
+                .await;
+        }
+        let mut summary = RunSummary::default();
+        // The stages of the failed routes, if known.
+        let mut failed_stages = vec![];
+        let mut deferrals = 0;
+        for route in routes {
+            let result = async {
+                let mut db_config = self.feed_db_config(Some(&route.feed_url))?;
+                db_config.namespace_opt = namespace_opt;
+                self.bridge_builder(&route.feed_url, accounts[route.identifier.as_str()].clone())?
+                    .state_store(db_config)
+                    .post_config(post_config.clone())
+                    .build()?
+                    .run_once()
+                    .await
+            }
+            .await;
+            match result {
+                Ok(route_summary) => summary.posted += route_summary.posted,
+                Err(err) if err.is::<bridge::Deferred>() => {
+                    Event::new(
+                        Level::WARN,
+                        Action::Deferred,
+                        format!("{} by {}: {err}", route.feed_url, route.identifier),
+                    )
+                    .feed(&route.feed_url)
+                    .emit();
+                    deferrals += 1;
+                }
+                Err(err) => {
+                    Event::failed(format!(
+                        "Failed to run {} by {}",
+                        route.feed_url, route.identifier
+                    ))
+                    .feed(&route.feed_url)
+                    .error(&err)
+                    .emit();
+                    failed_stages.push(
+                        err.downcast_ref::<RunFailure>()
+                            .map(|failure| failure.stage),
+                    );
                 }
             }
         }
+        if !failed_stages.is_empty() {
+            let message = format!(
+                "Failed to run {} of {} routes.",
+                failed_stages.len(),
+                routes.len()
+            );
+            // The stage is told only when all the routes fail at it.
+            let stage_opt = if failed_stages.len() < routes.len() {
+                Some(FailedStage::Partial)
+            } else if failed_stages.iter().all(|stage| *stage == failed_stages[0]) {
+                failed_stages[0]
+            } else {
+                None
+            };
+            match stage_opt {
+                Some(stage) => Err(RunFailure::new(stage, message))?,
+                None => Err(message)?,
+            }
+        }
+        // The routes are deferred only when nothing else fails.
+        if deferrals > 0 {
+            Err(bridge::Deferred {
+                message: format!(
+                    "Deferred {deferrals} of {} routes to the next run.",
+                    routes.len()
+                ),
+            })?;
+        }
+        Ok(summary)
+    }
+
+    fn tls_config(&self) -> TlsConfig {
+        TlsConfig {
+            ca_cert_paths: self.tls_ca_cert.clone(),
+            client_cert_path_opt: self.tls_client_cert.clone(),
+            client_key_path_opt: self.tls_client_key.clone(),
+        }
+    }
+
+    /// The account configured by the global options.
+    fn account(
+        &self,
+        atproto_identifier: &str,
+        atproto_password_opt: Option<String>,
+    ) -> Result<Account, Box<dyn Error>> {
+        let mut account =
+            Account::new(self.dry_run, atproto_identifier).with_session_path(self.session_path()?);
+        if let Some(atproto_password) = atproto_password_opt {
+            account = account.with_password(atproto_password);
+        }
+        if let Some(xrpc_host) = &self.xrpc_host {
+            account = account.with_xrpc_host(xrpc_host);
+        }
+        Ok(account)
+    }
+
+    /// The session path given, or the default path beside the DB given. The
+    /// feeds without --db-path share the session file in the state directory.
+    fn session_path(&self) -> Result<String, Box<dyn Error>> {
+        if let Some(session_path) = &self.session_path {
+            return Ok(session_path.to_string());
+        }
+        if let Some(db_path) = &self.db_path {
+            return Ok(format!("{db_path}.session"));
+        }
+        let state_dir = state_home()?.join("mstdn-rss2bsky-post");
+        std::fs::create_dir_all(&state_dir)
+            .map_err(|err| format!("Failed to create {}: {err}", state_dir.display()))?;
+        Ok(state_dir.join("session").to_string_lossy().into_owned())
+    }
 
-        {
-            let mut write_db_file = OpenOptions::new()
-                .write(true)
-                .truncate(true)
-                .open(db_path)
-                .map_err(|err| format!("Failed to open DB: {err}"))?;
-            for link_for_save in links_for_save {
-                writeln!(write_db_file, "{}", link_for_save)
-                    .map_err(|err| format!("Failed to write DB: {err}"))?;
+    /// The feed URL of the command, of which the DB is by default.
+    fn feed_url(&self) -> Option<&str> {
+        match &self.command {
+            Commands::Run { feed_url, .. } => feed_url.as_deref(),
+            Commands::SyncDeletes { feed_url, .. } | Commands::MarkPosted { feed_url, .. } => {
+                Some(feed_url)
             }
+            _ => None,
         }
     }
 
-    Ok(())
+    fn db_path(&self) -> Result<String, Box<dyn Error>> {
+        self.feed_db_path(self.feed_url())
+    }
+
+    /// The DB path given, or the default path by the feed URL.
+    fn feed_db_path(&self, feed_url_opt: Option<&str>) -> Result<String, Box<dyn Error>> {
+        if let Some(db_path) = &self.db_path {
+            return Ok(db_path.to_string());
+        }
+        let feed_url = match feed_url_opt {
+            Some(feed_url) => feed_url,
+            None => Err("--db-path is required for the command without a feed URL.")?,
+        };
+        let state_dir = default_state_dir(feed_url)?;
+        std::fs::create_dir_all(&state_dir)
+            .map_err(|err| format!("Failed to create {}: {err}", state_dir.display()))?;
+        Ok(state_dir.join("db").to_string_lossy().into_owned())
+    }
 }
 
-async fn fetch_channel(
-    client: &reqwest::Client,
-    url: String,
-) -> Result<rss::Channel, Box<dyn Error>> {
-    let request = client.get(url).send().await?;
-    let content_bytes = request.bytes().await?;
-    let channel = rss::Channel::read_from(&content_bytes[..])?;
-    Ok(channel)
+/// The directory of the state of the feed, as
+/// `$XDG_STATE_HOME/mstdn-rss2bsky-post/<feed-hash>` or
+/// `~/.local/state/mstdn-rss2bsky-post/<feed-hash>` without `$XDG_STATE_HOME`.
+fn default_state_dir(feed_url: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let feed_hash: String = Sha256::digest(feed_url.as_bytes())[..8]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    Ok(state_home()?.join("mstdn-rss2bsky-post").join(feed_hash))
 }
 
-#[derive(Debug)]
-struct ItemPost {
-    orig_link: String,
-    bsky_post_opt: Option<BskyPost>,
+fn state_home() -> Result<PathBuf, Box<dyn Error>> {
+    match std::env::var_os("XDG_STATE_HOME") {
+        Some(state_home) if !state_home.is_empty() => Ok(PathBuf::from(state_home)),
+        _ => match std::env::var_os("HOME") {
+            Some(home) => Ok(PathBuf::from(home).join(".local").join("state")),
+            None => {
+                Err("Failed to find the state directory: neither $XDG_STATE_HOME nor $HOME is set")?
+            }
+        },
+    }
 }
 
-async fn post_item<Client>(
-    client: &Client,
-    item: &rss::Item,
-    original_link_prefix: &str,
-    done_links: &HashSet<String>,
-    post_text_limit: usize,
-) -> Result<ItemPost, Box<dyn Error>>
-where
-    Client: XrpcHttpClient
-        + atproto::repo::create_record::CreateRecord
-        + atproto::repo::upload_blob::UploadBlob
-        + Sync,
-{
-    use bsky::richtext::facet;
-
-    let description = match &item.description {
-        Some(content) => content,
-        None => Err(Box::<dyn Error>::from(
-            "Failed to get any descriptions of the given RSS item.",
-        ))?,
-    };
-    let item_link = match &item.link {
-        Some(content) => content,
-        None => Err(Box::<dyn Error>::from(
-            "Failed to get any links of the given RSS item.",
-        ))?,
+/// Read a password from the terminal without echoing it. The input is read as
+/// is when it is not a terminal.
+fn prompt_password(prompt: &str) -> Result<String, Box<dyn Error>> {
+    eprint!("{prompt}");
+    std::io::stderr().flush()?;
+
+    #[cfg(unix)]
+    let saved_termios_opt = unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) == 0 {
+            let mut silent = termios;
+            silent.c_lflag &= !libc::ECHO;
+            silent.c_lflag |= libc::ECHONL;
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent);
+            Some(termios)
+        } else {
+            None
+        }
     };
 
-    if done_links.contains(item_link) {
-        return Ok(ItemPost {
-            orig_link: item_link.to_string(),
-            bsky_post_opt: None,
-        });
-    }
-
-    let mut content = String::from("");
-    let mut limit_count =
-        post_text_limit - original_link_prefix.chars().count() - item_link.chars().count() - 4;
-    let mut need_truncate = false;
-    let mut facets: Vec<facet::Main> = vec![];
-    for seg in richtext::from_html(description.as_str())? {
-        match seg {
-            RichTextSegment::PlainText { text } => {
-                let text_count = text.chars().count();
-
-                if text_count > limit_count {
-                    for c in text.chars().take(limit_count) {
-                        content.push(c);
-                    }
-                    need_truncate = true;
-                    limit_count = 0;
-                } else {
-                    content.push_str(&text);
-                    limit_count -= text_count;
-                }
+    let mut line = String::new();
+    let result = std::io::stdin().read_line(&mut line);
 
-                if need_truncate {
-                    break;
-                }
-            }
-            RichTextSegment::Link { text, link } => {
-                let text_count = text.chars().count();
+    #[cfg(unix)]
+    if let Some(termios) = saved_termios_opt {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+        }
+    }
 
-                let byte_start = content.len() as i32;
+    result.map_err(|err| format!("Failed to read password: {err}"))?;
+    non_empty_password(first_line(&line))
+}
 
-                if text_count > limit_count {
-                    for c in text.chars().take(limit_count) {
-                        content.push(c);
-                    }
-                    need_truncate = true;
-                    limit_count = 0;
-                } else {
-                    content.push_str(&text);
-                    limit_count -= text_count;
-                }
+fn command_render_html(
+    path: &str,
+    content_format: ContentFormat,
+    html_options: &richtext::HtmlOptions,
+) -> Result<(), Box<dyn Error>> {
+    let html =
+        std::fs::read_to_string(path).map_err(|err| format!("Failed to read {path}: {err}"))?;
+    let text = richtext::from_content(&html, content_format, html_options)?;
+    print!("{}", bridge::describe_richtext(&text));
 
-                let byte_end = content.len() as i32;
+    Ok(())
+}
 
-                facets.push(facet::Main {
-                    index: facet::ByteSlice {
-                        byte_start,
-                        byte_end,
-                    },
-                    features: vec![facet::MainFeaturesItem::Link(Box::new(facet::Link {
-                        uri: link,
-                    }))],
-                });
+fn command_db(
+    dry_run: bool,
+    db_config: &DbConfig,
+    command: &DbCommands,
+) -> Result<(), Box<dyn Error>> {
+    if let DbCommands::Check { repair } = command {
+        return command_db_check(dry_run, db_config, *repair);
+    }
 
-                if need_truncate {
-                    break;
+    let db_lock_opt = match command {
+        DbCommands::Remove { .. } | DbCommands::Import { .. } | DbCommands::Compact if !dry_run => {
+            Some(bridge::lock_db(db_config)?)
+        }
+        _ => None,
+    };
+    // The files are recovered only under the lock, as a run may be rewriting them.
+    if db_lock_opt.is_some() && matches!(db_config.backend, DbBackend::File | DbBackend::S3) {
+        FileStateStore::recover(&db_config.db_path)?;
+    }
+    let mut state_store = bridge::open_state_store(db_config, None)?;
+
+    match command {
+        DbCommands::List => {
+            for link in state_store.links() {
+                match state_store.posted_record(&link) {
+                    Some(record) => println!("{link} uri={}", record.uri),
+                    None => println!("{link}"),
                 }
             }
         }
+        DbCommands::Show { link } => {
+            let link = &state_store.link_key(link);
+            if !state_store.contains(link) {
+                Err(format!("Not found in the DB: {link}"))?;
+            }
+            println!("link: {link}");
+            if let Some(record) = state_store.posted_record(link) {
+                println!("uri: {}", record.uri);
+                println!("cid: {}", record.cid);
+                if let Some(posted_at) = record.posted_at_opt {
+                    println!("posted at: {}", posted_at.to_rfc3339());
+                }
+                if let Some(content_hash) = record.content_hash_opt {
+                    println!("content hash: {content_hash}");
+                }
+                if let Some(feed_url) = record.feed_url_opt {
+                    println!("feed: {feed_url}");
+                }
+                if let Some(pub_date) = record.pub_date_opt {
+                    println!("pub date: {}", pub_date.to_rfc3339());
+                }
+                if let Some(truncated) = record.truncated_opt {
+                    println!("truncated: {truncated}");
+                }
+                if let Some(media_count) = record.media_count_opt {
+                    println!("media: {media_count}");
+                }
+            }
+        }
+        DbCommands::Remove { link } => {
+            let link = &state_store.link_key(link);
+            if !state_store.contains(link) {
+                Err(format!("Not found in the DB: {link}"))?;
+            }
+            if dry_run {
+                println!("Dry run: remove {link}");
+            } else {
+                state_store.remove(link)?;
+                state_store.commit()?;
+                println!("Removed {link}");
+            }
+        }
+        DbCommands::Export { format } => {
+            let entries = state_store::export_entries(state_store.as_ref());
+            match format {
+                ExportFormat::Json => println!("{}", serde_json::to_string_pretty(&entries)?),
+                ExportFormat::JsonLines => {
+                    for entry in entries {
+                        println!("{}", serde_json::to_string(&entry)?);
+                    }
+                }
+                ExportFormat::Csv => {
+                    println!("{}", state_store::CSV_HEADER);
+                    for entry in entries {
+                        println!("{}", state_store::csv_row(&entry));
+                    }
+                }
+            }
+        }
+        DbCommands::Import { path, format } => {
+            let content = if path == "-" {
+                std::io::read_to_string(std::io::stdin())
+                    .map_err(|err| format!("Failed to read the standard input: {err}"))?
+            } else {
+                std::fs::read_to_string(path)
+                    .map_err(|err| format!("Failed to read {path}: {err}"))?
+            };
+            let entries = state_store::parse_entries(&content, *format)?;
+            if dry_run {
+                println!("Dry run: import {} entries", entries.len());
+            } else {
+                let count = state_store::import_entries(state_store.as_mut(), &entries)?;
+                state_store.commit()?;
+                println!("Imported {count} of {} entries", entries.len());
+            }
+        }
+        DbCommands::Compact => {
+            let link_count = state_store.links().len();
+            if dry_run {
+                println!("Dry run: compact {link_count} links");
+            } else {
+                state_store.compact()?;
+                let dropped_count = link_count - state_store.links().len();
+                println!("Dropped {dropped_count} of {link_count} links");
+            }
+        }
+        // Checked before opening the store, which fails on broken files.
+        DbCommands::Check { .. } => unreachable!(),
     }
 
-    if need_truncate {
-        content.push_str("...\n");
-    }
-    content.push_str(original_link_prefix);
+    Ok(())
+}
 
-    {
-        let byte_start = content.len() as i32;
-        content.push_str(&item_link);
-        let byte_end = content.len() as i32;
-        facets.push(facet::Main {
-            index: facet::ByteSlice {
-                byte_start,
-                byte_end,
-            },
-            features: vec![facet::MainFeaturesItem::Link(Box::new(facet::Link {
-                uri: item_link.to_string(),
-            }))],
-        });
+fn command_db_check(
+    dry_run: bool,
+    db_config: &DbConfig,
+    repair: bool,
+) -> Result<(), Box<dyn Error>> {
+    if !matches!(db_config.backend, DbBackend::File | DbBackend::S3) {
+        Err("Only the DB files can be checked, not Redis or SQLite.")?;
     }
 
-    let image_url_opt = rss_ext::get_media(item)
-        .and_then(|media| match media.rating {
-            rss_ext::Rating::NonAdult => Some(media),
-            rss_ext::Rating::Other => {
-                eprintln!("Ignore a image might be sensitive: {}", media.url);
-                None
-            }
-        })
-        .map(|media| media.url);
-
-    let result = post_to_bsky(client, content, facets, image_url_opt).await?;
+    if !repair || dry_run {
+        let issues = FileStateStore::check(&db_config.db_path)?;
+        for issue in &issues {
+            println!("{issue}");
+        }
+        if issues.is_empty() {
+            println!("No issues found in the DB.");
+        } else if repair {
+            println!("Dry run: repair {} issues", issues.len());
+        } else {
+            Err(format!(
+                "Found {} issues in the DB, which `db check --repair` fixes",
+                issues.len()
+            ))?;
+        }
+        return Ok(());
+    }
 
-    Ok(ItemPost {
-        orig_link: item_link.to_string(),
-        bsky_post_opt: Some(result),
-    })
+    let _db_lock = bridge::lock_db(db_config)?;
+    let issues = FileStateStore::repair(&db_config.db_path)?;
+    for issue in &issues {
+        println!("{issue}");
+    }
+    println!("Repaired {} issues", issues.len());
+    Ok(())
 }
 
-#[derive(Debug)]
-struct BskyPost {
-    cid: String,
-    uri: String,
-}
+fn command_migrate_db(dry_run: bool, db_config: &DbConfig) -> Result<(), Box<dyn Error>> {
+    if db_config.backend != DbBackend::Sqlite {
+        Err(
+            "The DB files are migrated into the SQLite backend, which needs `--db-backend sqlite`.",
+        )?;
+    }
 
-async fn post_to_bsky<Client>(
-    client: &Client,
-    text: String,
-    facets: Vec<bsky::richtext::facet::Main>,
-    image_url_opt: Option<String>,
-) -> Result<BskyPost, Box<dyn Error>>
-where
-    Client: XrpcHttpClient
-        + atproto::repo::create_record::CreateRecord
-        + atproto::repo::upload_blob::UploadBlob
-        + Sync,
-{
-    use atproto::repo::create_record;
-    use atrium_api::records::Record;
-    use bsky::feed::post;
-
-    let target_did = match client.current_did() {
-        Some(did) => did,
-        None => Err(Box::<dyn Error>::from(
-            "Expected an authenticated session of the given client.",
-        ))?,
+    let _db_lock = match dry_run {
+        true => None,
+        false => Some(bridge::lock_db(db_config)?),
     };
+    let namespaced_entries = state_store::read_namespaced_entries(&db_config.db_path)?;
+    let entry_count: usize = namespaced_entries.values().map(Vec::len).sum();
+    if dry_run {
+        println!("Dry run: migrate {entry_count} links");
+        return Ok(());
+    }
 
-    let image_opt = match image_url_opt {
-        Some(image_url) => {
-            let blob = upload_remote_image_to_bsky(client, &image_url).await?;
-            Some(bsky::embed::images::Image {
-                alt: image_url,
-                image: blob,
-            })
-        }
-        None => None,
-    };
+    let mut count = 0;
+    for (namespace_opt, entries) in namespaced_entries {
+        let mut state_store = bridge::open_state_store(db_config, namespace_opt)?;
+        count += state_store::import_entries(state_store.as_mut(), &entries)?;
+        state_store.commit()?;
+    }
+    println!(
+        "Migrated {count} of {entry_count} links into {}",
+        sqlite_store::sqlite_path(&db_config.db_path)
+    );
+    Ok(())
+}
 
-    let embed = image_opt.map(|image| {
-        post::RecordEmbedEnum::AppBskyEmbedImagesMain(Box::new(bsky::embed::images::Main {
-            images: vec![image],
-        }))
-    });
-
-    let input = create_record::Input {
-        collection: String::from("app.bsky.feed.post"),
-        record: Record::AppBskyFeedPost(Box::new(post::Record {
-            created_at: Utc::now().to_rfc3339(),
-            embed,
-            entities: None,
-            facets: Some(facets),
-            reply: None,
-            text: text,
-        })),
-        repo: String::from(target_did),
-        rkey: None,
-        swap_commit: None,
-        validate: None,
+fn command_status(db_config: &DbConfig, show_stats: bool) -> Result<(), Box<dyn Error>> {
+    let state_store = bridge::open_state_store(db_config, None)?;
+    let db_name = match db_config.backend {
+        DbBackend::File | DbBackend::S3 => db_config.db_path.to_string(),
+        DbBackend::Redis => String::from("Redis"),
+        DbBackend::Sqlite => sqlite_store::sqlite_path(&db_config.db_path),
     };
+    println!(
+        "DB: {db_name}: {} links, {} posted records",
+        state_store.links().len(),
+        state_store.posted_records().len()
+    );
 
-    let result = client.create_record(input).await?;
-    Ok(BskyPost {
-        cid: result.cid,
-        uri: result.uri,
-    })
-}
+    if show_stats {
+        let stats = stats::load_stats(&db_config.stats_path)?;
+        stats::print_stats(&stats, Utc::now());
+    }
 
-async fn upload_remote_image_to_bsky<Client>(
-    client: &Client,
-    image_url: &str,
-) -> Result<BlobRef, Box<dyn Error>>
-where
-    Client: XrpcHttpClient + atproto::repo::upload_blob::UploadBlob + Sync,
-{
-    let remote_content = client.get_remote_content(image_url).await?;
-    let output = client.upload_blob(remote_content.to_vec()).await?;
-    Ok(output.blob)
+    Ok(())
 }
@@ -0,0 +1,150 @@
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+/// Which hosts remote media and link card pages may be downloaded from.
+#[derive(Clone, Debug, Default)]
+pub struct MediaHostPolicy {
+    /// Allow only these hosts and their subdomains when not empty
+    pub allow_hosts: Vec<String>,
+    /// Refuse these hosts and their subdomains
+    pub deny_hosts: Vec<String>,
+    /// Allow the loopback and private network addresses
+    pub allow_private: bool,
+}
+
+fn matches_host(host: &str, pattern: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+    host == pattern || host.ends_with(&format!(".{pattern}"))
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || a == 0
+        // The shared address space for carrier-grade NATs.
+        || (a == 100 && (64..128).contains(&b)))
+}
+
+fn is_public_ipv6(ip: &Ipv6Addr) -> bool {
+    if let Some(ipv4) = ip.to_ipv4_mapped() {
+        return is_public_ipv4(&ipv4);
+    }
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        // The unique local addresses.
+        || (first & 0xfe00) == 0xfc00
+        // The link-local addresses.
+        || (first & 0xffc0) == 0xfe80)
+}
+
+fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => is_public_ipv6(ip),
+    }
+}
+
+impl MediaHostPolicy {
+    /// Check the host of the URL against the lists, and the address when the
+    /// host is an IP address, which is not resolved.
+    pub fn check_url(&self, url: &reqwest::Url) -> Result<(), Box<dyn Error>> {
+        let host_str = match url.host_str() {
+            Some(host) => host,
+            None => Err(format!("No host in the media URL: {url}"))?,
+        };
+        if self
+            .deny_hosts
+            .iter()
+            .any(|pattern| matches_host(host_str, pattern))
+        {
+            Err(format!("Denied media host: {host_str}"))?;
+        }
+        if !self.allow_hosts.is_empty()
+            && !self
+                .allow_hosts
+                .iter()
+                .any(|pattern| matches_host(host_str, pattern))
+        {
+            Err(format!("Not allowed media host: {host_str}"))?;
+        }
+        // IPv6 addresses are in brackets in URLs.
+        let ip_opt = host_str
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .ok();
+        match ip_opt {
+            Some(ip) if !self.allow_private && !is_public_ip(&ip) => {
+                Err(format!("Refused a private media address: {ip}"))?
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Resolve names only to public addresses, so that domains pointing to
+/// internal services are refused also after redirects.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_ip(&addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                Err(format!("Refused a private media host: {}", name.as_str()))?;
+            }
+            let addrs: reqwest::dns::Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// Build the client to download remote contents under the policy, which
+/// follows up to the given number of redirects checking each of them.
+pub fn new_media_client(
+    client_builder: reqwest::ClientBuilder,
+    policy: &MediaHostPolicy,
+    max_redirects: usize,
+) -> Result<reqwest::Client, Box<dyn Error>> {
+    let redirect_policy = policy.clone();
+    let mut client_builder =
+        client_builder.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+            // The first URL is the requested one.
+            let redirects = attempt.previous().len();
+            let url = attempt.url().to_string();
+            if let Err(err) = redirect_policy.check_url(attempt.url()) {
+                attempt.error(format!("Refused the redirect to {url}: {err}"))
+            } else if redirects > max_redirects {
+                let chain = attempt
+                    .previous()
+                    .iter()
+                    .map(|url| url.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" -> ");
+                attempt.error(format!(
+                    "Too many redirects: over {max_redirects} in {chain} -> {url}"
+                ))
+            } else {
+                attempt.follow()
+            }
+        }));
+
+    if !policy.allow_private {
+        client_builder = client_builder.dns_resolver(Arc::new(PublicResolver));
+    }
+    let client = client_builder
+        .build()
+        .map_err(|err| format!("Failed to build HTTP client: {err}"))?;
+    Ok(client)
+}
@@ -0,0 +1,22 @@
+/// How to post videos, which Mastodon also makes from animated GIFs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum AnimationPolicy {
+    /// Upload the video as a video embed, or attach the link card when it is over the limits
+    Video,
+    /// Attach the first frame as an image, extracted with ffmpeg
+    Still,
+    /// Attach the original link as an external card
+    Card,
+}
+
+/// How to post images whose size in the feed is over the threshold, decided
+/// before downloading them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LargeImagePolicy {
+    /// Attach the thumbnail of the image if any, or nothing
+    Skip,
+    /// Download the image and downscale it to fit in the blob limit
+    Resize,
+    /// Attach the thumbnail of the image if any, or the original link as an external card instead
+    LinkOnly,
+}
@@ -0,0 +1,106 @@
+use regex::Regex;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use crate::richtext::{RichText, RichTextSegment};
+
+/// A Fediverse account mapped to a Bluesky DID, written as `user@domain=did`.
+#[derive(Clone, Debug)]
+pub struct MentionMapping {
+    acct: String,
+    did: String,
+}
+
+impl FromStr for MentionMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (acct, did) = match s.split_once('=') {
+            Some(pair) => pair,
+            None => return Err(format!("Missing '=' in the mention mapping: {s}")),
+        };
+        if !did.starts_with("did:") {
+            return Err(format!("Not a DID in the mention mapping: {did}"));
+        }
+        Ok(MentionMapping {
+            acct: acct.trim_start_matches('@').to_lowercase(),
+            did: did.to_string(),
+        })
+    }
+}
+
+fn mention_regex() -> &'static Regex {
+    static MENTION_REGEX: OnceLock<Regex> = OnceLock::new();
+    MENTION_REGEX.get_or_init(|| {
+        Regex::new(r"(^|[^\w@/.])@(\w+)@([A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)+)")
+            .expect("Valid mention regex")
+    })
+}
+
+/// The `user@domain` of a profile link such as `https://domain/@user`.
+fn profile_acct(link: &str) -> Option<String> {
+    let url = reqwest::Url::parse(link).ok()?;
+    let user = url.path().strip_prefix("/@")?;
+    if user.is_empty() || user.contains(['/', '@']) {
+        return None;
+    }
+    Some(format!("{}@{}", user, url.host_str()?).to_lowercase())
+}
+
+/// Link the `@user@domain` mentions in the plain text to their profiles, and
+/// turn the mentions of the mapped accounts into Bluesky mentions.
+pub fn link_mentions(text: RichText, mappings: &[MentionMapping]) -> RichText {
+    let mapped_did = |acct: &str| {
+        mappings
+            .iter()
+            .find(|mapping| mapping.acct == acct)
+            .map(|mapping| mapping.did.to_string())
+    };
+
+    let mut linked: RichText = vec![];
+    for seg in text {
+        let plain = match seg {
+            RichTextSegment::PlainText { text } => text,
+            RichTextSegment::Link { text, link } if text.starts_with('@') => {
+                match profile_acct(&link).and_then(|acct| mapped_did(&acct)) {
+                    Some(did) => linked.push(RichTextSegment::Mention { text, did }),
+                    None => linked.push(RichTextSegment::Link { text, link }),
+                }
+                continue;
+            }
+            seg => {
+                linked.push(seg);
+                continue;
+            }
+        };
+
+        let mut last = 0;
+        for caps in mention_regex().captures_iter(&plain) {
+            let (start, end) = match (caps.get(1), caps.get(0)) {
+                (Some(prefix), Some(whole)) => (prefix.end(), whole.end()),
+                _ => continue,
+            };
+            let (user, domain) = (&caps[2], &caps[3]);
+            if start > last {
+                linked.push(RichTextSegment::PlainText {
+                    text: plain[last..start].to_string(),
+                });
+            }
+            let mention = plain[start..end].to_string();
+            match mapped_did(&format!("{user}@{domain}").to_lowercase()) {
+                Some(did) => linked.push(RichTextSegment::Mention { text: mention, did }),
+                None => linked.push(RichTextSegment::Link {
+                    text: mention,
+                    link: format!("https://{domain}/@{user}"),
+                }),
+            }
+            last = end;
+        }
+        if last < plain.len() {
+            linked.push(RichTextSegment::PlainText {
+                text: plain[last..].to_string(),
+            });
+        }
+    }
+    linked
+}
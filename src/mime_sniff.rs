@@ -0,0 +1,45 @@
+/// The MIME type of the image, sniffed from its magic bytes.
+pub fn image_mime(content: &[u8]) -> Option<&'static str> {
+    if content.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if content.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if content.starts_with(b"GIF87a") || content.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if content.len() >= 12 && &content[..4] == b"RIFF" && &content[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// The MIME type of the video, sniffed from its magic bytes.
+pub fn video_mime(content: &[u8]) -> Option<&'static str> {
+    if content.len() >= 12 && &content[4..8] == b"ftyp" {
+        match &content[8..12] {
+            b"qt  " => Some("video/quicktime"),
+            _ => Some("video/mp4"),
+        }
+    } else if content.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        Some("video/webm")
+    } else {
+        None
+    }
+}
+
+/// Whether the `Content-Type` header, if any, allows the content of the kind,
+/// such as `image`. Servers often respond HTML error pages with 200.
+pub fn is_content_type_of(content_type_opt: Option<&str>, kind: &str) -> bool {
+    let content_type = match content_type_opt {
+        Some(content_type) => content_type,
+        None => return true,
+    };
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_lowercase();
+    essence == "application/octet-stream"
+        || essence.split_once('/').is_some_and(|(typ, _)| typ == kind)
+}
@@ -0,0 +1,424 @@
+use base64::Engine;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+
+/// The scope to post records, as app passwords can.
+const SCOPE: &str = "atproto transition:generic";
+
+const CALLBACK_PATH: &str = "/callback";
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn random_base64url(len: usize) -> Result<String, Box<dyn Error>> {
+    let mut bytes = vec![0u8; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| "Failed to generate random bytes")?;
+    Ok(base64url(&bytes))
+}
+
+/// The P-256 key which the OAuth tokens are bound to by DPoP.
+pub struct DpopKey {
+    pkcs8: Vec<u8>,
+    key_pair: EcdsaKeyPair,
+}
+
+impl DpopKey {
+    pub fn generate() -> Result<Self, Box<dyn Error>> {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .map_err(|_| "Failed to generate DPoP key")?;
+        Self::from_pkcs8(pkcs8.as_ref().to_vec())
+    }
+
+    /// The key saved by `to_base64`.
+    pub fn from_base64(encoded: &str) -> Result<Self, Box<dyn Error>> {
+        let pkcs8 = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|err| format!("Failed to decode DPoP key: {err}"))?;
+        Self::from_pkcs8(pkcs8)
+    }
+
+    fn from_pkcs8(pkcs8: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8)
+            .map_err(|err| format!("Failed to load DPoP key: {err}"))?;
+        Ok(DpopKey { pkcs8, key_pair })
+    }
+
+    /// The key in PKCS#8 as base64, to be saved with the session.
+    pub fn to_base64(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(&self.pkcs8)
+    }
+
+    /// A DPoP proof of the request to the URL, which has the hash of the
+    /// access token for the requests to the PDS.
+    pub fn proof(
+        &self,
+        method: &str,
+        url: &str,
+        nonce_opt: Option<&str>,
+        access_token_opt: Option<&str>,
+    ) -> Result<String, Box<dyn Error>> {
+        // The public key is the uncompressed point, 0x04 || x || y.
+        let public_key = self.key_pair.public_key().as_ref();
+        let header = serde_json::json!({
+            "typ": "dpop+jwt",
+            "alg": "ES256",
+            "jwk": {
+                "kty": "EC",
+                "crv": "P-256",
+                "x": base64url(&public_key[1..33]),
+                "y": base64url(&public_key[33..65]),
+            },
+        });
+        // The URL is without the query and the fragment.
+        let mut htu = reqwest::Url::parse(url)?;
+        htu.set_query(None);
+        htu.set_fragment(None);
+        let mut claims = serde_json::json!({
+            "jti": random_base64url(16)?,
+            "htm": method,
+            "htu": htu.as_str(),
+            "iat": chrono::Utc::now().timestamp(),
+        });
+        if let Some(nonce) = nonce_opt {
+            claims["nonce"] = nonce.into();
+        }
+        if let Some(access_token) = access_token_opt {
+            claims["ath"] = base64url(&Sha256::digest(access_token.as_bytes())).into();
+        }
+        let signing_input = format!(
+            "{}.{}",
+            base64url(&serde_json::to_vec(&header)?),
+            base64url(&serde_json::to_vec(&claims)?)
+        );
+        let signature = self
+            .key_pair
+            .sign(&SystemRandom::new(), signing_input.as_bytes())
+            .map_err(|_| "Failed to sign DPoP proof")?;
+        Ok(format!("{signing_input}.{}", base64url(signature.as_ref())))
+    }
+}
+
+/// What refreshes and revokes the OAuth tokens, kept in the session.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OAuthSession {
+    /// The authorization server
+    pub issuer: String,
+    pub client_id: String,
+    pub token_endpoint: String,
+    #[serde(default, rename = "revocation_endpoint")]
+    pub revocation_endpoint_opt: Option<String>,
+    /// The DPoP key by `DpopKey::to_base64`
+    pub dpop_key: String,
+}
+
+pub struct OAuthTokens {
+    pub did: String,
+    pub access_token: String,
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize)]
+struct ProtectedResourceMetadata {
+    authorization_servers: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct AuthorizationServerMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    pushed_authorization_request_endpoint: String,
+    revocation_endpoint: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ParResponse {
+    request_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    token_type: String,
+    sub: String,
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<T, Box<dyn Error>> {
+    let body = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|err| format!("Failed to get {url}: {err}"))?
+        .bytes()
+        .await
+        .map_err(|err| format!("Failed to get {url}: {err}"))?;
+    serde_json::from_slice(&body).map_err(|err| format!("Failed to parse {url}: {err}").into())
+}
+
+/// Post the form with a DPoP proof, retrying once by the nonce which the
+/// authorization server requires.
+async fn post_form<T: serde::de::DeserializeOwned>(
+    client: &reqwest::Client,
+    dpop_key: &DpopKey,
+    url: &str,
+    params: &[(&str, &str)],
+) -> Result<T, Box<dyn Error>> {
+    let mut nonce_opt: Option<String> = None;
+    loop {
+        let proof = dpop_key.proof("POST", url, nonce_opt.as_deref(), None)?;
+        let res = client
+            .post(url)
+            .header("DPoP", proof)
+            .form(params)
+            .send()
+            .await
+            .map_err(|err| format!("Failed to post to {url}: {err}"))?;
+        let status = res.status();
+        let new_nonce_opt = res
+            .headers()
+            .get("DPoP-Nonce")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let body = res
+            .bytes()
+            .await
+            .map_err(|err| format!("Failed to post to {url}: {err}"))?;
+        if status.is_success() {
+            return serde_json::from_slice(&body)
+                .map_err(|err| format!("Failed to parse the response of {url}: {err}").into());
+        }
+        let use_nonce = serde_json::from_slice::<serde_json::Value>(&body)
+            .is_ok_and(|body| body["error"] == "use_dpop_nonce");
+        match new_nonce_opt {
+            Some(new_nonce) if use_nonce && nonce_opt.is_none() => nonce_opt = Some(new_nonce),
+            _ => Err(format!(
+                "Failed to post to {url}: status={status}, body={:?}",
+                String::from_utf8_lossy(&body)
+            ))?,
+        }
+    }
+}
+
+/// The metadata of the authorization server of the PDS.
+async fn authorization_server(
+    client: &reqwest::Client,
+    pds: &str,
+) -> Result<AuthorizationServerMetadata, Box<dyn Error>> {
+    let resource: ProtectedResourceMetadata = get_json(
+        client,
+        &format!("{pds}/.well-known/oauth-protected-resource"),
+    )
+    .await?;
+    let issuer = resource
+        .authorization_servers
+        .first()
+        .ok_or_else(|| format!("No authorization server of {pds}"))?;
+    let metadata: AuthorizationServerMetadata = get_json(
+        client,
+        &format!(
+            "{}/.well-known/oauth-authorization-server",
+            issuer.trim_end_matches('/')
+        ),
+    )
+    .await?;
+    if metadata.issuer.trim_end_matches('/') != issuer.trim_end_matches('/') {
+        Err(format!(
+            "The authorization server {issuer} claims another issuer {}",
+            metadata.issuer
+        ))?;
+    }
+    Ok(metadata)
+}
+
+/// Authorize the account on its PDS in the browser, receiving the redirect
+/// at a loopback port, as a native client which has no client metadata
+/// hosted.
+pub async fn authorize(
+    client: &reqwest::Client,
+    did: &str,
+    login_hint: &str,
+    pds: &str,
+) -> Result<(OAuthSession, OAuthTokens), Box<dyn Error>> {
+    let metadata = authorization_server(client, pds).await?;
+    let dpop_key = DpopKey::generate()?;
+
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .map_err(|err| format!("Failed to listen for the redirect: {err}"))?;
+    let redirect_uri = format!(
+        "http://127.0.0.1:{}{CALLBACK_PATH}",
+        listener.local_addr()?.port()
+    );
+    let client_id = format!(
+        "http://localhost?{}",
+        serde_urlencoded::to_string([("redirect_uri", redirect_uri.as_str()), ("scope", SCOPE)])?
+    );
+    let state = random_base64url(16)?;
+    let code_verifier = random_base64url(32)?;
+    let code_challenge = base64url(&Sha256::digest(code_verifier.as_bytes()));
+
+    let par: ParResponse = post_form(
+        client,
+        &dpop_key,
+        &metadata.pushed_authorization_request_endpoint,
+        &[
+            ("client_id", &client_id),
+            ("response_type", "code"),
+            ("redirect_uri", &redirect_uri),
+            ("scope", SCOPE),
+            ("state", &state),
+            ("code_challenge", &code_challenge),
+            ("code_challenge_method", "S256"),
+            ("login_hint", login_hint),
+        ],
+    )
+    .await?;
+    let mut authorization_url = reqwest::Url::parse(&metadata.authorization_endpoint)?;
+    authorization_url
+        .query_pairs_mut()
+        .append_pair("client_id", &client_id)
+        .append_pair("request_uri", &par.request_uri);
+    println!("Open this URL in the browser to authorize:\n\n  {authorization_url}\n");
+    println!("Waiting for the redirect to {redirect_uri} ...");
+
+    let callback = tokio::task::spawn_blocking(move || wait_callback(listener))
+        .await?
+        .map_err(|err| format!("Failed to receive the redirect: {err}"))?;
+    let param = |name: &str| {
+        callback
+            .query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    if let Some(error) = param("error") {
+        Err(format!(
+            "Failed to authorize: {error}: {}",
+            param("error_description").unwrap_or_default()
+        ))?;
+    }
+    if param("state").as_deref() != Some(&state) {
+        Err("Failed to authorize: the state does not match")?;
+    }
+    if param("iss").is_some_and(|iss| iss != metadata.issuer) {
+        Err("Failed to authorize: the issuer does not match")?;
+    }
+    let code = param("code").ok_or("Failed to authorize: no code is given")?;
+
+    let token: TokenResponse = post_form(
+        client,
+        &dpop_key,
+        &metadata.token_endpoint,
+        &[
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", &redirect_uri),
+            ("code_verifier", &code_verifier),
+            ("client_id", &client_id),
+        ],
+    )
+    .await?;
+    let session = OAuthSession {
+        issuer: metadata.issuer,
+        client_id,
+        token_endpoint: metadata.token_endpoint,
+        revocation_endpoint_opt: metadata.revocation_endpoint,
+        dpop_key: dpop_key.to_base64(),
+    };
+    Ok((session, checked_tokens(token, did)?))
+}
+
+/// The tokens of the response, which must be of the account.
+fn checked_tokens(token: TokenResponse, did: &str) -> Result<OAuthTokens, Box<dyn Error>> {
+    if !token.token_type.eq_ignore_ascii_case("DPoP") {
+        Err(format!("Unsupported token type: {}", token.token_type))?;
+    }
+    if token.sub != did {
+        Err(format!("The tokens are of {}, not of {did}", token.sub))?;
+    }
+    Ok(OAuthTokens {
+        did: token.sub,
+        access_token: token.access_token,
+        refresh_token: token.refresh_token,
+    })
+}
+
+/// Accept the connections until the redirect to the callback path, and
+/// return its URL.
+fn wait_callback(listener: TcpListener) -> Result<reqwest::Url, Box<dyn Error + Send + Sync>> {
+    loop {
+        let (mut stream, _) = listener.accept()?;
+        let mut request_line = String::new();
+        BufReader::new(&stream).read_line(&mut request_line)?;
+        let path = request_line.split_whitespace().nth(1).unwrap_or("");
+        let url = reqwest::Url::parse(&format!("http://127.0.0.1{path}"))?;
+        if url.path() != CALLBACK_PATH {
+            write!(
+                stream,
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+            )?;
+            continue;
+        }
+        let body = "Authorized. You can close this window.";
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )?;
+        return Ok(url);
+    }
+}
+
+/// Refresh the tokens of the account by the refresh token.
+pub async fn refresh(
+    client: &reqwest::Client,
+    session: &OAuthSession,
+    did: &str,
+    refresh_token: &str,
+) -> Result<OAuthTokens, Box<dyn Error>> {
+    let dpop_key = DpopKey::from_base64(&session.dpop_key)?;
+    let token: TokenResponse = post_form(
+        client,
+        &dpop_key,
+        &session.token_endpoint,
+        &[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("client_id", &session.client_id),
+        ],
+    )
+    .await?;
+    checked_tokens(token, did)
+}
+
+/// Revoke the token, if the authorization server supports it.
+pub async fn revoke(
+    client: &reqwest::Client,
+    session: &OAuthSession,
+    token: &str,
+) -> Result<(), Box<dyn Error>> {
+    let Some(revocation_endpoint) = &session.revocation_endpoint_opt else {
+        return Ok(());
+    };
+    let dpop_key = DpopKey::from_base64(&session.dpop_key)?;
+    post_form::<serde_json::Value>(
+        client,
+        &dpop_key,
+        revocation_endpoint,
+        &[("token", token), ("client_id", &session.client_id)],
+    )
+    .await
+    .map(|_| ())
+}
@@ -0,0 +1,126 @@
+use html5ever::tendril::SliceExt;
+use html5ever::tokenizer::{
+    BufferQueue, Tag, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer,
+};
+
+#[derive(Debug, Default)]
+pub struct OgMeta {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image: Option<String>,
+}
+
+struct OgMetaSink {
+    meta: OgMeta,
+    html_title: Option<String>,
+    html_description: Option<String>,
+    in_title: bool,
+}
+
+impl OgMetaSink {
+    fn process_meta(&mut self, tag: &Tag) {
+        let mut key_opt: Option<String> = None;
+        let mut content_opt: Option<String> = None;
+        for attr in &tag.attrs {
+            match attr.name.local.to_string().as_str() {
+                "property" | "name" => {
+                    key_opt = Some(attr.value.to_string());
+                }
+                "content" => {
+                    content_opt = Some(attr.value.to_string());
+                }
+                _ => {
+                    // do nothing
+                }
+            }
+        }
+
+        if let (Some(key), Some(content)) = (key_opt, content_opt) {
+            match key.as_str() {
+                "og:title" => {
+                    self.meta.title = Some(content);
+                }
+                "og:description" => {
+                    self.meta.description = Some(content);
+                }
+                "og:image" => {
+                    self.meta.image = Some(content);
+                }
+                "description" => {
+                    self.html_description = Some(content);
+                }
+                _ => {
+                    // do nothing
+                }
+            }
+        }
+    }
+}
+
+impl TokenSink for OgMetaSink {
+    type Handle = ();
+
+    fn process_token(&mut self, token: Token, _line_number: u64) -> TokenSinkResult<Self::Handle> {
+        match token {
+            Token::CharacterTokens(bs) if self.in_title => {
+                self.html_title
+                    .get_or_insert_with(String::new)
+                    .push_str(&bs);
+            }
+            Token::TagToken(tag) => match (tag.kind, tag.name.to_string().as_str()) {
+                (TagKind::StartTag, "meta") => {
+                    self.process_meta(&tag);
+                }
+                (TagKind::StartTag, "title") => {
+                    self.in_title = true;
+                }
+                (TagKind::EndTag, "title") => {
+                    self.in_title = false;
+                }
+                _ => {
+                    // do nothing
+                }
+            },
+            _ => {
+                // do nothing
+            }
+        }
+        TokenSinkResult::Continue
+    }
+}
+
+/// Collect the OpenGraph metadata of the page, falling back to `<title>` and
+/// the description meta. A relative image URL is resolved against the page URL.
+pub fn parse_og_meta(page_url: &str, html: &str) -> OgMeta {
+    let mut tokenizer = Tokenizer::new(
+        OgMetaSink {
+            meta: Default::default(),
+            html_title: None,
+            html_description: None,
+            in_title: false,
+        },
+        Default::default(),
+    );
+
+    let mut queue = BufferQueue::new();
+    queue.push_back(html.to_tendril());
+
+    let _ = tokenizer.feed(&mut queue);
+    tokenizer.end();
+
+    let OgMetaSink {
+        mut meta,
+        html_title,
+        html_description,
+        ..
+    } = tokenizer.sink;
+    meta.title = meta.title.or(html_title).map(|x| x.trim().to_string());
+    meta.description = meta.description.or(html_description);
+    meta.image = meta.image.and_then(|image| {
+        reqwest::Url::parse(page_url)
+            .and_then(|base| base.join(&image))
+            .map(|url| url.to_string())
+            .ok()
+    });
+    meta
+}
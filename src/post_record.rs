@@ -0,0 +1,244 @@
+use async_trait::async_trait;
+use atrium_api::app::bsky::feed::post;
+use atrium_api::blob::BlobRef;
+use atrium_api::com::atproto::repo::{create_record, get_record, put_record};
+use atrium_api::xrpc;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// An `app.bsky.feed.post` record with the fields which `post::Record` of
+/// atrium-api does not support yet.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostRecord {
+    #[serde(rename = "$type")]
+    pub typ: &'static str,
+    #[serde(flatten)]
+    pub base: post::Record,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub langs: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<SelfLabels>,
+    /// The embed when `base.embed` is not set, which `post::RecordEmbedEnum` cannot hold.
+    #[serde(rename = "embed", skip_serializing_if = "Option::is_none")]
+    pub custom_embed: Option<CustomEmbed>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum CustomEmbed {
+    Images(ImagesEmbed),
+    Video(VideoEmbed),
+}
+
+/// `app.bsky.embed.images` with the aspect ratios
+#[derive(Debug, Serialize)]
+pub struct ImagesEmbed {
+    #[serde(rename = "$type")]
+    pub typ: &'static str,
+    pub images: Vec<ImagesEmbedImage>,
+}
+
+/// `app.bsky.embed.images#image`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImagesEmbedImage {
+    pub alt: String,
+    pub image: BlobRef,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aspect_ratio: Option<AspectRatio>,
+}
+
+/// `app.bsky.embed.defs#aspectRatio`
+#[derive(Debug, Serialize)]
+pub struct AspectRatio {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ImagesEmbed {
+    pub fn new(images: Vec<ImagesEmbedImage>) -> Self {
+        Self {
+            typ: "app.bsky.embed.images",
+            images,
+        }
+    }
+}
+
+/// `app.bsky.embed.video`
+#[derive(Debug, Serialize)]
+pub struct VideoEmbed {
+    #[serde(rename = "$type")]
+    pub typ: &'static str,
+    pub video: BlobRef,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alt: Option<String>,
+}
+
+impl VideoEmbed {
+    pub fn new(video: BlobRef, alt: Option<String>) -> Self {
+        Self {
+            typ: "app.bsky.embed.video",
+            video,
+            alt,
+        }
+    }
+}
+
+/// `com.atproto.label.defs#selfLabels`
+#[derive(Debug, Serialize)]
+pub struct SelfLabels {
+    #[serde(rename = "$type")]
+    pub typ: &'static str,
+    pub values: Vec<SelfLabel>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SelfLabel {
+    pub val: String,
+}
+
+impl SelfLabels {
+    pub fn new(vals: &[String]) -> Self {
+        Self {
+            typ: "com.atproto.label.defs#selfLabels",
+            values: vals
+                .iter()
+                .map(|val| SelfLabel {
+                    val: val.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl PostRecord {
+    pub fn new(base: post::Record) -> Self {
+        Self {
+            typ: "app.bsky.feed.post",
+            base,
+            langs: None,
+            labels: None,
+            custom_embed: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreatePostInput {
+    pub collection: String,
+    pub record: PostRecord,
+    pub repo: String,
+    /// The key of the record, which makes retries of the request idempotent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rkey: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PutPostInput {
+    pub collection: String,
+    pub record: PostRecord,
+    pub repo: String,
+    pub rkey: String,
+    /// The CID of the record to replace, which fails if it has changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swap_record: Option<String>,
+}
+
+/// `com.atproto.repo.putRecord` for `PostRecord`.
+#[async_trait]
+pub trait PutPost: xrpc::XrpcClient {
+    async fn put_post(&self, input: PutPostInput) -> Result<put_record::Output, Box<dyn Error>> {
+        let body = xrpc::XrpcClient::send::<put_record::Error>(
+            self,
+            xrpc::http::Method::POST,
+            "com.atproto.repo.putRecord",
+            None,
+            Some(serde_json::to_vec(&input)?),
+            Some(String::from("application/json")),
+        )
+        .await?;
+        serde_json::from_slice(&body).map_err(|e| e.into())
+    }
+}
+
+/// `com.atproto.repo.createRecord` for `PostRecord`.
+#[async_trait]
+pub trait CreatePost: xrpc::XrpcClient {
+    async fn create_post(
+        &self,
+        input: CreatePostInput,
+    ) -> Result<create_record::Output, Box<dyn Error>> {
+        let body = xrpc::XrpcClient::send::<create_record::Error>(
+            self,
+            xrpc::http::Method::POST,
+            "com.atproto.repo.createRecord",
+            None,
+            Some(serde_json::to_vec(&input)?),
+            Some(String::from("application/json")),
+        )
+        .await?;
+        serde_json::from_slice(&body).map_err(|e| e.into())
+    }
+}
+
+/// The URI and the CID of a record.
+#[derive(Debug, Deserialize)]
+pub struct RecordRef {
+    pub uri: String,
+    pub cid: Option<String>,
+}
+
+/// `com.atproto.repo.getRecord` for the reference of the record only, as
+/// `get_record::GetRecord` of atrium-api fails to parse the records with the
+/// fields newer than it.
+#[async_trait]
+pub trait GetRecordRef: xrpc::XrpcClient {
+    async fn get_record_ref(
+        &self,
+        params: &get_record::Parameters,
+    ) -> Result<RecordRef, Box<dyn Error>> {
+        let body = xrpc::XrpcClient::send::<get_record::Error>(
+            self,
+            xrpc::http::Method::GET,
+            "com.atproto.repo.getRecord",
+            Some(serde_urlencoded::to_string(params)?),
+            None,
+            None,
+        )
+        .await?;
+        serde_json::from_slice(&body).map_err(|e| e.into())
+    }
+}
+
+const TID_CHARS: &[u8; 32] = b"234567abcdefghijklmnopqrstuvwxyz";
+
+/// A new record key as a TID, the microseconds since the epoch and a clock
+/// ID in the sortable base32. The keys are increasing in a process even in
+/// the same microsecond.
+pub fn new_tid() -> String {
+    static LAST_TID: AtomicU64 = AtomicU64::new(0);
+
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_micros() as u64)
+        .unwrap_or(0);
+    let clock_id = u64::from(std::process::id()) & 0x3ff;
+    let candidate = ((micros << 10) | clock_id) & (u64::MAX >> 1);
+    let prev = LAST_TID
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            Some(candidate.max(last + (1 << 10)))
+        })
+        .unwrap_or(0);
+    let mut value = candidate.max(prev + (1 << 10));
+    let mut chars = [0u8; 13];
+    for c in chars.iter_mut().rev() {
+        *c = TID_CHARS[(value & 31) as usize];
+        value >>= 5;
+    }
+    String::from_utf8_lossy(&chars).into_owned()
+}
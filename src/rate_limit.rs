@@ -0,0 +1,95 @@
+use chrono::{DateTime, TimeZone, Utc};
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use crate::logging::Event;
+
+/// The wait for a 429 response without the reset time.
+const DEFAULT_RETRY_SECS: u64 = 60;
+
+/// The budget of the requests left to the XRPC host, by the `RateLimit-*`
+/// headers of its last response.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub remaining: u64,
+    /// When the budget is reset
+    pub reset_at: DateTime<Utc>,
+}
+
+impl RateLimit {
+    /// The budget in the headers, or `None` if the host does not tell it.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let remaining = header_u64(headers, "ratelimit-remaining")?;
+        let reset_at = reset_time(header_u64(headers, "ratelimit-reset")?)?;
+        Some(RateLimit {
+            remaining,
+            reset_at,
+        })
+    }
+
+    /// How long to wait before the next request, when the budget is used up.
+    pub fn wait(&self) -> Option<Duration> {
+        if self.remaining > 0 {
+            return None;
+        }
+        (self.reset_at - Utc::now()).to_std().ok()
+    }
+}
+
+/// How long to wait before retrying the request rate limited by the
+/// response, by `Retry-After` or `RateLimit-Reset`.
+pub fn retry_wait(headers: &HeaderMap) -> Duration {
+    if let Some(secs) = header_u64(headers, "retry-after") {
+        return Duration::from_secs(secs);
+    }
+    header_u64(headers, "ratelimit-reset")
+        .and_then(reset_time)
+        .and_then(|reset_at| (reset_at - Utc::now()).to_std().ok())
+        .unwrap_or(Duration::from_secs(DEFAULT_RETRY_SECS))
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+/// The reset time, which Bluesky gives in seconds since the epoch, and other
+/// hosts may give in seconds from now as the IETF draft.
+fn reset_time(reset: u64) -> Option<DateTime<Utc>> {
+    const EPOCH_THRESHOLD: u64 = 1_000_000_000;
+
+    if reset >= EPOCH_THRESHOLD {
+        Utc.timestamp_opt(i64::try_from(reset).ok()?, 0).single()
+    } else {
+        Some(Utc::now() + chrono::Duration::seconds(i64::try_from(reset).ok()?))
+    }
+}
+
+/// The budget saved by the last run, so that runs in a row do not exceed it.
+/// An expired or unreadable budget is none.
+pub fn load(path: &str) -> Option<RateLimit> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return None,
+        Err(err) => {
+            Event::warn("Failed to read rate limit").error(err).emit();
+            return None;
+        }
+    };
+    match serde_json::from_str::<RateLimit>(&content) {
+        Ok(rate_limit) if Utc::now() < rate_limit.reset_at => Some(rate_limit),
+        Ok(_) => None,
+        Err(err) => {
+            Event::warn("Failed to parse rate limit").error(err).emit();
+            None
+        }
+    }
+}
+
+pub fn save(path: &str, rate_limit: &RateLimit) -> Result<(), Box<dyn Error>> {
+    std::fs::write(path, serde_json::to_string(rate_limit)?)
+        .map_err(|err| format!("Failed to write rate limit: {err}"))?;
+    Ok(())
+}
@@ -0,0 +1,298 @@
+use chrono::{DateTime, Duration, Utc};
+use redis::{Commands, Connection};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use crate::state_store::{
+    CachedBlob, PostedRecord, StateStore, BLOB_CACHE_SIZE, CREATED_RECORDS_RETENTION_HOURS,
+};
+
+/// The prefix of the keys, followed by the namespace if any.
+const KEY_PREFIX: &str = "mstdn-rss2bsky-post";
+
+/// The timeout of connecting to the server and of each command.
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Run the blocking command of Redis, letting the runtime move the other tasks
+/// off the worker thread meanwhile.
+fn blocking<T>(command: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(command)
+        }
+        _ => command(),
+    }
+}
+
+/// Connect to the server of the URL as `redis://[[user]:password@]host[:port][/db]`,
+/// or `rediss://` for TLS.
+fn connect(url: &str) -> Result<Connection, Box<dyn Error>> {
+    let client =
+        redis::Client::open(url).map_err(|err| format!("Failed to parse Redis URL: {err}"))?;
+    let connection = blocking(|| client.get_connection_with_timeout(TIMEOUT))
+        .map_err(|err| format!("Failed to connect to Redis: {err}"))?;
+    connection
+        .set_read_timeout(Some(TIMEOUT))
+        .and_then(|()| connection.set_write_timeout(Some(TIMEOUT)))
+        .map_err(|err| format!("Failed to connect to Redis: {err}"))?;
+    Ok(connection)
+}
+
+/// A store in Redis, for deployments without a persistent disk.
+///
+/// The links are kept in a sorted set by their post time, and the posted
+/// records in a hash by the link. They are loaded on open and written through
+/// as they change, so the commit has nothing to do and the compaction only
+/// drops the links beyond the retention. The uploaded blobs are cached in a
+/// hash by the key, with a sorted set of the keys by their upload time to keep
+/// the newest ones.
+///
+/// The records created by an account are kept in a sorted set of the account
+/// by their creation time, which is shared by the namespaces.
+pub struct RedisStateStore {
+    connection: Connection,
+    links_key: String,
+    records_key: String,
+    blobs_key: String,
+    blob_keys_key: String,
+    min_save_posts: usize,
+    retention_days_opt: Option<u32>,
+    links: Vec<String>,
+    link_set: HashSet<String>,
+    records: HashMap<String, PostedRecord>,
+    blobs: HashMap<String, CachedBlob>,
+}
+
+impl RedisStateStore {
+    /// Connect to the server and load the links in the namespace if any.
+    pub fn open(
+        url: &str,
+        namespace_opt: Option<&str>,
+        min_save_posts: usize,
+        retention_days_opt: Option<u32>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut connection = connect(url)?;
+        let key_prefix = match namespace_opt {
+            Some(namespace) => format!("{KEY_PREFIX}:{namespace}"),
+            None => KEY_PREFIX.to_string(),
+        };
+        let links_key = format!("{key_prefix}:links");
+        let records_key = format!("{key_prefix}:records");
+        let blobs_key = format!("{key_prefix}:blobs");
+        let blob_keys_key = format!("{key_prefix}:blob-keys");
+
+        let (links, record_fields, blob_fields) = blocking(|| {
+            let links: Vec<String> = connection.zrange(&links_key, 0, -1)?;
+            let record_fields: HashMap<String, String> = connection.hgetall(&records_key)?;
+            let blob_fields: HashMap<String, String> = connection.hgetall(&blobs_key)?;
+            Ok::<_, redis::RedisError>((links, record_fields, blob_fields))
+        })
+        .map_err(|err| format!("Failed to load DB from Redis: {err}"))?;
+        let mut records = HashMap::new();
+        for (link, record_json) in record_fields {
+            if let Ok(record) = serde_json::from_str::<PostedRecord>(&record_json) {
+                records.insert(link, record);
+            }
+        }
+        let mut blobs = HashMap::new();
+        for (key, blob_json) in blob_fields {
+            if let Ok(blob) = serde_json::from_str::<CachedBlob>(&blob_json) {
+                blobs.insert(key, blob);
+            }
+        }
+
+        Ok(RedisStateStore {
+            connection,
+            links_key,
+            records_key,
+            blobs_key,
+            blob_keys_key,
+            min_save_posts,
+            retention_days_opt,
+            link_set: links.iter().cloned().collect(),
+            links,
+            records,
+            blobs,
+        })
+    }
+
+    fn forget_links(&mut self, links: &[String]) -> Result<(), Box<dyn Error>> {
+        if links.is_empty() {
+            return Ok(());
+        }
+        let (links_key, records_key) = (&self.links_key, &self.records_key);
+        let connection = &mut self.connection;
+        blocking(|| {
+            connection.zrem::<_, _, ()>(links_key, links)?;
+            connection.hdel::<_, _, ()>(records_key, links)
+        })
+        .map_err(|err| format!("Failed to remove links from Redis: {err}"))?;
+
+        let dropped: HashSet<&String> = links.iter().collect();
+        self.links.retain(|link| !dropped.contains(link));
+        for link in links {
+            self.link_set.remove(link);
+            self.records.remove(link);
+        }
+        Ok(())
+    }
+}
+
+impl StateStore for RedisStateStore {
+    fn contains(&self, link: &str) -> bool {
+        self.link_set.contains(link)
+    }
+
+    fn links(&self) -> Vec<String> {
+        self.links.clone()
+    }
+
+    fn remove(&mut self, link: &str) -> Result<(), Box<dyn Error>> {
+        self.forget_links(&[link.to_string()])
+    }
+
+    fn record_posted(
+        &mut self,
+        link: &str,
+        record_opt: Option<&PostedRecord>,
+    ) -> Result<(), Box<dyn Error>> {
+        let score = Utc::now().timestamp_millis();
+        let (links_key, connection) = (&self.links_key, &mut self.connection);
+        blocking(|| {
+            redis::cmd("ZADD")
+                .arg(links_key)
+                .arg("NX")
+                .arg(score)
+                .arg(link)
+                .query::<()>(connection)
+        })
+        .map_err(|err| format!("Failed to record link in Redis: {err}"))?;
+        if self.link_set.insert(link.to_string()) {
+            self.links.push(link.to_string());
+        }
+
+        match record_opt {
+            Some(record) => self.update_record(link, record),
+            None => Ok(()),
+        }
+    }
+
+    fn posted_record(&self, link: &str) -> Option<PostedRecord> {
+        self.records.get(link).cloned()
+    }
+
+    fn posted_records(&self) -> Vec<(String, PostedRecord)> {
+        self.records
+            .iter()
+            .map(|(link, record)| (link.to_string(), record.clone()))
+            .collect()
+    }
+
+    fn forget_record(&mut self, link: &str) -> Result<(), Box<dyn Error>> {
+        let (records_key, connection) = (&self.records_key, &mut self.connection);
+        blocking(|| connection.hdel::<_, _, ()>(records_key, link))
+            .map_err(|err| format!("Failed to forget record in Redis: {err}"))?;
+        self.records.remove(link);
+        Ok(())
+    }
+
+    fn update_record(&mut self, link: &str, record: &PostedRecord) -> Result<(), Box<dyn Error>> {
+        let record_json = serde_json::to_string(record)?;
+        let (records_key, connection) = (&self.records_key, &mut self.connection);
+        blocking(|| connection.hset::<_, _, _, ()>(records_key, link, &record_json))
+            .map_err(|err| format!("Failed to record post in Redis: {err}"))?;
+        self.records.insert(link.to_string(), record.clone());
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<(), Box<dyn Error>> {
+        // The links are scored by their post time in milliseconds.
+        let (links_key, connection) = (&self.links_key, &mut self.connection);
+        let dropped: Vec<String> = match self.retention_days_opt {
+            Some(days) => {
+                let cutoff = Utc::now() - Duration::days(i64::from(days));
+                let max_score = format!("({}", cutoff.timestamp_millis());
+                blocking(|| connection.zrangebyscore(links_key, "-inf", max_score))
+            }
+            None => {
+                let stop = -(self.min_save_posts as isize) - 1;
+                blocking(|| connection.zrange(links_key, 0, stop))
+            }
+        }
+        .map_err(|err| format!("Failed to compact DB in Redis: {err}"))?;
+        self.forget_links(&dropped)
+    }
+
+    fn cached_blob(&self, key: &str) -> Option<CachedBlob> {
+        self.blobs.get(key).cloned()
+    }
+
+    fn cache_blob(&mut self, key: &str, blob: &CachedBlob) -> Result<(), Box<dyn Error>> {
+        let blob_json = serde_json::to_string(blob)?;
+        let score = Utc::now().timestamp_millis();
+        let (blobs_key, blob_keys_key) = (&self.blobs_key, &self.blob_keys_key);
+        let connection = &mut self.connection;
+        let dropped = blocking(|| {
+            connection.hset::<_, _, _, ()>(blobs_key, key, &blob_json)?;
+            connection.zadd::<_, _, _, ()>(blob_keys_key, key, score)?;
+            // The oldest blobs beyond the cache size are dropped.
+            let stop = -(BLOB_CACHE_SIZE as isize) - 1;
+            let dropped: Vec<String> = connection.zrange(blob_keys_key, 0, stop)?;
+            if !dropped.is_empty() {
+                connection.zrem::<_, _, ()>(blob_keys_key, &dropped)?;
+                connection.hdel::<_, _, ()>(blobs_key, &dropped)?;
+            }
+            Ok::<_, redis::RedisError>(dropped)
+        })
+        .map_err(|err| format!("Failed to cache blob in Redis: {err}"))?;
+        self.blobs.insert(key.to_string(), blob.clone());
+        for key in dropped {
+            self.blobs.remove(&key);
+        }
+        Ok(())
+    }
+
+    fn created_records_since(
+        &mut self,
+        account: &str,
+        since: DateTime<Utc>,
+    ) -> Result<usize, Box<dyn Error>> {
+        let key = created_records_key(account);
+        let min_score = since.timestamp_millis();
+        let connection = &mut self.connection;
+        let count: usize = blocking(|| connection.zcount(&key, min_score, "+inf"))
+            .map_err(|err| format!("Failed to count created records: {err}"))?;
+        Ok(count)
+    }
+
+    fn record_created(&mut self, account: &str, count: usize) -> Result<(), Box<dyn Error>> {
+        // The records are scored by their creation time in milliseconds, and
+        // told apart by their index.
+        let key = created_records_key(account);
+        let now = Utc::now().timestamp_millis();
+        let members: Vec<(i64, String)> = (0..count)
+            .map(|index| (now, format!("{now}:{index}")))
+            .collect();
+        let max_score = format!(
+            "({}",
+            (Utc::now() - Duration::hours(CREATED_RECORDS_RETENTION_HOURS)).timestamp_millis()
+        );
+        let connection = &mut self.connection;
+        blocking(|| {
+            if !members.is_empty() {
+                connection.zadd_multiple::<_, _, _, ()>(&key, &members)?;
+            }
+            connection.zrembyscore::<_, _, _, ()>(&key, "-inf", &max_score)
+        })
+        .map_err(|err| format!("Failed to record created records in Redis: {err}"))?;
+        Ok(())
+    }
+}
+
+fn created_records_key(account: &str) -> String {
+    format!("{KEY_PREFIX}:created:{account}")
+}
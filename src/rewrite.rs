@@ -0,0 +1,58 @@
+use regex::Regex;
+use std::str::FromStr;
+
+use crate::richtext::{RichText, RichTextSegment};
+
+/// A rule replacing matches of the regex, written as `PATTERN=>REPLACEMENT`.
+/// The replacement can refer to capture groups as `$1` or `${name}`.
+#[derive(Clone, Debug)]
+pub struct RewriteRule {
+    pattern: Regex,
+    replacement: String,
+}
+
+impl FromStr for RewriteRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, replacement) = match s.split_once("=>") {
+            Some(pair) => pair,
+            None => return Err(format!("Missing '=>' in the rewrite rule: {s}")),
+        };
+        let pattern =
+            Regex::new(pattern).map_err(|err| format!("Invalid rewrite pattern: {err}"))?;
+        Ok(RewriteRule {
+            pattern,
+            replacement: replacement.to_string(),
+        })
+    }
+}
+
+/// Apply the rules in order to the text and the targets of the links. Each
+/// segment is rewritten separately, so a match never spans a link.
+pub fn apply_rewrite_rules(text: RichText, rules: &[RewriteRule]) -> RichText {
+    let rewrite = |s: &str| {
+        rules.iter().fold(s.to_string(), |s, rule| {
+            rule.pattern
+                .replace_all(&s, rule.replacement.as_str())
+                .into_owned()
+        })
+    };
+
+    text.into_iter()
+        .map(|seg| match seg {
+            RichTextSegment::PlainText { text } => RichTextSegment::PlainText {
+                text: rewrite(&text),
+            },
+            RichTextSegment::Link { text, link } => RichTextSegment::Link {
+                text: rewrite(&text),
+                link: rewrite(&link),
+            },
+            RichTextSegment::Mention { text, did } => RichTextSegment::Mention {
+                text: rewrite(&text),
+                did,
+            },
+        })
+        .filter(|seg| !seg.text().is_empty())
+        .collect()
+}
@@ -0,0 +1,66 @@
+use regex::Regex;
+use std::sync::OnceLock;
+
+use crate::richtext::{RichText, RichTextSegment};
+
+/// Punctuation which usually ends the sentence rather than the URL.
+const TRAILING_PUNCTUATION: &[char] = &['.', ',', ';', ':', '!', '?', '\'', '"'];
+
+fn url_regex() -> &'static Regex {
+    static URL_REGEX: OnceLock<Regex> = OnceLock::new();
+    URL_REGEX.get_or_init(|| Regex::new(r#"https?://[^\s<>"]+"#).expect("Valid URL regex"))
+}
+
+/// Trim the trailing punctuation and the unbalanced closing parentheses,
+/// as in "(see https://example.com/a_(b))."
+fn trim_url_end(url: &str) -> &str {
+    let mut url = url;
+    loop {
+        let trimmed = url.trim_end_matches(TRAILING_PUNCTUATION);
+        let trimmed = match trimmed.strip_suffix(')') {
+            Some(rest) if rest.matches('(').count() <= rest.matches(')').count() => rest,
+            _ => trimmed,
+        };
+        if trimmed.len() == url.len() {
+            return url;
+        }
+        url = trimmed;
+    }
+}
+
+pub fn autolink(text: RichText) -> RichText {
+    let mut linked: RichText = vec![];
+    for seg in text {
+        let plain = match seg {
+            RichTextSegment::PlainText { text } => text,
+            seg @ (RichTextSegment::Link { .. } | RichTextSegment::Mention { .. }) => {
+                linked.push(seg);
+                continue;
+            }
+        };
+
+        let mut last = 0;
+        for m in url_regex().find_iter(&plain) {
+            let url = trim_url_end(m.as_str());
+            if url.len() <= "https://".len() {
+                continue;
+            }
+            if m.start() > last {
+                linked.push(RichTextSegment::PlainText {
+                    text: plain[last..m.start()].to_string(),
+                });
+            }
+            linked.push(RichTextSegment::Link {
+                text: url.to_string(),
+                link: url.to_string(),
+            });
+            last = m.start() + url.len();
+        }
+        if last < plain.len() {
+            linked.push(RichTextSegment::PlainText {
+                text: plain[last..].to_string(),
+            });
+        }
+    }
+    linked
+}
@@ -0,0 +1,278 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::richtext::{RichText, RichTextSegment};
+
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkBudget {
+    pub max_graphemes: usize,
+    pub max_bytes: usize,
+}
+
+struct Atom<'a> {
+    text: &'a str,
+    graphemes: usize,
+    segment_index: usize,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum BreakPriority {
+    Anywhere,
+    Word,
+    Paragraph,
+}
+
+impl<'a> Atom<'a> {
+    fn break_priority_after(&self) -> BreakPriority {
+        if self.text.ends_with('\n') {
+            BreakPriority::Paragraph
+        } else if self.text.ends_with(char::is_whitespace) {
+            BreakPriority::Word
+        } else {
+            BreakPriority::Anywhere
+        }
+    }
+
+    fn is_blank(&self) -> bool {
+        self.text.chars().all(char::is_whitespace)
+    }
+}
+
+fn atomize<'a>(text: &'a RichText, budget: &ChunkBudget) -> Vec<Atom<'a>> {
+    let mut atoms = vec![];
+    for (segment_index, seg) in text.iter().enumerate() {
+        match seg {
+            RichTextSegment::PlainText { text } => {
+                for g in text.graphemes(true) {
+                    atoms.push(Atom {
+                        text: g,
+                        graphemes: 1,
+                        segment_index,
+                    });
+                }
+            }
+            RichTextSegment::Link { text, .. } | RichTextSegment::Mention { text, .. } => {
+                let graphemes = text.graphemes(true).count();
+                if graphemes <= budget.max_graphemes && text.len() <= budget.max_bytes {
+                    atoms.push(Atom {
+                        text,
+                        graphemes,
+                        segment_index,
+                    });
+                } else {
+                    // The link can never fit in a chunk, so it is split anyway.
+                    for g in text.graphemes(true) {
+                        atoms.push(Atom {
+                            text: g,
+                            graphemes: 1,
+                            segment_index,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    atoms
+}
+
+fn build_chunk(text: &RichText, atoms: &[Atom]) -> RichText {
+    let mut chunk: RichText = vec![];
+    let mut current: Option<(usize, String)> = None;
+    for atom in atoms {
+        match &mut current {
+            Some((segment_index, buf)) if *segment_index == atom.segment_index => {
+                buf.push_str(atom.text);
+            }
+            _ => {
+                if let Some((segment_index, buf)) = current.take() {
+                    chunk.push(text[segment_index].with_text(buf));
+                }
+                current = Some((atom.segment_index, String::from(atom.text)));
+            }
+        }
+    }
+    if let Some((segment_index, buf)) = current {
+        chunk.push(text[segment_index].with_text(buf));
+    }
+    chunk
+}
+
+pub fn chunk_richtext(text: &RichText, budget: ChunkBudget) -> Vec<RichText> {
+    let atoms = atomize(text, &budget);
+
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < atoms.len() {
+        // Skip blanks at the beginning of each chunk.
+        if atoms[start].is_blank() {
+            start += 1;
+            continue;
+        }
+
+        let mut end = start;
+        let mut graphemes = 0;
+        let mut bytes = 0;
+        while end < atoms.len()
+            && graphemes + atoms[end].graphemes <= budget.max_graphemes
+            && bytes + atoms[end].text.len() <= budget.max_bytes
+        {
+            graphemes += atoms[end].graphemes;
+            bytes += atoms[end].text.len();
+            end += 1;
+        }
+
+        if end == start {
+            // A single grapheme exceeds the budget; emit it alone rather than loop forever.
+            end = start + 1;
+        } else if end < atoms.len() {
+            // Only look back over the latter half so that chunks do not get too short.
+            let lower = start + (end - start) / 2;
+            let mut best_priority = BreakPriority::Anywhere;
+            let mut best_end = end;
+            for i in (lower.max(start + 1)..=end).rev() {
+                let priority = atoms[i - 1].break_priority_after();
+                if priority > best_priority {
+                    best_priority = priority;
+                    best_end = i;
+                }
+                if best_priority == BreakPriority::Paragraph {
+                    break;
+                }
+            }
+            end = best_end;
+        }
+
+        let mut chunk_end = end;
+        while chunk_end > start && atoms[chunk_end - 1].is_blank() {
+            chunk_end -= 1;
+        }
+        chunks.push(build_chunk(text, &atoms[start..chunk_end]));
+        start = end;
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain(text: &str) -> RichTextSegment {
+        RichTextSegment::PlainText {
+            text: text.to_string(),
+        }
+    }
+
+    fn link(text: &str) -> RichTextSegment {
+        RichTextSegment::Link {
+            text: text.to_string(),
+            link: text.to_string(),
+        }
+    }
+
+    fn budget(max_graphemes: usize, max_bytes: usize) -> ChunkBudget {
+        ChunkBudget {
+            max_graphemes,
+            max_bytes,
+        }
+    }
+
+    fn texts(chunks: &[RichText]) -> Vec<String> {
+        chunks
+            .iter()
+            .map(|chunk| chunk.iter().map(|seg| seg.text()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn never_splits_grapheme_clusters() {
+        let family = "\u{1F469}\u{200D}\u{1F469}\u{200D}\u{1F467}\u{200D}\u{1F466}";
+        let text = vec![plain(&format!("a{family}e\u{301}"))];
+        assert_eq!(
+            texts(&chunk_richtext(&text, budget(1, 100))),
+            ["a", family, "e\u{301}"]
+        );
+        // A cluster over the byte budget is emitted alone rather than split.
+        assert_eq!(
+            texts(&chunk_richtext(&text, budget(100, 4))),
+            ["a", family, "e\u{301}"]
+        );
+    }
+
+    #[test]
+    fn never_splits_links_which_fit() {
+        let url = "https://example.com/x";
+        let text = vec![plain("aaaa "), link(url)];
+        let chunks = chunk_richtext(&text, budget(24, 300));
+        assert_eq!(texts(&chunks), ["aaaa", url]);
+        assert!(matches!(
+            chunks[1].as_slice(),
+            [RichTextSegment::Link { text, link }] if text == url && link == url
+        ));
+    }
+
+    #[test]
+    fn splits_links_over_budget_by_graphemes() {
+        let url = "https://example.com/abcdefghi";
+        let text = vec![link(url)];
+        let chunks = chunk_richtext(&text, budget(10, 300));
+        assert_eq!(texts(&chunks), ["https://ex", "ample.com/", "abcdefghi"]);
+        // Each part still links to the whole URL.
+        for chunk in &chunks {
+            assert!(matches!(
+                chunk.as_slice(),
+                [RichTextSegment::Link { link, .. }] if link == url
+            ));
+        }
+    }
+
+    #[test]
+    fn prefers_paragraph_breaks() {
+        let text = vec![plain("aaaaaa\nbb cc dd")];
+        assert_eq!(
+            texts(&chunk_richtext(&text, budget(12, 300))),
+            ["aaaaaa", "bb cc dd"]
+        );
+    }
+
+    #[test]
+    fn prefers_word_breaks() {
+        let text = vec![plain("aaaa bbbb cccc")];
+        assert_eq!(
+            texts(&chunk_richtext(&text, budget(12, 300))),
+            ["aaaa bbbb", "cccc"]
+        );
+    }
+
+    #[test]
+    fn enforces_grapheme_budget() {
+        let text = vec![plain("abcdefghij")];
+        assert_eq!(
+            texts(&chunk_richtext(&text, budget(4, 300))),
+            ["abcd", "efgh", "ij"]
+        );
+    }
+
+    #[test]
+    fn enforces_byte_budget() {
+        // Each kana is 3 bytes.
+        let text = vec![plain("\u{3042}\u{3044}\u{3046}\u{3048}\u{304A}")];
+        assert_eq!(
+            texts(&chunk_richtext(&text, budget(300, 7))),
+            ["\u{3042}\u{3044}", "\u{3046}\u{3048}", "\u{304A}"]
+        );
+    }
+
+    #[test]
+    fn trims_and_drops_blank_chunks() {
+        let text = vec![plain("ab  cd")];
+        assert_eq!(texts(&chunk_richtext(&text, budget(4, 300))), ["ab", "cd"]);
+
+        let text = vec![plain("aaaa    \n\n   bbbb")];
+        assert_eq!(
+            texts(&chunk_richtext(&text, budget(4, 300))),
+            ["aaaa", "bbbb"]
+        );
+
+        let text = vec![plain(" \n ")];
+        assert!(chunk_richtext(&text, budget(4, 300)).is_empty());
+    }
+}
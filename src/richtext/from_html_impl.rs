@@ -4,13 +4,19 @@ use html5ever::tokenizer::{
 };
 use std::error::Error;
 
-use crate::richtext::{RichText, RichTextSegment};
+use crate::richtext::{HtmlOptions, RichText, RichTextSegment};
 
-struct Html2RichTextSink {
+struct Html2RichTextSink<'a> {
     text: RichText,
     tag_depth: usize,
+    pre_depth: usize,
+    table_depth: usize,
+    cell_depth: usize,
+    row_cells: usize,
+    skip_next_newline: bool,
     state: ProcessState,
     err: Option<String>,
+    options: &'a HtmlOptions,
 }
 
 enum ProcessState {
@@ -25,8 +31,37 @@ enum ProcessState {
     },
 }
 
-impl Html2RichTextSink {
-    fn process_plain_char(&mut self, c: char) -> () {
+impl<'a> Html2RichTextSink<'a> {
+    fn ends_with_newline(&self) -> bool {
+        let text_continue = match &self.state {
+            ProcessState::NotProcessed => None,
+            ProcessState::ProcessingPlainText { text_continue } => Some(text_continue),
+            ProcessState::ProcessingLink { text_continue, .. } => Some(text_continue),
+        };
+        match text_continue {
+            Some(text_continue) if !text_continue.is_empty() => text_continue.ends_with('\n'),
+            _ => match self.text.last() {
+                Some(seg) => seg.text().ends_with('\n'),
+                None => true,
+            },
+        }
+    }
+
+    fn process_char(&mut self, c: char) {
+        // Ignore the whitespace between the table tags.
+        if self.table_depth > 0 && self.cell_depth == 0 {
+            return;
+        }
+        if self.skip_next_newline {
+            self.skip_next_newline = false;
+            if c == '\n' {
+                return;
+            }
+        }
+        self.process_plain_char(c);
+    }
+
+    fn process_plain_char(&mut self, c: char) {
         match &mut self.state {
             ProcessState::NotProcessed => {
                 self.state = ProcessState::ProcessingPlainText {
@@ -42,7 +77,7 @@ impl Html2RichTextSink {
         }
     }
 
-    fn process_start_link(&mut self, tag: &Tag) -> () {
+    fn process_start_link(&mut self, tag: &Tag) {
         let mut link_opt: Option<String> = None;
         for attr in &tag.attrs {
             match attr.name.local.to_string().as_str() {
@@ -77,13 +112,42 @@ impl Html2RichTextSink {
         }
     }
 
-    fn process_start_tag(&mut self, tag: &Tag) -> () {
+    fn process_start_tag(&mut self, tag: &Tag) {
         match tag.name.to_string().as_str() {
             "br" => {
                 self.process_plain_char('\n');
             }
             "a" => {
-                self.process_start_link(&tag);
+                self.process_start_link(tag);
+            }
+            "pre" => {
+                if !self.ends_with_newline() {
+                    self.process_plain_char('\n');
+                }
+                self.pre_depth += 1;
+                // A newline just after <pre> is ignored as in browsers.
+                self.skip_next_newline = true;
+            }
+            "code" if self.options.code_backticks && self.pre_depth == 0 => {
+                self.process_plain_char('`');
+            }
+            "table" => {
+                if !self.ends_with_newline() {
+                    self.process_plain_char('\n');
+                }
+                self.table_depth += 1;
+            }
+            "tr" if self.table_depth > 0 => {
+                self.row_cells = 0;
+            }
+            "td" | "th" if self.table_depth > 0 => {
+                if self.row_cells > 0 {
+                    self.process_plain_char(' ');
+                    self.process_plain_char('|');
+                    self.process_plain_char(' ');
+                }
+                self.row_cells += 1;
+                self.cell_depth += 1;
             }
             _ => {
                 // do nothing
@@ -92,7 +156,7 @@ impl Html2RichTextSink {
         self.tag_depth += 1;
     }
 
-    fn process_eng_tag(&mut self, tag: &Tag) -> () {
+    fn process_eng_tag(&mut self, tag: &Tag) {
         self.tag_depth -= 1;
         match tag.name.to_string().as_str() {
             "a" => {
@@ -101,13 +165,31 @@ impl Html2RichTextSink {
             "p" => {
                 self.process_plain_char('\n');
             }
+            "pre" => {
+                self.pre_depth = self.pre_depth.saturating_sub(1);
+                if !self.ends_with_newline() {
+                    self.process_plain_char('\n');
+                }
+            }
+            "code" if self.options.code_backticks && self.pre_depth == 0 => {
+                self.process_plain_char('`');
+            }
+            "table" => {
+                self.table_depth = self.table_depth.saturating_sub(1);
+            }
+            "tr" if self.table_depth > 0 && self.row_cells > 0 => {
+                self.process_plain_char('\n');
+            }
+            "td" | "th" if self.table_depth > 0 => {
+                self.cell_depth = self.cell_depth.saturating_sub(1);
+            }
             _ => {
                 // do nothing
             }
         }
     }
 
-    fn end_process(&mut self) -> () {
+    fn end_process(&mut self) {
         match &self.state {
             ProcessState::NotProcessed => {
                 // do nothing
@@ -134,14 +216,14 @@ impl Html2RichTextSink {
     }
 }
 
-impl TokenSink for Html2RichTextSink {
+impl<'a> TokenSink for Html2RichTextSink<'a> {
     type Handle = ();
 
     fn process_token(&mut self, token: Token, _line_number: u64) -> TokenSinkResult<Self::Handle> {
         match token {
             Token::CharacterTokens(bs) => {
                 for c in bs.chars() {
-                    self.process_plain_char(c);
+                    self.process_char(c);
                 }
             }
             Token::NullCharacterToken => {
@@ -172,13 +254,20 @@ impl TokenSink for Html2RichTextSink {
     }
 }
 
-pub fn from_html(content: &str) -> Result<RichText, Box<dyn Error>> {
+pub fn from_html(content: &str, options: &HtmlOptions) -> Result<RichText, Box<dyn Error>> {
     let mut tokenizer = Tokenizer::new(
         Html2RichTextSink {
             text: vec![],
             tag_depth: 0,
+            pre_depth: 0,
+            table_depth: 0,
+            cell_depth: 0,
+            row_cells: 0,
+
+            skip_next_newline: false,
             state: ProcessState::NotProcessed,
             err: None,
+            options,
         },
         Default::default(),
     );
@@ -0,0 +1,94 @@
+use pulldown_cmark::{Event, Parser, Tag};
+
+use crate::richtext::{HtmlOptions, RichText, RichTextSegment};
+
+struct Markdown2RichTextBuilder {
+    text: RichText,
+    text_continue: String,
+    link_opt: Option<String>,
+}
+
+impl Markdown2RichTextBuilder {
+    fn ends_with_newline(&self) -> bool {
+        if !self.text_continue.is_empty() {
+            return self.text_continue.ends_with('\n');
+        }
+        match self.text.last() {
+            Some(seg) => seg.text().ends_with('\n'),
+            None => true,
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        self.text_continue.push_str(s);
+    }
+
+    fn push_newline_if_needed(&mut self) {
+        if !self.ends_with_newline() {
+            self.push_str("\n");
+        }
+    }
+
+    fn start_link(&mut self, link: &str) {
+        self.end_segment();
+        self.link_opt = Some(link.to_string());
+    }
+
+    fn end_segment(&mut self) {
+        if self.text_continue.is_empty() {
+            self.link_opt = None;
+            return;
+        }
+        let text = std::mem::take(&mut self.text_continue);
+        match self.link_opt.take() {
+            Some(link) => self.text.push(RichTextSegment::Link { text, link }),
+            None => self.text.push(RichTextSegment::PlainText { text }),
+        }
+    }
+}
+
+pub fn from_markdown(content: &str, options: &HtmlOptions) -> RichText {
+    let mut builder = Markdown2RichTextBuilder {
+        text: vec![],
+        text_continue: String::from(""),
+        link_opt: None,
+    };
+
+    for event in Parser::new(content) {
+        match event {
+            Event::Start(Tag::Link(_, link, _)) => {
+                builder.start_link(&link);
+            }
+            Event::End(Tag::Link(..)) => {
+                builder.end_segment();
+            }
+            Event::Start(Tag::Item) => {
+                builder.push_newline_if_needed();
+                builder.push_str("- ");
+            }
+            Event::Start(Tag::CodeBlock(_)) => {
+                builder.push_newline_if_needed();
+            }
+            Event::End(Tag::Paragraph | Tag::Heading(..) | Tag::Item | Tag::CodeBlock(_)) => {
+                builder.push_newline_if_needed();
+            }
+            Event::Text(text) => {
+                builder.push_str(&text);
+            }
+            Event::Code(code) if options.code_backticks => {
+                builder.push_str(&format!("`{code}`"));
+            }
+            Event::Code(code) => {
+                builder.push_str(&code);
+            }
+            Event::SoftBreak | Event::HardBreak => {
+                builder.push_str("\n");
+            }
+            _ => {
+                // do nothing
+            }
+        }
+    }
+    builder.end_segment();
+    builder.text
+}
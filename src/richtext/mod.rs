@@ -2,13 +2,152 @@ use std::error::Error;
 
 pub type RichText = Vec<RichTextSegment>;
 
+#[derive(Clone, Debug)]
 pub enum RichTextSegment {
     PlainText { text: String },
     Link { text: String, link: String },
+    Mention { text: String, did: String },
+}
+
+impl RichTextSegment {
+    pub fn text(&self) -> &str {
+        match self {
+            RichTextSegment::PlainText { text } => text,
+            RichTextSegment::Link { text, .. } => text,
+            RichTextSegment::Mention { text, .. } => text,
+        }
+    }
+
+    pub fn text_mut(&mut self) -> &mut String {
+        match self {
+            RichTextSegment::PlainText { text } => text,
+            RichTextSegment::Link { text, .. } => text,
+            RichTextSegment::Mention { text, .. } => text,
+        }
+    }
+
+    pub fn is_same_link(&self, other: &RichTextSegment) -> bool {
+        match (self, other) {
+            (RichTextSegment::Link { link, .. }, RichTextSegment::Link { link: other, .. }) => {
+                link == other
+            }
+            _ => false,
+        }
+    }
+
+    pub fn with_text(&self, text: String) -> Self {
+        match self {
+            RichTextSegment::PlainText { .. } => RichTextSegment::PlainText { text },
+            RichTextSegment::Link { link, .. } => RichTextSegment::Link {
+                text,
+                link: link.to_string(),
+            },
+            RichTextSegment::Mention { did, .. } => RichTextSegment::Mention {
+                text,
+                did: did.to_string(),
+            },
+        }
+    }
+}
+
+pub fn to_plain_text(text: &RichText) -> String {
+    text.iter().map(|seg| seg.text()).collect()
+}
+
+/// Remove trailing whitespace, dropping segments which become empty.
+pub fn trim_end(text: &mut RichText) {
+    while let Some(last) = text.last_mut() {
+        let seg_text = last.text_mut();
+        seg_text.truncate(seg_text.trim_end().len());
+        if seg_text.is_empty() {
+            text.pop();
+        } else {
+            break;
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct HtmlOptions {
+    /// Wrap inline `<code>` elements in backticks
+    pub code_backticks: bool,
+    /// Collapse runs of whitespace other than newlines into a space
+    pub collapse_whitespace: bool,
+    /// Remove whitespace at the start and the end of lines
+    pub trim_lines: bool,
+    /// Maximum number of consecutive blank lines
+    pub max_blank_lines_opt: Option<usize>,
+}
+
+impl HtmlOptions {
+    fn normalizes_whitespace(&self) -> bool {
+        self.collapse_whitespace || self.trim_lines || self.max_blank_lines_opt.is_some()
+    }
 }
 
 mod from_html_impl;
 
-pub fn from_html(content: &str) -> Result<RichText, Box<dyn Error>> {
-    from_html_impl::from_html(content)
+pub fn from_html(content: &str, options: &HtmlOptions) -> Result<RichText, Box<dyn Error>> {
+    from_html_impl::from_html(content, options)
+}
+
+mod from_markdown_impl;
+
+pub fn from_markdown(content: &str, options: &HtmlOptions) -> RichText {
+    from_markdown_impl::from_markdown(content, options)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ContentFormat {
+    /// HTML as Mastodon renders
+    Html,
+    /// CommonMark
+    Markdown,
+    /// Text without any markup
+    Plain,
+}
+
+/// Convert the content of the given format into a rich text, normalizing the
+/// whitespace as configured and linking the bare URLs in the text.
+pub fn from_content(
+    content: &str,
+    format: ContentFormat,
+    options: &HtmlOptions,
+) -> Result<RichText, Box<dyn Error>> {
+    let text = match format {
+        ContentFormat::Html => from_html(content, options)?,
+        ContentFormat::Markdown => from_markdown(content, options),
+        ContentFormat::Plain => vec![RichTextSegment::PlainText {
+            text: content.to_string(),
+        }],
+    };
+    let text = if options.normalizes_whitespace() {
+        normalize_whitespace(text, options)
+    } else {
+        text
+    };
+    Ok(autolink(text))
+}
+
+mod normalize_impl;
+
+/// Normalize the whitespace across segments as configured in the options.
+pub fn normalize_whitespace(text: RichText, options: &HtmlOptions) -> RichText {
+    normalize_impl::normalize_whitespace(text, options)
+}
+
+mod autolink_impl;
+
+/// Turn the http(s) URLs in the plain text segments into links.
+pub fn autolink(text: RichText) -> RichText {
+    autolink_impl::autolink(text)
+}
+
+mod chunk_impl;
+pub use chunk_impl::ChunkBudget;
+
+/// Split the rich text into chunks within the budget, never breaking a grapheme
+/// cluster or a link which fits in a chunk, and preferring paragraph breaks.
+pub fn chunk_richtext(text: &RichText, budget: ChunkBudget) -> Vec<RichText> {
+    chunk_impl::chunk_richtext(text, budget)
 }
@@ -0,0 +1,95 @@
+use crate::richtext::{HtmlOptions, RichText, RichTextSegment};
+
+struct WhitespaceNormalizer<'a> {
+    options: &'a HtmlOptions,
+    text: RichText,
+    /// Whether the segment being normalized is a plain text
+    in_plain_text: bool,
+    /// Whitespace other than newlines, held until the next character
+    pending_spaces: String,
+    at_line_start: bool,
+    /// Newlines since the last non-blank line
+    newline_run: usize,
+}
+
+impl<'a> WhitespaceNormalizer<'a> {
+    /// Emit the pending whitespace, keeping it out of the start of links.
+    fn flush_spaces(&mut self, current: &mut String) {
+        let spaces = std::mem::take(&mut self.pending_spaces);
+        if spaces.is_empty() {
+            return;
+        }
+        let spaces = if self.options.collapse_whitespace {
+            " ".to_string()
+        } else {
+            spaces
+        };
+        if self.in_plain_text || !current.is_empty() {
+            current.push_str(&spaces);
+        } else if let Some(RichTextSegment::PlainText { text }) = self.text.last_mut() {
+            text.push_str(&spaces);
+        } else {
+            self.text.push(RichTextSegment::PlainText { text: spaces });
+        }
+        self.newline_run = 0;
+    }
+
+    fn push_newline(&mut self, current: &mut String) {
+        if self.options.trim_lines {
+            self.pending_spaces.clear();
+        } else {
+            self.flush_spaces(current);
+        }
+        self.newline_run += 1;
+        self.at_line_start = true;
+        if let Some(max_blank_lines) = self.options.max_blank_lines_opt {
+            if self.newline_run > max_blank_lines + 1 {
+                return;
+            }
+        }
+        current.push('\n');
+    }
+
+    fn push_char(&mut self, current: &mut String, c: char) {
+        if self.at_line_start && self.options.trim_lines {
+            self.pending_spaces.clear();
+        } else {
+            self.flush_spaces(current);
+        }
+        current.push(c);
+        self.newline_run = 0;
+        self.at_line_start = false;
+    }
+}
+
+pub fn normalize_whitespace(text: RichText, options: &HtmlOptions) -> RichText {
+    let mut normalizer = WhitespaceNormalizer {
+        options,
+        text: vec![],
+        in_plain_text: true,
+        pending_spaces: String::from(""),
+        at_line_start: true,
+        newline_run: 0,
+    };
+
+    for seg in text {
+        normalizer.in_plain_text = matches!(seg, RichTextSegment::PlainText { .. });
+        let mut current = String::from("");
+        for c in seg.text().chars() {
+            match c {
+                '\n' => normalizer.push_newline(&mut current),
+                c if c.is_whitespace() => normalizer.pending_spaces.push(c),
+                c => normalizer.push_char(&mut current, c),
+            }
+        }
+        if !current.is_empty() {
+            normalizer.text.push(seg.with_text(current));
+        }
+    }
+    if !options.trim_lines {
+        let mut current = String::from("");
+        normalizer.in_plain_text = false;
+        normalizer.flush_spaces(&mut current);
+    }
+    normalizer.text
+}
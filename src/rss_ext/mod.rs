@@ -1,43 +1,141 @@
+use rss::extension::Extension;
+
+use crate::logging::Event;
+
 #[derive(Debug)]
 pub struct Media {
     pub url: String,
     pub file_size: usize,
     pub typ: String,
     pub rating: Rating,
+    /// The alt text in `media:description`
+    pub description: Option<String>,
+    /// The length of videos in seconds
+    pub duration_opt: Option<f64>,
+    /// The URL in `media:thumbnail`, a smaller image of the media
+    pub thumbnail_url_opt: Option<String>,
+}
+
+impl Media {
+    pub fn is_image(&self) -> bool {
+        self.typ.starts_with("image/")
+    }
+
+    pub fn is_video(&self) -> bool {
+        self.typ.starts_with("video/")
+    }
+
+    pub fn is_audio(&self) -> bool {
+        self.typ.starts_with("audio/")
+    }
 }
 
 #[derive(Debug)]
 pub enum Rating {
     NonAdult,
+    Adult,
+
     Other,
 }
 
-pub fn get_media(item: &rss::Item) -> Option<Media> {
-    let media_content = {
-        let media_opt = item
-            .extensions
-            .get("media")
-            .and_then(|x| x.get("content"))
-            .and_then(|x| x.get(0));
-        match media_opt {
-            Some(x) => x,
-            None => return None,
+/// All the media of the item, the `media:content` and `media:group` elements
+/// followed by the enclosure, or the `media:thumbnail` of the item when it has
+/// none of them.
+pub fn get_medias(item: &rss::Item) -> Vec<Media> {
+    let media_ext = item.extensions.get("media");
+    let mut medias: Vec<Media> = media_ext
+        .and_then(|x| x.get("content"))
+        .map(|contents| {
+            contents
+                .iter()
+                .filter_map(|content| parse_media_content(content, None))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    for group in media_ext.and_then(|x| x.get("group")).into_iter().flatten() {
+        if let Some(media) = parse_media_group(group) {
+            if medias.iter().all(|x| x.url != media.url) {
+                medias.push(media);
+            }
+        }
+    }
+
+    if let Some(enclosure) = &item.enclosure {
+        if medias.iter().all(|media| media.url != enclosure.url) {
+            medias.push(Media {
+                url: enclosure.url.to_string(),
+                file_size: enclosure.length.parse().unwrap_or(0),
+                typ: enclosure.mime_type.to_string(),
+                // Enclosures have no rating.
+                rating: Rating::NonAdult,
+                description: None,
+                duration_opt: None,
+                thumbnail_url_opt: None,
+            });
         }
-    };
+    }
 
+    if medias.is_empty() {
+        let thumbnail_url_opt = media_ext
+            .and_then(|x| x.get("thumbnail"))
+            .and_then(|x| x.first())
+            .and_then(|x| x.attrs.get("url"));
+        if let Some(thumbnail_url) = thumbnail_url_opt {
+            medias.push(Media {
+                url: thumbnail_url.to_string(),
+                // Thumbnails have no size and no type, but are images.
+                file_size: 0,
+                typ: String::from("image/*"),
+                rating: Rating::NonAdult,
+                description: None,
+                duration_opt: None,
+                thumbnail_url_opt: None,
+            });
+        }
+    }
+
+    medias
+}
+
+/// The contents of a group are alternatives of the same media, so the default
+/// one or the first one is taken.
+fn parse_media_group(media_group: &Extension) -> Option<Media> {
+    let contents = media_group.children.get("content")?;
+    let content = contents
+        .iter()
+        .find(|x| x.attrs.get("isDefault").map(String::as_str) == Some("true"))
+        .or_else(|| contents.first())?;
+    parse_media_content(content, Some(media_group))
+}
+
+/// The child element of the media content, or of the enclosing group which
+/// gives the default of its contents.
+fn media_child<'a>(
+    media_content: &'a Extension,
+    group_opt: Option<&'a Extension>,
+    name: &str,
+) -> Option<&'a Extension> {
+    media_content
+        .children
+        .get(name)
+        .or_else(|| group_opt.and_then(|group| group.children.get(name)))
+        .and_then(|x| x.first())
+}
+
+fn parse_media_content(media_content: &Extension, group_opt: Option<&Extension>) -> Option<Media> {
     let file_size = match media_content.attrs.get("fileSize") {
         Some(x) => match x.parse() {
             Ok(parsed) => parsed,
             Err(err) => {
-                eprintln!(
-                    "Failed to parse the 'fileSize' attribute of the media content: {}",
-                    err
-                );
+                Event::warn("Failed to parse the 'fileSize' attribute of the media content")
+                    .error(err)
+                    .emit();
                 return None;
             }
         },
         None => {
-            eprintln!("Not found the 'fileSize' attribute of the media content.");
+            Event::warn("Not found the 'fileSize' attribute of the media content.").emit();
             return None;
         }
     };
@@ -45,7 +143,7 @@ pub fn get_media(item: &rss::Item) -> Option<Media> {
     let typ = match media_content.attrs.get("type") {
         Some(x) => x,
         None => {
-            eprintln!("Not found the 'type' attribute of the media content.");
+            Event::warn("Not found the 'type' attribute of the media content.").emit();
             return None;
         }
     };
@@ -53,15 +151,15 @@ pub fn get_media(item: &rss::Item) -> Option<Media> {
     let url = match media_content.attrs.get("url") {
         Some(x) => x,
         None => {
-            eprintln!("Not found the 'url' attribute of the media content.");
+            Event::warn("Not found the 'url' attribute of the media content.").emit();
             return None;
         }
     };
 
-    let rating_ext = match media_content.children.get("rating").and_then(|x| x.get(0)) {
+    let rating_ext = match media_child(media_content, group_opt, "rating") {
         Some(x) => x,
         None => {
-            eprintln!("Not found the 'rating' content of the media content.");
+            Event::warn("Not found the 'rating' content of the media content.").emit();
             return None;
         }
     };
@@ -69,21 +167,40 @@ pub fn get_media(item: &rss::Item) -> Option<Media> {
     let rating = match &rating_ext.value {
         Some(x) => match x.as_str() {
             "nonadult" => Rating::NonAdult,
+            "adult" => Rating::Adult,
             other => {
-                eprintln!("Failed to parse the rating {}", other);
+                Event::warn(format!("Failed to parse the rating {other}")).emit();
                 Rating::Other
             }
         },
         None => {
-            eprintln!("Not found the 'value' of the media rating content.");
+            Event::warn("Not found the 'value' of the media rating content.").emit();
             return None;
         }
     };
 
+    let description = media_child(media_content, group_opt, "description")
+        .and_then(|x| x.value.as_deref())
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(str::to_string);
+
+    let duration_opt = media_content
+        .attrs
+        .get("duration")
+        .and_then(|x| x.parse().ok());
+
+    let thumbnail_url_opt = media_child(media_content, group_opt, "thumbnail")
+        .and_then(|x| x.attrs.get("url"))
+        .map(|x| x.to_string());
+
     Some(Media {
         url: url.to_string(),
         typ: typ.to_string(),
         file_size,
         rating,
+        description,
+        duration_opt,
+        thumbnail_url_opt,
     })
 }
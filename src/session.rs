@@ -0,0 +1,205 @@
+use base64::Engine;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+
+use crate::logging::Event;
+use crate::oauth::OAuthSession;
+
+/// The time before the expiry when a token is not used any more.
+const EXPIRY_MARGIN_MINUTES: i64 = 5;
+
+/// The session of the Bluesky account, kept across runs so that the password
+/// is only sent when the session cannot be refreshed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedSession {
+    /// The XRPC host which issued the session
+    pub host: String,
+    /// The identifier which the session is created by
+    pub identifier: String,
+    pub did: String,
+    pub access_jwt: String,
+    pub refresh_jwt: String,
+    /// The OAuth session which the tokens are issued by, instead of the
+    /// password
+    #[serde(default, rename = "oauth", skip_serializing_if = "Option::is_none")]
+    pub oauth_opt: Option<OAuthSession>,
+}
+
+impl SavedSession {
+    /// Whether the access token is usable for a while.
+    pub fn access_usable(&self) -> bool {
+        jwt_usable(&self.access_jwt)
+    }
+
+    /// Whether the refresh token is usable for a while.
+    pub fn refresh_usable(&self) -> bool {
+        jwt_usable(&self.refresh_jwt)
+    }
+}
+
+/// Whether the JWT expires after the margin, by its `exp` claim. Tokens
+/// whose expiry is unknown are tried as usable.
+fn jwt_usable(jwt: &str) -> bool {
+    match jwt_expiry(jwt) {
+        Some(expiry) => Utc::now() + Duration::minutes(EXPIRY_MARGIN_MINUTES) < expiry,
+        None => true,
+    }
+}
+
+fn jwt_expiry(jwt: &str) -> Option<DateTime<Utc>> {
+    #[derive(Deserialize)]
+    struct Claims {
+        exp: i64,
+    }
+
+    let payload = jwt.split('.').nth(1)?;
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    let claims: Claims = serde_json::from_slice(&payload).ok()?;
+    Utc.timestamp_opt(claims.exp, 0).single()
+}
+
+/// The sessions saved at the path, one per line. A missing or unreadable
+/// file has no sessions, as new sessions are created then.
+fn load_sessions(path: &str) -> Vec<SavedSession> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return vec![],
+        Err(err) => {
+            Event::warn("Failed to read session").error(err).emit();
+            return vec![];
+        }
+    };
+    content
+        .lines()
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(session) => Some(session),
+            Err(err) => {
+                Event::warn("Failed to parse session").error(err).emit();
+                None
+            }
+        })
+        .collect()
+}
+
+/// Load the session of the account saved at the path, which may be shared by
+/// the runs of several feeds and accounts.
+pub fn load_session(path: &str, host: &str, identifier: &str) -> Option<SavedSession> {
+    load_sessions(path)
+        .into_iter()
+        .find(|session| session.host == host && session.identifier == identifier)
+}
+
+/// Lock the sessions at the path by an exclusive lock on the lock file beside
+/// it, which is held until the returned file is dropped. The runs sharing the
+/// sessions wait for each other, so that no run drops the session saved by
+/// another between its load and its write.
+fn lock_sessions(path: &str) -> Result<File, Box<dyn Error>> {
+    let lock_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(format!("{path}.lock"))
+        .map_err(|err| format!("Failed to open session lock: {err}"))?;
+    lock_file
+        .lock()
+        .map_err(|err| format!("Failed to lock session: {err}"))?;
+    Ok(lock_file)
+}
+
+/// Save the session to the path in place of the session of the same account.
+pub fn save_session(path: &str, session: &SavedSession) -> Result<(), Box<dyn Error>> {
+    let _lock = lock_sessions(path)?;
+    let mut sessions = load_sessions(path);
+    sessions.retain(|saved| saved.host != session.host || saved.identifier != session.identifier);
+    sessions.push(session.clone());
+    write_sessions(path, &sessions)
+}
+
+/// Remove the session of the account from the path, if any.
+pub fn remove_session(path: &str, host: &str, identifier: &str) -> Result<(), Box<dyn Error>> {
+    let _lock = lock_sessions(path)?;
+    let mut sessions = load_sessions(path);
+    sessions.retain(|saved| saved.host != host || saved.identifier != identifier);
+    write_sessions(path, &sessions)
+}
+
+/// Replace the sessions at the path through a temporary file which only the
+/// owner can read, as the tokens are secrets. The sessions are locked by the
+/// caller.
+fn write_sessions(path: &str, sessions: &[SavedSession]) -> Result<(), Box<dyn Error>> {
+    // The runs sharing the file do not write the same temporary file.
+    let tmp_path = format!("{path}.{}.tmp", std::process::id());
+    let mut options = OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    {
+        let mut tmp_file = options
+            .open(&tmp_path)
+            .map_err(|err| format!("Failed to open session: {err}"))?;
+        // The mode is only applied on creation.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            tmp_file
+                .set_permissions(std::fs::Permissions::from_mode(0o600))
+                .map_err(|err| format!("Failed to write session: {err}"))?;
+        }
+        for session in sessions {
+            writeln!(tmp_file, "{}", serde_json::to_string(session)?)
+                .map_err(|err| format!("Failed to write session: {err}"))?;
+        }
+        tmp_file
+            .sync_all()
+            .map_err(|err| format!("Failed to sync session: {err}"))?;
+    }
+    std::fs::rename(&tmp_path, path).map_err(|err| format!("Failed to replace session: {err}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_saves_keep_every_account() {
+        let path = std::env::temp_dir()
+            .join(format!("sessions-test-{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let _ = std::fs::remove_file(&path);
+
+        let threads: Vec<_> = (0..8)
+            .map(|index| {
+                let path = path.to_string();
+                std::thread::spawn(move || {
+                    let session = SavedSession {
+                        host: String::from("https://bsky.social"),
+                        identifier: format!("user{index}.example.com"),
+                        did: format!("did:plc:user{index}"),
+                        access_jwt: String::from("access"),
+                        refresh_jwt: String::from("refresh"),
+                        oauth_opt: None,
+                    };
+                    for _ in 0..20 {
+                        save_session(&path, &session).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(load_sessions(&path).len(), 8);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{path}.lock"));
+    }
+}
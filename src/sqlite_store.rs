@@ -0,0 +1,411 @@
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::state_store::{
+    CachedBlob, FsyncPolicy, PostedRecord, StateStore, BLOB_CACHE_SIZE,
+    CREATED_RECORDS_RETENTION_HOURS,
+};
+
+/// The tables of the store, created when missing.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS links (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    namespace TEXT NOT NULL,
+    link TEXT NOT NULL,
+    record TEXT,
+    UNIQUE (namespace, link)
+);
+CREATE TABLE IF NOT EXISTS blobs (
+    key TEXT PRIMARY KEY,
+    blob TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS created_records (
+    account TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    count INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS created_records_account ON created_records (account, created_at);
+";
+
+/// The path of the SQLite database beside the DB files, which `migrate-db`
+/// imports them into.
+pub fn sqlite_path(db_path: &str) -> String {
+    format!("{db_path}.sqlite")
+}
+
+/// A store in a SQLite database at `<path>.sqlite`.
+///
+/// The links are rows by their post order, with their posted records as JSON,
+/// and the links in a namespace are rows of the namespace. As in the DB files,
+/// the links without a namespace are seen from every namespace, and the store
+/// without a namespace sees the other links prefixed by their namespace and a
+/// space. The links and the records are loaded on open and written through as
+/// they change, each in its own transaction. The uploaded blobs are cached by
+/// the key, keeping the newest ones.
+///
+/// The compaction drops the links as in the DB files, beyond the retention
+/// days when given, or beyond the last `min_save_posts` links of their
+/// namespace otherwise or when their post time is unknown.
+pub struct SqliteStateStore {
+    connection: Connection,
+    /// The namespace of the store, which is empty without one
+    namespace: String,
+    fsync_policy: FsyncPolicy,
+    min_save_posts: usize,
+    retention_days_opt: Option<u32>,
+    links: Vec<String>,
+    /// The namespace and the link of the row of each link seen, preferring the
+    /// own namespace
+    rows: HashMap<String, (String, String)>,
+    records: HashMap<String, PostedRecord>,
+    blobs: HashMap<String, CachedBlob>,
+}
+
+impl SqliteStateStore {
+    /// Open the database, creating it if it does not exist, and load the
+    /// links in the namespace if any.
+    pub fn open(
+        path: &str,
+        namespace_opt: Option<&str>,
+        fsync_policy: FsyncPolicy,
+        min_save_posts: usize,
+        retention_days_opt: Option<u32>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let connection =
+            Connection::open(path).map_err(|err| format!("Failed to open DB {path}: {err}"))?;
+        // The WAL is synced on each commit only for the per-post policy, and
+        // by the checkpoint on commit of the store otherwise.
+        let synchronous = match fsync_policy {
+            FsyncPolicy::PerPost => "FULL",
+            FsyncPolicy::PerRun => "NORMAL",
+        };
+        connection
+            .execute_batch(&format!(
+                "PRAGMA journal_mode = WAL; PRAGMA synchronous = {synchronous}; {SCHEMA}"
+            ))
+            .map_err(|err| format!("Failed to open DB {path}: {err}"))?;
+
+        let mut state_store = SqliteStateStore {
+            connection,
+            namespace: namespace_opt.unwrap_or_default().to_string(),
+            fsync_policy,
+            min_save_posts,
+            retention_days_opt,
+            links: vec![],
+            rows: HashMap::new(),
+            records: HashMap::new(),
+            blobs: HashMap::new(),
+        };
+        state_store
+            .load()
+            .map_err(|err| format!("Failed to load DB {path}: {err}"))?;
+        Ok(state_store)
+    }
+
+    fn load(&mut self) -> Result<(), rusqlite::Error> {
+        self.links.clear();
+        self.rows.clear();
+        self.records.clear();
+        self.blobs.clear();
+
+        let mut statement = self.connection.prepare(
+            "SELECT namespace, link, record FROM links WHERE ?1 = '' OR namespace IN (?1, '') ORDER BY id",
+        )?;
+        let rows = statement.query_map([&self.namespace], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })?;
+        for row in rows {
+            let (namespace, link, record_json_opt) = row?;
+            let key = match namespace.as_str() {
+                namespace if namespace == self.namespace || namespace.is_empty() => {
+                    link.to_string()
+                }
+                namespace => format!("{namespace} {link}"),
+            };
+            match self.rows.get(&key) {
+                // The row in the own namespace takes precedence.
+                Some((existing, _)) if *existing == self.namespace => continue,
+                Some(_) => {}
+                None => self.links.push(key.to_string()),
+            }
+            let record_opt = record_json_opt
+                .and_then(|record_json| serde_json::from_str::<PostedRecord>(&record_json).ok());
+            match record_opt {
+                Some(record) => self.records.insert(key.to_string(), record),
+                None => self.records.remove(&key),
+            };
+            self.rows.insert(key, (namespace, link));
+        }
+        drop(statement);
+
+        let mut statement = self
+            .connection
+            .prepare("SELECT key, blob FROM blobs ORDER BY rowid")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (key, blob_json) = row?;
+            // An unreadable blob is only a cache miss.
+            if let Ok(blob) = serde_json::from_str::<CachedBlob>(&blob_json) {
+                self.blobs.insert(key, blob);
+            }
+        }
+        Ok(())
+    }
+
+    /// The namespace and the link of the row of the link, which may have no
+    /// namespace.
+    fn existing_row_of(&self, link: &str) -> (String, String) {
+        self.rows
+            .get(link)
+            .cloned()
+            .unwrap_or_else(|| (self.namespace.to_string(), link.to_string()))
+    }
+
+    /// The IDs of the links beyond the retention, of which the links are
+    /// counted per namespace as in the DB files.
+    fn expired_ids(&self) -> Result<Vec<i64>, rusqlite::Error> {
+        let cutoff_opt = self
+            .retention_days_opt
+            .map(|days| Utc::now() - Duration::days(i64::from(days)));
+        let mut statement = self
+            .connection
+            .prepare("SELECT id, namespace, record FROM links ORDER BY id")?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for (_, namespace, _) in &rows {
+            *counts.entry(namespace).or_default() += 1;
+        }
+        let mut indices: HashMap<&str, usize> = HashMap::new();
+        let mut expired = vec![];
+        for (id, namespace, record_json_opt) in &rows {
+            let posted_at_opt = record_json_opt
+                .as_deref()
+                .and_then(|record_json| serde_json::from_str::<PostedRecord>(record_json).ok())
+                .and_then(|record| record.posted_at_opt);
+            let by_count_from = counts[namespace.as_str()].saturating_sub(self.min_save_posts);
+            let index = indices.entry(namespace).or_default();
+            let in_count = *index >= by_count_from;
+            *index += 1;
+            let retained = match (cutoff_opt, posted_at_opt) {
+                (Some(cutoff), Some(posted_at)) => posted_at >= cutoff,
+                _ => in_count,
+            };
+            if !retained {
+                expired.push(*id);
+            }
+        }
+        Ok(expired)
+    }
+}
+
+impl StateStore for SqliteStateStore {
+    fn contains(&self, link: &str) -> bool {
+        self.rows.contains_key(link)
+    }
+
+    fn links(&self) -> Vec<String> {
+        self.links.clone()
+    }
+
+    fn remove(&mut self, link: &str) -> Result<(), Box<dyn Error>> {
+        // The rows in the own namespace and without one are removed as in the
+        // DB files.
+        let (namespace, row_link) = self.existing_row_of(link);
+        self.connection
+            .execute(
+                "DELETE FROM links WHERE (namespace IN (?1, '') AND link = ?2) OR (namespace = ?3 AND link = ?4)",
+                params![self.namespace, link, namespace, row_link],
+            )
+            .map_err(|err| format!("Failed to remove link from DB: {err}"))?;
+        self.links.retain(|retained| retained != link);
+        self.rows.remove(link);
+        self.records.remove(link);
+        Ok(())
+    }
+
+    fn record_posted(
+        &mut self,
+        link: &str,
+        record_opt: Option<&PostedRecord>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.connection
+            .execute(
+                "INSERT OR IGNORE INTO links (namespace, link) VALUES (?1, ?2)",
+                params![self.namespace, link],
+            )
+            .map_err(|err| format!("Failed to write DB: {err}"))?;
+        if !self.rows.contains_key(link) {
+            self.links.push(link.to_string());
+        }
+        self.rows.insert(
+            link.to_string(),
+            (self.namespace.to_string(), link.to_string()),
+        );
+
+        match record_opt {
+            Some(record) => self.update_record(link, record),
+            None => Ok(()),
+        }
+    }
+
+    fn posted_record(&self, link: &str) -> Option<PostedRecord> {
+        self.records.get(link).cloned()
+    }
+
+    fn posted_records(&self) -> Vec<(String, PostedRecord)> {
+        self.records
+            .iter()
+            .map(|(link, record)| (link.to_string(), record.clone()))
+            .collect()
+    }
+
+    fn forget_record(&mut self, link: &str) -> Result<(), Box<dyn Error>> {
+        let (namespace, row_link) = self.existing_row_of(link);
+        self.connection
+            .execute(
+                "UPDATE links SET record = NULL WHERE namespace = ?1 AND link = ?2",
+                params![namespace, row_link],
+            )
+            .map_err(|err| format!("Failed to forget posted record: {err}"))?;
+        self.records.remove(link);
+        Ok(())
+    }
+
+    fn update_record(&mut self, link: &str, record: &PostedRecord) -> Result<(), Box<dyn Error>> {
+        let record_json = serde_json::to_string(record)?;
+        let (namespace, row_link) = self.existing_row_of(link);
+        self.connection
+            .execute(
+                "UPDATE links SET record = ?1 WHERE namespace = ?2 AND link = ?3",
+                params![record_json, namespace, row_link],
+            )
+            .map_err(|err| format!("Failed to write posted records: {err}"))?;
+        self.records.insert(link.to_string(), record.clone());
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), Box<dyn Error>> {
+        // Each change is committed already, and synced with the per-post policy.
+        if self.fsync_policy == FsyncPolicy::PerRun {
+            self.connection
+                .query_row("PRAGMA wal_checkpoint(FULL)", [], |_| Ok(()))
+                .map_err(|err| format!("Failed to sync DB: {err}"))?;
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<(), Box<dyn Error>> {
+        let expired = self
+            .expired_ids()
+            .map_err(|err| format!("Failed to compact DB: {err}"))?;
+        let transaction = self
+            .connection
+            .transaction()
+            .map_err(|err| format!("Failed to compact DB: {err}"))?;
+        {
+            let mut statement = transaction
+                .prepare("DELETE FROM links WHERE id = ?1")
+                .map_err(|err| format!("Failed to compact DB: {err}"))?;
+            for id in expired {
+                statement
+                    .execute([id])
+                    .map_err(|err| format!("Failed to compact DB: {err}"))?;
+            }
+        }
+        transaction
+            .commit()
+            .map_err(|err| format!("Failed to compact DB: {err}"))?;
+        self.connection
+            .execute_batch("VACUUM")
+            .map_err(|err| format!("Failed to compact DB: {err}"))?;
+        self.load()
+            .map_err(|err| format!("Failed to load DB: {err}"))?;
+        Ok(())
+    }
+
+    fn cached_blob(&self, key: &str) -> Option<CachedBlob> {
+        self.blobs.get(key).cloned()
+    }
+
+    fn cache_blob(&mut self, key: &str, blob: &CachedBlob) -> Result<(), Box<dyn Error>> {
+        let blob_json = serde_json::to_string(blob)?;
+        // The replaced row gets a new rowid, as the newest blob.
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO blobs (key, blob) VALUES (?1, ?2)",
+                params![key, blob_json],
+            )
+            .map_err(|err| format!("Failed to write blob cache: {err}"))?;
+        self.blobs.insert(key.to_string(), blob.clone());
+
+        // The oldest blobs beyond the cache size are dropped.
+        if self.blobs.len() > BLOB_CACHE_SIZE {
+            let dropped_count = self.blobs.len() - BLOB_CACHE_SIZE;
+            let mut statement = self
+                .connection
+                .prepare("DELETE FROM blobs WHERE rowid IN (SELECT rowid FROM blobs ORDER BY rowid LIMIT ?1) RETURNING key")
+                .map_err(|err| format!("Failed to trim blob cache: {err}"))?;
+            let dropped = statement
+                .query_map([dropped_count], |row| row.get::<_, String>(0))
+                .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+                .map_err(|err| format!("Failed to trim blob cache: {err}"))?;
+            for key in dropped {
+                self.blobs.remove(&key);
+            }
+        }
+        Ok(())
+    }
+
+    fn created_records_since(
+        &mut self,
+        account: &str,
+        since: DateTime<Utc>,
+    ) -> Result<usize, Box<dyn Error>> {
+        let count: Option<i64> = self
+            .connection
+            .query_row(
+                "SELECT SUM(count) FROM created_records WHERE account = ?1 AND created_at >= ?2",
+                params![account, since.timestamp_millis()],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|err| format!("Failed to count created records: {err}"))?
+            .flatten();
+        Ok(usize::try_from(count.unwrap_or_default())?)
+    }
+
+    fn record_created(&mut self, account: &str, count: usize) -> Result<(), Box<dyn Error>> {
+        let now = Utc::now();
+        let cutoff = now - Duration::hours(CREATED_RECORDS_RETENTION_HOURS);
+        self.connection
+            .execute(
+                "INSERT INTO created_records (account, created_at, count) VALUES (?1, ?2, ?3)",
+                params![account, now.timestamp_millis(), i64::try_from(count)?],
+            )
+            .and_then(|_| {
+                self.connection.execute(
+                    "DELETE FROM created_records WHERE created_at < ?1",
+                    [cutoff.timestamp_millis()],
+                )
+            })
+            .map_err(|err| format!("Failed to write created records: {err}"))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1231 @@
+use atrium_api::blob::BlobRef;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// The number of blobs kept in the cache.
+pub(crate) const BLOB_CACHE_SIZE: usize = 1000;
+
+/// How long the created records are kept for the daily budget.
+pub const CREATED_RECORDS_RETENTION_HOURS: i64 = 24;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum FsyncPolicy {
+    /// Sync the DB to the disk after each posted item
+    PerPost,
+    /// Sync the DB to the disk only when the run finishes
+    PerRun,
+}
+
+/// What to do with the posted items whose content is edited on Mastodon.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum EditPolicy {
+    /// Keep the post as is
+    Ignore,
+    /// Replace the post in place, or repost it when it needs a thread
+    Update,
+    /// Delete the post and post the item again
+    Repost,
+}
+
+/// Where the posted links are stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DbBackend {
+    /// Plain files at the DB path
+    File,
+    /// A Redis server, for deployments without a persistent disk
+    Redis,
+    /// Files at the DB path, pulled from and pushed to an S3-compatible storage
+    S3,
+    /// A SQLite database beside the DB path, which `migrate-db` imports the files into
+    Sqlite,
+}
+
+/// What the links are namespaced by, when several feeds or accounts share a DB.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DbNamespace {
+    /// The URL of the feed
+    Feed,
+    /// The Bluesky account
+    Account,
+}
+
+/// The store of the links already posted to Bluesky.
+pub trait StateStore {
+    /// Whether the link has been posted already.
+    fn contains(&self, link: &str) -> bool;
+
+    /// The key of the link in the store, which is the canonical link unless
+    /// the link is stored as is before links are canonicalized.
+    fn link_key(&self, link: &str) -> String {
+        if self.contains(link) {
+            link.to_string()
+        } else {
+            canonical_link(link)
+        }
+    }
+
+    /// The retained links from the oldest.
+    fn links(&self) -> Vec<String>;
+
+    /// Forget the link and its Bluesky record, so that the item is posted again.
+    fn remove(&mut self, link: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Record the link as posted, as the Bluesky record if it is known.
+    fn record_posted(
+        &mut self,
+        link: &str,
+        record_opt: Option<&PostedRecord>,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// The Bluesky record posted for the link, if it is still retained.
+    fn posted_record(&self, link: &str) -> Option<PostedRecord>;
+
+    /// All the retained links with their Bluesky records.
+    fn posted_records(&self) -> Vec<(String, PostedRecord)>;
+
+    /// Forget the Bluesky record of the link, which is deleted, keeping the
+    /// link as posted.
+    fn forget_record(&mut self, link: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Replace the Bluesky record of the link posted already.
+    fn update_record(&mut self, link: &str, record: &PostedRecord) -> Result<(), Box<dyn Error>>;
+
+    /// Make the changes of the store durable.
+    fn commit(&mut self) -> Result<(), Box<dyn Error>>;
+
+    /// Drop the old links beyond the retention and rewrite the store without
+    /// them, which `db compact` runs instead of every run.
+    fn compact(&mut self) -> Result<(), Box<dyn Error>>;
+
+    /// The blob uploaded for the key, such as the source URL or the content hash.
+    fn cached_blob(&self, key: &str) -> Option<CachedBlob>;
+
+    /// Remember the blob uploaded for the key.
+    fn cache_blob(&mut self, key: &str, blob: &CachedBlob) -> Result<(), Box<dyn Error>>;
+
+    /// The number of the records created by the account since the time, which
+    /// the daily budget is counted by.
+    fn created_records_since(
+        &mut self,
+        account: &str,
+        since: DateTime<Utc>,
+    ) -> Result<usize, Box<dyn Error>>;
+
+    /// Count the records created by the account now.
+    fn record_created(&mut self, account: &str, count: usize) -> Result<(), Box<dyn Error>>;
+}
+
+/// The canonical form of the link, so that trivially different forms of the
+/// same link are stored as one. The scheme and the host are lowercased, and
+/// the default port, the fragment and the trailing slash of the path are
+/// removed. Links which fail to parse are kept as is.
+pub fn canonical_link(link: &str) -> String {
+    let mut url = match reqwest::Url::parse(link) {
+        Ok(url) => url,
+        Err(_) => return link.to_string(),
+    };
+    url.set_fragment(None);
+    let path = url.path();
+    if path.len() > 1 && path.ends_with('/') {
+        let path = path.trim_end_matches('/').to_string();
+        url.set_path(&path);
+    }
+    url.to_string()
+}
+
+/// A blob uploaded to Bluesky, which later posts can refer to again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CachedBlob {
+    pub blob: BlobRef,
+    /// The width and the height of images
+    pub dimensions_opt: Option<(u32, u32)>,
+}
+
+/// The Bluesky post of an item, which is the root post of a thread.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PostedRecord {
+    pub uri: String,
+    pub cid: String,
+    /// When the item is posted, which the time-based retention depends on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub posted_at_opt: Option<DateTime<Utc>>,
+    /// The hash of the item content which is posted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash_opt: Option<String>,
+    /// The hash of the normalized text of the item, to find the same content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedup_hash_opt: Option<String>,
+    /// The URL of the feed which the item is in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feed_url_opt: Option<String>,
+    /// The `pubDate` of the item
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pub_date_opt: Option<DateTime<Utc>>,
+    /// Whether the text is truncated to fit in a post
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub truncated_opt: Option<bool>,
+    /// The number of the attached images or videos
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_count_opt: Option<usize>,
+}
+
+/// Records created by an account at a time, which are counted across the
+/// namespaces as the budget is of the account.
+#[derive(Debug, Serialize, Deserialize)]
+struct CreatedRecordsEntry {
+    account: String,
+    created_at: DateTime<Utc>,
+    count: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PostedRecordEntry {
+    link: String,
+    #[serde(flatten)]
+    record: PostedRecord,
+}
+
+/// A posted link with its Bluesky record, as exported.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StateEntry {
+    pub link: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record: Option<PostedRecord>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// A JSON array of the entries
+    Json,
+    /// One JSON entry per line
+    JsonLines,
+    /// CSV rows of the link, the Bluesky URI, the CID and the post time
+    Csv,
+}
+
+/// The header row of the CSV format.
+pub const CSV_HEADER: &str = "original_link,bsky_uri,cid,posted_at";
+
+/// The CSV row of the entry, whose fields are quoted as in RFC 4180 if needed.
+/// The fields of the record are empty for the links without records.
+pub fn csv_row(entry: &StateEntry) -> String {
+    let (uri, cid, posted_at) = match &entry.record {
+        Some(record) => (
+            record.uri.to_string(),
+            record.cid.to_string(),
+            record
+                .posted_at_opt
+                .map(|posted_at| posted_at.to_rfc3339())
+                .unwrap_or_default(),
+        ),
+        None => Default::default(),
+    };
+    [entry.link.as_str(), &uri, &cid, &posted_at]
+        .iter()
+        .map(|field| {
+            if field.contains([',', '"', '\r', '\n']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The fields of the CSV row, unquoting the quoted fields.
+fn csv_fields(row: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = row.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn parse_csv_entry(row: &str) -> Result<StateEntry, Box<dyn Error>> {
+    let fields = csv_fields(row);
+    let [link, uri, cid, posted_at] = <[String; 4]>::try_from(fields)
+        .map_err(|_| format!("Failed to parse the entry {row}: not 4 fields"))?;
+    let posted_at_opt = match posted_at.as_str() {
+        "" => None,
+        posted_at => Some(
+            DateTime::parse_from_rfc3339(posted_at)
+                .map_err(|err| format!("Failed to parse the entry {row}: {err}"))?
+                .with_timezone(&Utc),
+        ),
+    };
+    let record = (!uri.is_empty()).then_some(PostedRecord {
+        uri,
+        cid,
+        posted_at_opt,
+        content_hash_opt: None,
+        dedup_hash_opt: None,
+        feed_url_opt: None,
+        pub_date_opt: None,
+        truncated_opt: None,
+        media_count_opt: None,
+    });
+    Ok(StateEntry { link, record })
+}
+
+/// Export the posted links of the store from the oldest.
+pub fn export_entries(state_store: &dyn StateStore) -> Vec<StateEntry> {
+    state_store
+        .links()
+        .into_iter()
+        .map(|link| StateEntry {
+            record: state_store.posted_record(&link),
+            link,
+        })
+        .collect()
+}
+
+/// Import the entries into the store, skipping the links posted already.
+/// Returns the number of the imported links.
+pub fn import_entries(
+    state_store: &mut dyn StateStore,
+    entries: &[StateEntry],
+) -> Result<usize, Box<dyn Error>> {
+    let mut count = 0;
+    for entry in entries {
+        if state_store.contains(&entry.link) {
+            continue;
+        }
+        state_store.record_posted(&entry.link, entry.record.as_ref())?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Read the posted links of the DB files at the path with their Bluesky
+/// records, from the oldest, grouped by their namespace. The links without a
+/// namespace come first, as they are seen from every namespace.
+pub fn read_namespaced_entries(
+    path: &str,
+) -> Result<BTreeMap<Option<String>, Vec<StateEntry>>, Box<dyn Error>> {
+    let mut records = HashMap::new();
+    // The records file may be missing in an old DB.
+    let records_lines = read_raw_lines(&format!("{path}.records"))?.lines;
+    for line in records_lines.into_iter().flatten() {
+        // A line cut by a crash only loses the record, as when loaded.
+        if let Ok(entry) = serde_json::from_str::<PostedRecordEntry>(&line) {
+            records.insert(entry.link, entry.record);
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut entries: BTreeMap<Option<String>, Vec<StateEntry>> = BTreeMap::new();
+    for line in read_links(path)? {
+        if !seen.insert(line.to_string()) {
+            continue;
+        }
+        let link = match line.rsplit_once(' ') {
+            Some((_, link)) => link.to_string(),
+            None => line.to_string(),
+        };
+        entries
+            .entry(line_namespace(&line))
+            .or_default()
+            .push(StateEntry {
+                link,
+                record: records.remove(&line),
+            });
+    }
+    Ok(entries)
+}
+
+/// Parse the exported entries, either a JSON array or JSON lines.
+pub fn parse_entries(
+    content: &str,
+    format: ExportFormat,
+) -> Result<Vec<StateEntry>, Box<dyn Error>> {
+    match format {
+        ExportFormat::Json => serde_json::from_str(content)
+            .map_err(|err| format!("Failed to parse the entries: {err}").into()),
+        ExportFormat::JsonLines => content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                serde_json::from_str(line)
+                    .map_err(|err| format!("Failed to parse the entry {line}: {err}").into())
+            })
+            .collect(),
+        ExportFormat::Csv => content
+            .lines()
+            .filter(|line| !line.trim().is_empty() && *line != CSV_HEADER)
+            .map(parse_csv_entry)
+            .collect(),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BlobCacheEntry {
+    key: String,
+    #[serde(flatten)]
+    blob: CachedBlob,
+}
+
+/// A store which keeps one link per line in a plain file.
+///
+/// Links are appended as they are posted, and the file is rewritten through a
+/// temporary file and a rename on compaction or on commit after removals, so a
+/// crash leaves either the old or the new file in place. The posted records of
+/// the retained links are kept in
+/// `<path>.records`, and uploaded blobs are cached in `<path>.blobs`, as JSON
+/// lines in the same way.
+///
+/// Links are retained for the retention days when given, or while they are in
+/// the last `min_save_posts` links otherwise or when their post time is unknown.
+///
+/// Links in a namespace are kept as lines prefixed by the namespace and a
+/// space, and counted for the retention per namespace.
+pub struct FileStateStore {
+    path: String,
+    namespace_opt: Option<String>,
+    fsync_policy: FsyncPolicy,
+    min_save_posts: usize,
+    retention_days_opt: Option<u32>,
+    links: HashSet<String>,
+    old_links_for_save: VecDeque<String>,
+    new_links: Vec<String>,
+    append_file: File,
+    records: HashMap<String, PostedRecord>,
+    records_append_file: File,
+    blobs: HashMap<String, CachedBlob>,
+    blob_keys: VecDeque<String>,
+    blobs_append_file: File,
+    created_records: Vec<CreatedRecordsEntry>,
+    created_records_append_file: File,
+    /// Whether links or records are removed, which only a rewrite drops
+    rewrite_needed: bool,
+}
+
+impl FileStateStore {
+    /// Open the store, creating the file if it does not exist.
+    pub fn open(
+        path: &str,
+        fsync_policy: FsyncPolicy,
+        min_save_posts: usize,
+        retention_days_opt: Option<u32>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let append_file = open_append(path)?;
+
+        let mut links = HashSet::new();
+        let mut old_links_for_save = VecDeque::new();
+        for link in read_links(path)? {
+            links.insert(link.to_string());
+            old_links_for_save.push_back(link);
+        }
+
+        let records_path = format!("{path}.records");
+        let records_append_file = open_append(&records_path)?;
+        let mut records = HashMap::new();
+        for line in read_links(&records_path)? {
+            if let Ok(entry) = serde_json::from_str::<PostedRecordEntry>(&line) {
+                records.insert(entry.link, entry.record);
+            }
+        }
+
+        let blobs_path = format!("{path}.blobs");
+        let blobs_append_file = open_append(&blobs_path)?;
+        let mut blobs = HashMap::new();
+        let mut blob_keys = VecDeque::new();
+        for line in read_links(&blobs_path)? {
+            // A line cut by a crash is only a cache miss.
+            if let Ok(entry) = serde_json::from_str::<BlobCacheEntry>(&line) {
+                if blobs.insert(entry.key.to_string(), entry.blob).is_none() {
+                    blob_keys.push_back(entry.key);
+                }
+            }
+        }
+
+        let created_records_path = format!("{path}.created");
+        let created_records_append_file = open_append(&created_records_path)?;
+        let mut created_records = vec![];
+        for line in read_links(&created_records_path)? {
+            // A line cut by a crash only loses the count.
+            if let Ok(entry) = serde_json::from_str::<CreatedRecordsEntry>(&line) {
+                created_records.push(entry);
+            }
+        }
+
+        Ok(FileStateStore {
+            path: path.to_string(),
+            namespace_opt: None,
+            fsync_policy,
+            min_save_posts,
+            retention_days_opt,
+            links,
+            old_links_for_save,
+            new_links: vec![],
+            append_file,
+            records,
+            records_append_file,
+            blobs,
+            blob_keys,
+            blobs_append_file,
+            created_records,
+            created_records_append_file,
+            rewrite_needed: false,
+        })
+    }
+
+    /// See only the links in the namespace, such as the feed URL, and record
+    /// new links in it. The links recorded without a namespace are seen from
+    /// every namespace, so that namespacing an existing DB posts nothing again.
+    pub fn with_namespace(mut self, namespace_opt: Option<String>) -> Self {
+        self.namespace_opt = namespace_opt;
+        self
+    }
+
+    /// The line of the link recorded in the namespace.
+    fn line_of(&self, link: &str) -> String {
+        match &self.namespace_opt {
+            Some(namespace) => format!("{namespace} {link}"),
+            None => link.to_string(),
+        }
+    }
+
+    /// The line of the link posted already, which may have no namespace.
+    fn existing_line_of(&self, link: &str) -> String {
+        let line = self.line_of(link);
+        if !self.links.contains(&line) && self.links.contains(link) {
+            link.to_string()
+        } else {
+            line
+        }
+    }
+
+    /// The link of the line if it is seen from the namespace.
+    fn link_of<'a>(&self, line: &'a str) -> Option<&'a str> {
+        match (&self.namespace_opt, line.rsplit_once(' ')) {
+            (Some(namespace), Some((line_namespace, link))) => {
+                (line_namespace == namespace).then_some(link)
+            }
+            _ => Some(line),
+        }
+    }
+
+    /// Drop the old links beyond the retention.
+    fn trim_old_links(&mut self) {
+        let cutoff_opt = self
+            .retention_days_opt
+            .map(|days| Utc::now() - Duration::days(i64::from(days)));
+        // Feeds post at their own pace, so the links are counted per namespace.
+        let mut counts: HashMap<Option<String>, usize> = HashMap::new();
+        for line in &self.old_links_for_save {
+            *counts.entry(line_namespace(line)).or_default() += 1;
+        }
+        let min_save_posts = self.min_save_posts;
+        let records = &self.records;
+        let mut indices: HashMap<Option<String>, usize> = HashMap::new();
+        self.old_links_for_save.retain(|link| {
+            let posted_at_opt = records.get(link).and_then(|record| record.posted_at_opt);
+            let namespace_opt = line_namespace(link);
+            let by_count_from = counts[&namespace_opt].saturating_sub(min_save_posts);
+            let index = indices.entry(namespace_opt).or_default();
+            let in_count = *index >= by_count_from;
+            *index += 1;
+            match (cutoff_opt, posted_at_opt) {
+                (Some(cutoff), Some(posted_at)) => posted_at >= cutoff,
+                _ => in_count,
+            }
+        });
+    }
+
+    /// Rewrite the files with the links and the records in the store.
+    fn rewrite(&mut self) -> Result<(), Box<dyn Error>> {
+        let lines: Vec<&String> = self
+            .old_links_for_save
+            .iter()
+            .chain(&self.new_links)
+            .collect();
+        replace_lines(&self.path, &lines, "DB")?;
+
+        // The old file is gone, so follow the new one for later appends.
+        self.append_file = open_append(&self.path)?;
+        for link in self.new_links.drain(..) {
+            self.old_links_for_save.push_back(link);
+        }
+
+        self.commit_records()?;
+        self.commit_blobs()?;
+        self.commit_created_records()?;
+        self.rewrite_needed = false;
+        Ok(())
+    }
+
+    fn commit_records(&mut self) -> Result<(), Box<dyn Error>> {
+        let retained: HashSet<&String> = self.old_links_for_save.iter().collect();
+        self.records.retain(|link, _| retained.contains(link));
+
+        let records_path = format!("{}.records", self.path);
+        let mut lines = vec![];
+        for link in &self.old_links_for_save {
+            if let Some(record) = self.records.get(link) {
+                let entry = PostedRecordEntry {
+                    link: link.to_string(),
+                    record: record.clone(),
+                };
+                lines.push(serde_json::to_string(&entry)?);
+            }
+        }
+        replace_lines(&records_path, &lines, "posted records")?;
+        self.records_append_file = open_append(&records_path)?;
+        Ok(())
+    }
+
+    fn commit_blobs(&mut self) -> Result<(), Box<dyn Error>> {
+        while self.blob_keys.len() > BLOB_CACHE_SIZE {
+            if let Some(key) = self.blob_keys.pop_front() {
+                self.blobs.remove(&key);
+            }
+        }
+
+        let blobs_path = format!("{}.blobs", self.path);
+        let mut lines = vec![];
+        for key in &self.blob_keys {
+            if let Some(blob) = self.blobs.get(key) {
+                let entry = BlobCacheEntry {
+                    key: key.to_string(),
+                    blob: blob.clone(),
+                };
+                lines.push(serde_json::to_string(&entry)?);
+            }
+        }
+        replace_lines(&blobs_path, &lines, "blob cache")?;
+        self.blobs_append_file = open_append(&blobs_path)?;
+        Ok(())
+    }
+
+    fn commit_created_records(&mut self) -> Result<(), Box<dyn Error>> {
+        let cutoff = Utc::now() - Duration::hours(CREATED_RECORDS_RETENTION_HOURS);
+        self.created_records
+            .retain(|entry| entry.created_at >= cutoff);
+
+        let created_records_path = format!("{}.created", self.path);
+        let mut lines = vec![];
+        for entry in &self.created_records {
+            lines.push(serde_json::to_string(entry)?);
+        }
+        replace_lines(&created_records_path, &lines, "created records")?;
+        self.created_records_append_file = open_append(&created_records_path)?;
+        Ok(())
+    }
+
+    /// Recover the files of the store at the path from a crash, under the lock
+    /// before opening it: the temporary files of a rewrite cut before its
+    /// rename are removed, as the old files are in place, and the last lines
+    /// cut are ended.
+    pub fn recover(path: &str) -> Result<(), Box<dyn Error>> {
+        for suffix in ["", ".records", ".blobs", ".created"] {
+            let file_path = format!("{path}{suffix}");
+            remove_stale_tmp(&file_path)?;
+            end_torn_line(&file_path)?;
+        }
+        Ok(())
+    }
+
+    /// Find the issues in the files of the store at the path, which is not
+    /// opened: duplicate links, non-UTF-8 or unterminated lines, unreadable
+    /// entries, and records of links not in the DB.
+    pub fn check(path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(scan_files(path)?.issues)
+    }
+
+    /// Whether a last line of the files of the store at the path is cut, as
+    /// by a crash while appending it, which `check` reports as unterminated.
+    pub fn has_torn_tail(path: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(scan_files(path)?.torn)
+    }
+
+    /// Rewrite the files of the store at the path without the issues found
+    /// by `check`, and return the issues. Unreadable lines are dropped.
+    pub fn repair(path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let scanned = scan_files(path)?;
+        if !scanned.issues.is_empty() {
+            replace_lines(path, &scanned.links, "DB")?;
+            replace_lines(
+                &format!("{path}.records"),
+                &scanned.records,
+                "posted records",
+            )?;
+            replace_lines(&format!("{path}.blobs"), &scanned.blobs, "blob cache")?;
+        }
+        Ok(scanned.issues)
+    }
+}
+
+/// The readable lines of the store files, with the issues found in them.
+struct ScannedFiles {
+    issues: Vec<String>,
+    /// Whether a last line is unterminated
+    torn: bool,
+    links: Vec<String>,
+    records: Vec<String>,
+    blobs: Vec<String>,
+}
+
+fn scan_files(path: &str) -> Result<ScannedFiles, Box<dyn Error>> {
+    let mut issues = vec![];
+    let mut torn = false;
+
+    // A link cut by a crash cannot be told from a whole one, so it is kept.
+    let RawLines { lines, terminated } = read_raw_lines(path)?;
+    if !terminated {
+        issues.push(format!("{path}: The last line is unterminated"));
+        torn = true;
+    }
+    let mut seen = HashSet::new();
+    let mut links = vec![];
+    for (index, line_opt) in lines.into_iter().enumerate() {
+        let line_number = index + 1;
+        match line_opt {
+            None => issues.push(format!("{path}:{line_number}: Not UTF-8")),
+            Some(link) if link.trim().is_empty() => {
+                issues.push(format!("{path}:{line_number}: Empty line"));
+            }
+            Some(link) if seen.contains(&link) => {
+                issues.push(format!("{path}:{line_number}: Duplicate link {link}"));
+            }
+            Some(link) => {
+                seen.insert(link.to_string());
+                links.push(link);
+            }
+        }
+    }
+
+    let records_path = format!("{path}.records");
+    let RawLines { lines, terminated } = read_raw_lines(&records_path)?;
+    if !terminated {
+        issues.push(format!("{records_path}: The last line is unterminated"));
+        torn = true;
+    }
+    let mut records = HashMap::new();
+    for (index, line_opt) in lines.into_iter().enumerate() {
+        let line_number = index + 1;
+        let entry_opt =
+            line_opt.and_then(|line| serde_json::from_str::<PostedRecordEntry>(&line).ok());
+        match entry_opt {
+            None => issues.push(format!("{records_path}:{line_number}: Unreadable record")),
+            Some(entry) if !seen.contains(&entry.link) => {
+                issues.push(format!(
+                    "{records_path}:{line_number}: Orphaned record of {}",
+                    entry.link
+                ));
+            }
+            // The later line of the link takes precedence as when loaded.
+            Some(entry) => {
+                records.insert(entry.link.to_string(), entry);
+            }
+        }
+    }
+    let mut record_lines = vec![];
+    for link in &links {
+        if let Some(entry) = records.get(link) {
+            record_lines.push(serde_json::to_string(entry)?);
+        }
+    }
+
+    let blobs_path = format!("{path}.blobs");
+    let RawLines { lines, terminated } = read_raw_lines(&blobs_path)?;
+    if !terminated {
+        issues.push(format!("{blobs_path}: The last line is unterminated"));
+        torn = true;
+    }
+    let mut blobs = vec![];
+    for (index, line_opt) in lines.into_iter().enumerate() {
+        let line_number = index + 1;
+        match line_opt.filter(|line| serde_json::from_str::<BlobCacheEntry>(line).is_ok()) {
+            Some(line) => blobs.push(line),
+            None => issues.push(format!("{blobs_path}:{line_number}: Unreadable blob")),
+        }
+    }
+
+    Ok(ScannedFiles {
+        issues,
+        torn,
+        links,
+        records: record_lines,
+        blobs,
+    })
+}
+
+struct RawLines {
+    /// The lines, which are `None` when not in UTF-8
+    lines: Vec<Option<String>>,
+    /// Whether the last line ends with a newline
+    terminated: bool,
+}
+
+/// Read the lines of the file as they are. A missing file has no lines.
+fn read_raw_lines(path: &str) -> Result<RawLines, Box<dyn Error>> {
+    let content = match std::fs::read(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Ok(RawLines {
+                lines: vec![],
+                terminated: true,
+            })
+        }
+        Err(err) => Err(format!("Failed to read {path}: {err}"))?,
+    };
+    let terminated = content.is_empty() || content.ends_with(b"\n");
+    let mut lines: Vec<Option<String>> = content
+        .split(|byte| *byte == b'\n')
+        .map(|line| String::from_utf8(line.to_vec()).ok())
+        .collect();
+    // The split leaves an empty line after the last newline.
+    if terminated {
+        lines.pop();
+    }
+    Ok(RawLines { lines, terminated })
+}
+
+impl StateStore for FileStateStore {
+    fn contains(&self, link: &str) -> bool {
+        self.links.contains(&self.line_of(link)) || self.links.contains(link)
+    }
+
+    fn links(&self) -> Vec<String> {
+        self.old_links_for_save
+            .iter()
+            .chain(&self.new_links)
+            .filter_map(|line| self.link_of(line))
+            .map(|link| link.to_string())
+            .collect()
+    }
+
+    fn remove(&mut self, link: &str) -> Result<(), Box<dyn Error>> {
+        // The files are rewritten without the link on commit.
+        for line in [self.line_of(link), link.to_string()] {
+            self.links.remove(&line);
+            self.old_links_for_save.retain(|old_line| *old_line != line);
+            self.new_links.retain(|new_line| *new_line != line);
+            self.records.remove(&line);
+        }
+        self.rewrite_needed = true;
+        Ok(())
+    }
+
+    fn record_posted(
+        &mut self,
+        link: &str,
+        record_opt: Option<&PostedRecord>,
+    ) -> Result<(), Box<dyn Error>> {
+        let line = self.line_of(link);
+        writeln!(self.append_file, "{line}").map_err(|err| format!("Failed to write DB: {err}"))?;
+        self.append_file
+            .flush()
+            .map_err(|err| format!("Failed to flush DB: {err}"))?;
+        if self.fsync_policy == FsyncPolicy::PerPost {
+            self.append_file
+                .sync_data()
+                .map_err(|err| format!("Failed to sync DB: {err}"))?;
+        }
+        self.links.insert(line.to_string());
+        self.new_links.push(line);
+
+        // The link is already recorded, so a lost record only loses the mapping.
+        match record_opt {
+            Some(record) => self.update_record(link, record),
+            None => Ok(()),
+        }
+    }
+
+    fn update_record(&mut self, link: &str, record: &PostedRecord) -> Result<(), Box<dyn Error>> {
+        // The later line of the link takes precedence when loaded.
+        let line = self.existing_line_of(link);
+        let entry = PostedRecordEntry {
+            link: line.to_string(),
+            record: record.clone(),
+        };
+        writeln!(
+            self.records_append_file,
+            "{}",
+            serde_json::to_string(&entry)?
+        )
+        .map_err(|err| format!("Failed to write posted records: {err}"))?;
+        self.records_append_file
+            .flush()
+            .map_err(|err| format!("Failed to flush posted records: {err}"))?;
+        self.records.insert(line, record.clone());
+        Ok(())
+    }
+
+    fn posted_record(&self, link: &str) -> Option<PostedRecord> {
+        self.records.get(&self.existing_line_of(link)).cloned()
+    }
+
+    fn posted_records(&self) -> Vec<(String, PostedRecord)> {
+        self.records
+            .iter()
+            .filter_map(|(line, record)| Some((self.link_of(line)?.to_string(), record.clone())))
+            .collect()
+    }
+
+    fn forget_record(&mut self, link: &str) -> Result<(), Box<dyn Error>> {
+        // The file is rewritten without the record on commit.
+        let line = self.existing_line_of(link);
+        self.records.remove(&line);
+        self.rewrite_needed = true;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.rewrite_needed {
+            return self.rewrite();
+        }
+        // The lines are appended and flushed already.
+        for (file, name) in [
+            (&self.append_file, "DB"),
+            (&self.records_append_file, "posted records"),
+            (&self.blobs_append_file, "blob cache"),
+            (&self.created_records_append_file, "created records"),
+        ] {
+            file.sync_data()
+                .map_err(|err| format!("Failed to sync {name}: {err}"))?;
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<(), Box<dyn Error>> {
+        for link in self.new_links.drain(..) {
+            self.old_links_for_save.push_back(link);
+        }
+        self.trim_old_links();
+        let retained: HashSet<&String> = self.old_links_for_save.iter().collect();
+        self.links.retain(|link| retained.contains(link));
+        self.rewrite()
+    }
+
+    fn cached_blob(&self, key: &str) -> Option<CachedBlob> {
+        self.blobs.get(key).cloned()
+    }
+
+    fn cache_blob(&mut self, key: &str, blob: &CachedBlob) -> Result<(), Box<dyn Error>> {
+        let entry = BlobCacheEntry {
+            key: key.to_string(),
+            blob: blob.clone(),
+        };
+        writeln!(self.blobs_append_file, "{}", serde_json::to_string(&entry)?)
+            .map_err(|err| format!("Failed to write blob cache: {err}"))?;
+        self.blobs_append_file
+            .flush()
+            .map_err(|err| format!("Failed to flush blob cache: {err}"))?;
+        if self.blobs.insert(key.to_string(), blob.clone()).is_none() {
+            self.blob_keys.push_back(key.to_string());
+        }
+        Ok(())
+    }
+
+    fn created_records_since(
+        &mut self,
+        account: &str,
+        since: DateTime<Utc>,
+    ) -> Result<usize, Box<dyn Error>> {
+        Ok(self
+            .created_records
+            .iter()
+            .filter(|entry| entry.account == account && entry.created_at >= since)
+            .map(|entry| entry.count)
+            .sum())
+    }
+
+    fn record_created(&mut self, account: &str, count: usize) -> Result<(), Box<dyn Error>> {
+        let entry = CreatedRecordsEntry {
+            account: account.to_string(),
+            created_at: Utc::now(),
+            count,
+        };
+        writeln!(
+            self.created_records_append_file,
+            "{}",
+            serde_json::to_string(&entry)?
+        )
+        .map_err(|err| format!("Failed to write created records: {err}"))?;
+        self.created_records_append_file
+            .flush()
+            .map_err(|err| format!("Failed to flush created records: {err}"))?;
+        self.created_records.push(entry);
+        Ok(())
+    }
+}
+
+/// The namespace of the line in the DB, as links have no spaces.
+fn line_namespace(line: &str) -> Option<String> {
+    line.rsplit_once(' ')
+        .map(|(namespace, _)| namespace.to_string())
+}
+
+/// Read all links in the DB file.
+pub fn read_links(path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let db_file = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .map_err(|err| format!("Failed to open DB: {err}"))?;
+    let mut links = vec![];
+    for link in BufReader::new(db_file).lines() {
+        links.push(link.map_err(|err| format!("Failed to read DB: {err}"))?);
+    }
+    Ok(links)
+}
+
+/// Replace the file with the lines through a synced temporary file and a
+/// rename, so a crash leaves either the old or the new file in place.
+pub(crate) fn replace_lines(
+    path: &str,
+    lines: &[impl AsRef<str>],
+    name: &str,
+) -> Result<(), Box<dyn Error>> {
+    let tmp_path = format!("{path}.tmp");
+    {
+        let mut tmp_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)
+            .map_err(|err| format!("Failed to open {name}: {err}"))?;
+        for line in lines {
+            writeln!(tmp_file, "{}", line.as_ref())
+                .map_err(|err| format!("Failed to write {name}: {err}"))?;
+        }
+        tmp_file
+            .sync_all()
+            .map_err(|err| format!("Failed to sync {name}: {err}"))?;
+    }
+    std::fs::rename(&tmp_path, path).map_err(|err| format!("Failed to replace {name}: {err}"))?;
+    sync_parent_dir(path);
+    Ok(())
+}
+
+fn open_append(path: &str) -> Result<File, Box<dyn Error>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| format!("Failed to open DB: {err}"))?;
+    Ok(file)
+}
+
+/// End the last line of the file if a crash cut it, so that the next line
+/// appended is not joined to it.
+fn end_torn_line(path: &str) -> Result<(), Box<dyn Error>> {
+    let mut file = match OpenOptions::new().read(true).append(true).open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => Err(format!("Failed to open {path}: {err}"))?,
+    };
+    let len = file
+        .metadata()
+        .map_err(|err| format!("Failed to read {path}: {err}"))?
+        .len();
+    if len == 0 {
+        return Ok(());
+    }
+    let mut last_byte = [0];
+    file.seek(SeekFrom::Start(len - 1))
+        .and_then(|_| file.read_exact(&mut last_byte))
+        .map_err(|err| format!("Failed to read {path}: {err}"))?;
+    if last_byte[0] != b'\n' {
+        writeln!(file).map_err(|err| format!("Failed to write {path}: {err}"))?;
+    }
+    Ok(())
+}
+
+/// Remove the temporary file of a rewrite cut by a crash before its rename,
+/// which left the old file in place.
+fn remove_stale_tmp(path: &str) -> Result<(), Box<dyn Error>> {
+    match std::fs::remove_file(format!("{path}.tmp")) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(format!(
+            "Failed to remove the temporary file of {path}: {err}"
+        ))?,
+        _ => Ok(()),
+    }
+}
+
+/// Sync the directory entry of the renamed file. Not all platforms allow
+/// opening a directory, so this is best-effort.
+fn sync_parent_dir(path: &str) {
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Ok(dir_file) = File::open(dir) {
+        let _ = dir_file.sync_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for the files of a test.
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("state-store-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn record_of(uri: &str) -> PostedRecord {
+        PostedRecord {
+            uri: uri.to_string(),
+            cid: String::from("cid"),
+            posted_at_opt: Some(Utc::now()),
+            content_hash_opt: None,
+            dedup_hash_opt: None,
+            feed_url_opt: None,
+            pub_date_opt: None,
+            truncated_opt: None,
+            media_count_opt: None,
+        }
+    }
+
+    #[test]
+    fn torn_last_lines_are_ended_on_recover() {
+        let dir = test_dir("torn");
+        let path = dir.join("db").to_string_lossy().to_string();
+        let whole_entry = PostedRecordEntry {
+            link: String::from("https://example.com/1"),
+            record: record_of("at://post/1"),
+        };
+        let whole_line = serde_json::to_string(&whole_entry).unwrap();
+        std::fs::write(&path, "https://example.com/1\nhttps://example.com/2").unwrap();
+        std::fs::write(
+            format!("{path}.records"),
+            format!("{whole_line}\n{}", &whole_line[..whole_line.len() / 2]),
+        )
+        .unwrap();
+
+        FileStateStore::recover(&path).unwrap();
+        let mut state_store = FileStateStore::open(&path, FsyncPolicy::PerRun, 10, None).unwrap();
+        assert!(state_store.posted_record("https://example.com/1").is_some());
+        state_store
+            .record_posted("https://example.com/3", Some(&record_of("at://post/3")))
+            .unwrap();
+        state_store.commit().unwrap();
+
+        let state_store = FileStateStore::open(&path, FsyncPolicy::PerRun, 10, None).unwrap();
+        assert_eq!(
+            state_store.links(),
+            [
+                "https://example.com/1",
+                "https://example.com/2",
+                "https://example.com/3"
+            ]
+        );
+        assert!(state_store.posted_record("https://example.com/1").is_some());
+        assert!(state_store.posted_record("https://example.com/3").is_some());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn interrupted_rewrite_keeps_old_file() {
+        let dir = test_dir("rewrite");
+        let path = dir.join("db").to_string_lossy().to_string();
+        std::fs::write(&path, "https://example.com/1\n").unwrap();
+        // The rewrite wrote the temporary file but crashed before the rename.
+        std::fs::write(format!("{path}.tmp"), "https://example.com/2\n").unwrap();
+        std::fs::write(format!("{path}.records.tmp"), "").unwrap();
+
+        FileStateStore::recover(&path).unwrap();
+        let state_store = FileStateStore::open(&path, FsyncPolicy::PerRun, 10, None).unwrap();
+        assert_eq!(state_store.links(), ["https://example.com/1"]);
+        assert!(!Path::new(&format!("{path}.tmp")).exists());
+        assert!(!Path::new(&format!("{path}.records.tmp")).exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn fsync_policies_write_through() {
+        for fsync_policy in [FsyncPolicy::PerPost, FsyncPolicy::PerRun] {
+            let dir = test_dir(&format!("fsync-{fsync_policy:?}"));
+            let path = dir.join("db").to_string_lossy().to_string();
+            let mut state_store = FileStateStore::open(&path, fsync_policy, 10, None).unwrap();
+            state_store
+                .record_posted("https://example.com/1", Some(&record_of("at://post/1")))
+                .unwrap();
+            // The lines are in the files before the commit under both policies.
+            assert_eq!(read_links(&path).unwrap(), ["https://example.com/1"]);
+            assert_eq!(read_links(&format!("{path}.records")).unwrap().len(), 1);
+
+            state_store.forget_record("https://example.com/1").unwrap();
+            state_store.commit().unwrap();
+            let state_store = FileStateStore::open(&path, fsync_policy, 10, None).unwrap();
+            assert_eq!(state_store.links(), ["https://example.com/1"]);
+            assert!(state_store.posted_record("https://example.com/1").is_none());
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+    }
+
+    #[test]
+    fn repair_drops_corrupted_lines() {
+        let dir = test_dir("repair");
+        let path = dir.join("db").to_string_lossy().to_string();
+        let entry_line = |link: &str| {
+            let entry = PostedRecordEntry {
+                link: link.to_string(),
+                record: record_of("at://post"),
+            };
+            serde_json::to_string(&entry).unwrap()
+        };
+        let mut db = b"https://example.com/1\n\nhttps://example.com/1\n".to_vec();
+        db.extend(b"\xff\xfe\n");
+        db.extend(b"https://example.com/2");
+        std::fs::write(&path, db).unwrap();
+        std::fs::write(
+            format!("{path}.records"),
+            format!(
+                "{}\n{{\"link\":\n{}\n",
+                entry_line("https://example.com/1"),
+                entry_line("https://example.com/9")
+            ),
+        )
+        .unwrap();
+        std::fs::write(format!("{path}.blobs"), "not a blob\n").unwrap();
+
+        let issues = FileStateStore::check(&path).unwrap();
+        assert_eq!(
+            issues,
+            [
+                format!("{path}: The last line is unterminated"),
+                format!("{path}:2: Empty line"),
+                format!("{path}:3: Duplicate link https://example.com/1"),
+                format!("{path}:4: Not UTF-8"),
+                format!("{path}.records:2: Unreadable record"),
+                format!("{path}.records:3: Orphaned record of https://example.com/9"),
+                format!("{path}.blobs:1: Unreadable blob"),
+            ]
+        );
+        assert_eq!(FileStateStore::repair(&path).unwrap(), issues);
+        assert!(FileStateStore::check(&path).unwrap().is_empty());
+
+        let state_store = FileStateStore::open(&path, FsyncPolicy::PerRun, 10, None).unwrap();
+        assert_eq!(
+            state_store.links(),
+            ["https://example.com/1", "https://example.com/2"]
+        );
+        assert!(state_store.posted_record("https://example.com/1").is_some());
+        assert!(state_store.posted_record("https://example.com/9").is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
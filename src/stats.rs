@@ -0,0 +1,97 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, ErrorKind};
+
+use crate::logging::Event;
+use crate::state_store;
+
+/// How long stats records are kept in the stats file.
+const STATS_RETENTION_DAYS: i64 = 30;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PostStat {
+    pub posted_at: DateTime<Utc>,
+    pub account: String,
+    pub text_length: usize,
+    pub media_count: usize,
+}
+
+pub fn load_stats(stats_path: &str) -> Result<Vec<PostStat>, Box<dyn Error>> {
+    let stats_file = match OpenOptions::new().read(true).open(stats_path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => Err(format!("Failed to open stats: {err}"))?,
+    };
+    let mut stats = vec![];
+    for line in BufReader::new(stats_file).lines() {
+        let line = line?;
+        match serde_json::from_str(&line) {
+            Ok(stat) => stats.push(stat),
+            Err(err) => {
+                Event::warn("Ignore a broken stats record")
+                    .error(err)
+                    .emit();
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Append the new records and drop the ones older than the retention.
+pub fn save_stats(stats_path: &str, new_stats: &[PostStat]) -> Result<(), Box<dyn Error>> {
+    let since = Utc::now() - Duration::days(STATS_RETENTION_DAYS);
+    let mut stats = load_stats(stats_path)?;
+    stats.retain(|stat| stat.posted_at >= since);
+
+    let mut lines = vec![];
+    for stat in stats.iter().chain(new_stats) {
+        lines.push(serde_json::to_string(stat)?);
+    }
+    state_store::replace_lines(stats_path, &lines, "stats")
+}
+
+pub fn print_stats(stats: &[PostStat], now: DateTime<Utc>) {
+    let mut by_account: BTreeMap<&str, Vec<&PostStat>> = BTreeMap::new();
+    for stat in stats {
+        by_account.entry(&stat.account).or_default().push(stat);
+    }
+
+    if by_account.is_empty() {
+        println!("No posts recorded in the last {STATS_RETENTION_DAYS} days.");
+    }
+    for (account, stats) in by_account {
+        let posts_since = |duration: Duration| {
+            stats
+                .iter()
+                .filter(|stat| stat.posted_at >= now - duration)
+                .count()
+        };
+        let with_media = stats.iter().filter(|stat| stat.media_count > 0).count();
+        let total_length: usize = stats.iter().map(|stat| stat.text_length).sum();
+
+        println!("account={account}:");
+        println!(
+            "  posts in the last day: {}",
+            posts_since(Duration::days(1))
+        );
+        println!(
+            "  posts in the last week: {}",
+            posts_since(Duration::weeks(1))
+        );
+        println!(
+            "  posts in the last {STATS_RETENTION_DAYS} days: {}",
+            stats.len()
+        );
+        println!(
+            "  media ratio: {:.2}",
+            with_media as f64 / stats.len() as f64
+        );
+        println!(
+            "  average length: {:.1}",
+            total_length as f64 / stats.len() as f64
+        );
+    }
+}
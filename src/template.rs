@@ -0,0 +1,238 @@
+use handlebars::Handlebars;
+use serde::Serialize;
+use std::error::Error;
+use std::str::FromStr;
+
+use crate::richtext::{self, RichText, RichTextSegment};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LinkPlacement {
+    /// Append the original link just after the content
+    Append,
+    /// Put the original link on its own trailing line
+    OwnLine,
+    /// Put the original link before the content
+    First,
+    /// Do not include the original link
+    Omit,
+    /// Append the original link only when the content was truncated
+    WhenTruncated,
+    /// Attach the original link as an external card, or append it when the post has images
+    Card,
+}
+
+impl LinkPlacement {
+    pub fn default_template(&self) -> PostTemplate {
+        use LayoutPart::*;
+
+        let parts = match self {
+            LinkPlacement::Append | LinkPlacement::WhenTruncated | LinkPlacement::Card => {
+                vec![Content, Newline, LinkPrefix, Link]
+            }
+            LinkPlacement::OwnLine => vec![Content, Newline, LinkPrefix, Newline, Link],
+            LinkPlacement::First => vec![LinkPrefix, Link, Newline, Content],
+            LinkPlacement::Omit => vec![Content],
+        };
+        PostTemplate {
+            source: TemplateSource::Layout(parts),
+        }
+    }
+}
+
+/// A part of the layouts of the link placements.
+#[derive(Clone, Copy, Debug)]
+enum LayoutPart {
+    Content,
+    LinkPrefix,
+    Link,
+    Newline,
+}
+
+#[derive(Clone, Debug)]
+enum TemplateSource {
+    /// The layout of a link placement, which needs no parsing
+    Layout(Vec<LayoutPart>),
+    /// A Handlebars template given by the user
+    Handlebars(Box<Handlebars<'static>>),
+}
+
+/// The name of the template in the Handlebars registry.
+const TEMPLATE_NAME: &str = "post";
+
+/// The markers of `{{content}}` and `{{link}}` in the rendered text, which are
+/// replaced by the rich text. They are noncharacters, which are stripped from
+/// the other fields in case a feed has them.
+const CONTENT_MARKER: char = '\u{FDD0}';
+const LINK_MARKER: char = '\u{FDD1}';
+
+/// A post layout in Handlebars, such as
+/// `{{#if title}}{{title}}\n{{/if}}{{content}}\n{{link_prefix}}{{link}}`.
+///
+/// The `{{content}}` field keeps its link facets and `{{link}}` becomes a link
+/// facet, while the other fields are rendered as plain text. Nothing is
+/// escaped, as posts are plain text rather than HTML.
+#[derive(Clone, Debug)]
+pub struct PostTemplate {
+    source: TemplateSource,
+}
+
+pub struct TemplateValues<'a> {
+    pub link: &'a str,
+    pub link_prefix: &'a str,
+    pub title: &'a str,
+    pub pub_date: &'a str,
+    pub author: &'a str,
+}
+
+/// The fields given to Handlebars.
+#[derive(Serialize)]
+struct TemplateData<'a> {
+    content: String,
+    link: String,
+    link_prefix: &'a str,
+    title: &'a str,
+    pub_date: &'a str,
+    author: &'a str,
+}
+
+impl FromStr for PostTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut handlebars = Handlebars::new();
+        // Unknown fields are errors rather than empty.
+        handlebars.set_strict_mode(true);
+        handlebars.register_escape_fn(handlebars::no_escape);
+        handlebars
+            .register_template_string(TEMPLATE_NAME, s)
+            .map_err(|err| format!("Invalid post template: {err}"))?;
+        let template = PostTemplate {
+            source: TemplateSource::Handlebars(Box::new(handlebars)),
+        };
+
+        // The fields are checked by rendering them once.
+        let values = TemplateValues {
+            link: "https://example.com/",
+            link_prefix: "prefix",
+            title: "title",
+            pub_date: "pub_date",
+            author: "author",
+        };
+        template
+            .render(&vec![], &values)
+            .map_err(|err| err.to_string())?;
+        Ok(template)
+    }
+}
+
+impl PostTemplate {
+    /// Render the template, inserting the given content at `{{content}}`.
+    /// Empty fields are skipped and trailing whitespace is trimmed.
+    pub fn render(
+        &self,
+        content: &RichText,
+        values: &TemplateValues,
+    ) -> Result<RichText, Box<dyn Error>> {
+        // Empty fields are falsy in conditionals.
+        let marker_of = |marker: char, empty: bool| match empty {
+            true => String::new(),
+            false => marker.to_string(),
+        };
+        let content_field = marker_of(CONTENT_MARKER, content.is_empty());
+        let link_field = marker_of(LINK_MARKER, values.link.is_empty());
+        let strip_markers = |field: &str| field.replace([CONTENT_MARKER, LINK_MARKER], "");
+        let link_prefix = strip_markers(values.link_prefix);
+        let rendered = match &self.source {
+            TemplateSource::Layout(parts) => parts
+                .iter()
+                .map(|part| match part {
+                    LayoutPart::Content => content_field.as_str(),
+                    LayoutPart::LinkPrefix => link_prefix.as_str(),
+                    LayoutPart::Link => link_field.as_str(),
+                    LayoutPart::Newline => "\n",
+                })
+                .collect(),
+            TemplateSource::Handlebars(handlebars) => {
+                let data = TemplateData {
+                    content: content_field,
+                    link: link_field,
+                    link_prefix: &link_prefix,
+                    title: &strip_markers(values.title),
+                    pub_date: &strip_markers(values.pub_date),
+                    author: &strip_markers(values.author),
+                };
+                handlebars
+                    .render(TEMPLATE_NAME, &data)
+                    .map_err(|err| format!("Failed to render post template: {err}"))?
+            }
+        };
+
+        let mut text: RichText = vec![];
+        let mut literal = String::new();
+        for c in rendered.chars() {
+            if c != CONTENT_MARKER && c != LINK_MARKER {
+                literal.push(c);
+                continue;
+            }
+            if !literal.is_empty() {
+                text.push(RichTextSegment::PlainText {
+                    text: std::mem::take(&mut literal),
+                });
+            }
+            if c == CONTENT_MARKER {
+                text.extend(content.iter().cloned());
+            } else {
+                text.push(RichTextSegment::Link {
+                    text: values.link.to_string(),
+                    link: values.link.to_string(),
+                });
+            }
+        }
+        if !literal.is_empty() {
+            text.push(RichTextSegment::PlainText { text: literal });
+        }
+        richtext::trim_end(&mut text);
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_strips_markers_from_fields() {
+        let template: PostTemplate =
+            "{{title}} {{author}} {{pub_date}}\n{{content}}\n{{link_prefix}}{{link}}"
+                .parse()
+                .unwrap();
+        let content = vec![RichTextSegment::PlainText {
+            text: String::from("body"),
+        }];
+        let values = TemplateValues {
+            link: "https://example.com/1",
+            link_prefix: "via\u{FDD1}:",
+            title: "a\u{FDD0}title",
+            pub_date: "\u{FDD1}today",
+            author: "an\u{FDD0}\u{FDD1}author",
+        };
+        let text = template.render(&content, &values).unwrap();
+
+        let texts: Vec<&str> = text.iter().map(|seg| seg.text()).collect();
+        assert_eq!(
+            texts,
+            [
+                "atitle anauthor today\n",
+                "body",
+                "\nvia:",
+                "https://example.com/1"
+            ]
+        );
+        assert_eq!(
+            text.iter()
+                .filter(|seg| matches!(seg, RichTextSegment::Link { .. }))
+                .count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,37 @@
+pub const DEFAULT_TRACKING_PARAMS: &str =
+    "utm_*,fbclid,gclid,dclid,msclkid,yclid,mc_cid,mc_eid,igshid,_hsenc,_hsmi";
+
+/// Remove query parameters matching the given names from the link. A name
+/// ending with `*` matches as a prefix. Links which fail to parse are kept as is.
+pub fn strip_tracking_params(link: &str, params: &[String]) -> String {
+    let mut url = match reqwest::Url::parse(link) {
+        Ok(url) => url,
+        Err(_) => return link.to_string(),
+    };
+    if url.query().is_none() {
+        return link.to_string();
+    }
+
+    let is_tracking = |key: &str| {
+        params.iter().any(|param| match param.strip_suffix('*') {
+            Some(prefix) => key.starts_with(prefix),
+            None => key == param,
+        })
+    };
+    let pairs_count = url.query_pairs().count();
+    let kept: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| !is_tracking(key))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    if kept.len() == pairs_count {
+        return link.to_string();
+    }
+
+    if kept.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(kept);
+    }
+    url.to_string()
+}
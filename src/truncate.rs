@@ -0,0 +1,151 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::richtext::{self, RichText, RichTextSegment};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum TruncateStrategy {
+    /// Cut exactly at the text limit
+    Hard,
+    /// Cut at the last whitespace before the text limit
+    WordBoundary,
+    /// Cut after the last sentence terminator before the text limit
+    SentenceBoundary,
+}
+
+const SENTENCE_TERMINATORS: &[char] = &['.', '!', '?', '。', '！', '？', '\n'];
+
+/// Truncate the text to at most `limit` chars, moving the cut back to a clean
+/// break per the strategy. Returns whether the text was truncated.
+pub fn truncate_richtext(
+    text: RichText,
+    limit: usize,
+    strategy: TruncateStrategy,
+) -> (RichText, bool) {
+    let plain = richtext::to_plain_text(&text);
+
+    let hard_cut = match plain.char_indices().nth(limit) {
+        Some((i, _)) => i,
+        None => return (text, false),
+    };
+    let hard_truncated = &plain[..hard_cut];
+
+    let cut = match strategy {
+        TruncateStrategy::Hard => None,
+        TruncateStrategy::WordBoundary if plain[hard_cut..].starts_with(char::is_whitespace) => {
+            Some(hard_truncated.trim_end().len())
+        }
+        TruncateStrategy::WordBoundary => find_word_boundary(hard_truncated),
+        TruncateStrategy::SentenceBoundary => {
+            find_sentence_boundary(hard_truncated).or_else(|| find_word_boundary(hard_truncated))
+        }
+    }
+    .unwrap_or(hard_cut);
+
+    (take_bytes(text, cut), true)
+}
+
+/// Truncate the text like `truncate_richtext`, but keep the first external
+/// link at the end of the truncated text when it would be dropped.
+pub fn truncate_richtext_preserving_link(
+    text: RichText,
+    limit: usize,
+    strategy: TruncateStrategy,
+) -> (RichText, bool) {
+    // Mentions and hashtags are also links, but not what the post is about.
+    let first_link_opt = text
+        .iter()
+        .find(|seg| match seg {
+            RichTextSegment::Link { text, .. } => !text.starts_with(['#', '@']),
+            RichTextSegment::PlainText { .. } | RichTextSegment::Mention { .. } => false,
+        })
+        .cloned();
+
+    let (truncated, need_truncate) = truncate_richtext(text.clone(), limit, strategy);
+    let first_link = match first_link_opt {
+        Some(first_link) if need_truncate => first_link,
+        _ => return (truncated, need_truncate),
+    };
+    if truncated
+        .iter()
+        .any(|seg| seg.text() == first_link.text() && seg.is_same_link(&first_link))
+    {
+        return (truncated, need_truncate);
+    }
+
+    let link_count = first_link.text().chars().count() + 1;
+    if link_count >= limit {
+        return (truncated, need_truncate);
+    }
+
+    let (mut truncated, _) = truncate_richtext(text, limit - link_count, strategy);
+    truncated.retain(|seg| !seg.is_same_link(&first_link));
+    richtext::trim_end(&mut truncated);
+    truncated.push(RichTextSegment::PlainText {
+        text: String::from(" "),
+    });
+    truncated.push(first_link);
+    (truncated, true)
+}
+
+/// Truncate the plain text to at most `limit` graphemes, ending it with an
+/// ellipsis when truncated.
+pub fn truncate_graphemes(text: &str, limit: usize) -> String {
+    let hard_cut = match text.grapheme_indices(true).nth(limit) {
+        Some((i, _)) => i,
+        None => return text.to_string(),
+    };
+    // The ellipsis takes the place of the last grapheme.
+    let cut = text[..hard_cut]
+        .grapheme_indices(true)
+        .next_back()
+        .map_or(0, |(i, _)| i);
+    format!("{}…", text[..cut].trim_end())
+}
+
+fn take_bytes(text: RichText, cut: usize) -> RichText {
+    let mut rest = cut;
+    let mut truncated: RichText = vec![];
+    for seg in text {
+        if rest == 0 {
+            break;
+        }
+        let seg_text = seg.text();
+        if seg_text.len() <= rest {
+            rest -= seg_text.len();
+            truncated.push(seg);
+            continue;
+        }
+        // A link cut in the middle would label its whole target with a part of
+        // its text, so it is dropped unless nothing else is left.
+        let keeps_cut_link = truncated.iter().all(|kept| kept.text().trim().is_empty());
+        match seg {
+            RichTextSegment::Link { .. } | RichTextSegment::Mention { .. } if !keeps_cut_link => {}
+
+            _ => truncated.push(seg.with_text(seg_text[..rest].to_string())),
+        }
+        rest = 0;
+    }
+    truncated
+}
+
+fn find_word_boundary(content: &str) -> Option<usize> {
+    let trimmed = content.trim_end();
+    if trimmed.len() < content.len() {
+        return Some(trimmed.len());
+    }
+    trimmed
+        .char_indices()
+        .rev()
+        .find(|(_, c)| c.is_whitespace())
+        .map(|(i, _)| content[..i].trim_end().len())
+        .filter(|cut| *cut > 0)
+}
+
+fn find_sentence_boundary(content: &str) -> Option<usize> {
+    content
+        .char_indices()
+        .rev()
+        .find(|(_, c)| SENTENCE_TERMINATORS.contains(c))
+        .map(|(i, c)| content[..i + c.len_utf8()].trim_end().len())
+        .filter(|cut| *cut > 0)
+}
@@ -0,0 +1,92 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+use crate::logging::Event;
+
+const LATEST_RELEASE_URL: &str =
+    "https://api.github.com/repos/mizunashi-mana/mstdn-rss2bsky-post/releases/latest";
+
+/// How long the fetched latest version is reused.
+const CHECK_INTERVAL_HOURS: i64 = 24;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UpdateCheckCache {
+    checked_at: DateTime<Utc>,
+    latest_version: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+}
+
+/// Log when a newer release is published. Failures are only logged, since the
+/// check should never stop posting.
+pub async fn check_update(client: &reqwest::Client, cache_path: &str) {
+    let current_version = env!("CARGO_PKG_VERSION");
+    match latest_version(client, cache_path).await {
+        Ok(latest_version) if is_newer(&latest_version, current_version) => {
+            Event::warn(format!(
+                "A newer version is available: {latest_version} (running {current_version})."
+            ))
+            .emit();
+        }
+        Ok(_) => {
+            // up to date
+        }
+        Err(err) => {
+            Event::warn("Failed to check updates").error(err).emit();
+        }
+    }
+}
+
+async fn latest_version(
+    client: &reqwest::Client,
+    cache_path: &str,
+) -> Result<String, Box<dyn Error>> {
+    if let Some(cache) = load_cache(cache_path) {
+        if Utc::now() - cache.checked_at < Duration::hours(CHECK_INTERVAL_HOURS) {
+            return Ok(cache.latest_version);
+        }
+    }
+
+    let body = client
+        .get(LATEST_RELEASE_URL)
+        .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let release: Release = serde_json::from_slice(&body)?;
+    let latest_version = release.tag_name.trim_start_matches('v').to_string();
+
+    let cache = UpdateCheckCache {
+        checked_at: Utc::now(),
+        latest_version: latest_version.to_string(),
+    };
+    std::fs::write(cache_path, serde_json::to_string(&cache)?)
+        .map_err(|err| format!("Failed to write update check cache: {err}"))?;
+
+    Ok(latest_version)
+}
+
+fn load_cache(cache_path: &str) -> Option<UpdateCheckCache> {
+    let content = std::fs::read_to_string(cache_path).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// Compare the numeric parts of `major.minor.patch`, ignoring pre-release suffixes.
+fn is_newer(latest: &str, current: &str) -> bool {
+    let parse = |version: &str| -> Vec<u64> {
+        version
+            .split('-')
+            .next()
+            .unwrap_or("")
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    };
+    parse(latest) > parse(current)
+}
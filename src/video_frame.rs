@@ -0,0 +1,37 @@
+use std::error::Error;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Extract the first frame of the video as PNG with the `ffmpeg` command.
+pub fn extract_still_frame(content: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    // MP4 files may have their index at the end, so ffmpeg needs a seekable file.
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.subsec_nanos())
+        .unwrap_or(0);
+    let video_path = std::env::temp_dir().join(format!(
+        "mstdn-rss2bsky-post-{}-{}.video",
+        std::process::id(),
+        nanos
+    ));
+    std::fs::write(&video_path, content)
+        .map_err(|err| format!("Failed to write the video to extract a frame: {err}"))?;
+
+    let output = Command::new("ffmpeg")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-i")
+        .arg(&video_path)
+        .args(["-frames:v", "1", "-f", "image2pipe", "-vcodec", "png", "-"])
+        .output();
+    let _ = std::fs::remove_file(&video_path);
+
+    let output = output.map_err(|err| format!("Failed to run ffmpeg: {err}"))?;
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(Box::<dyn Error>::from(format!(
+            "Failed to extract a frame of the video: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output.stdout)
+}
@@ -1,6 +1,45 @@
 use async_trait::async_trait;
+use atrium_api::app::bsky::feed::get_author_feed;
+use atrium_api::com::atproto::repo::upload_blob;
 use atrium_api::xrpc;
 use std::error::Error;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{Instrument, Level};
+
+use crate::logging::{Action, Event};
+use crate::media_hosts::MediaHostPolicy;
+use crate::oauth::{self, DpopKey, OAuthSession};
+use crate::rate_limit::{self, RateLimit};
+
+/// The retries of a request rate limited by the XRPC host.
+const RATE_LIMIT_RETRIES: u32 = 3;
+
+/// The wait before the first retry of a transient failure, which doubles
+/// every retry.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Called with the new access and refresh tokens when the session is
+/// refreshed in the middle of a run, to save them.
+pub type OnSessionRefreshed = Box<dyn Fn(&str, &str) + Send + Sync>;
+
+/// The tokens to refresh the session when the access token expires.
+struct SessionRefresh {
+    /// The access token of the last refresh, which is sent in place of the
+    /// one set by `set_session`
+    access_jwt_opt: Option<String>,
+    refresh_jwt: String,
+    /// The OAuth session which refreshes the tokens in place of the PDS
+    oauth_opt: Option<OAuthSession>,
+    on_refreshed: OnSessionRefreshed,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RefreshedSession {
+    access_jwt: String,
+    refresh_jwt: String,
+}
 
 pub struct XrpcReqwestClient {
     client: reqwest::Client,
@@ -8,6 +47,26 @@ pub struct XrpcReqwestClient {
     access_did: Option<String>,
     host: String,
     dry_run: bool,
+    remote_content_timeout: Duration,
+    remote_content_retries: u32,
+    remote_content_max_bytes: usize,
+    /// The client to get remote contents, which may differ in the redirects
+    /// and the name resolution
+    remote_content_client: reqwest::Client,
+    remote_content_hosts: MediaHostPolicy,
+    /// The budget of the requests left by the last response
+    rate_limit: Mutex<Option<RateLimit>>,
+    /// The longest wait for the rate limit, over which the request fails
+    max_rate_limit_wait: Duration,
+    /// The retries of a request failed by a server error or the connection
+    retries: u32,
+    /// Locked while the session is refreshed, so that concurrent requests
+    /// refresh it once
+    session_refresh: tokio::sync::Mutex<Option<SessionRefresh>>,
+    /// The key which the OAuth access token is bound to
+    dpop_key_opt: Option<DpopKey>,
+    /// The last DPoP nonce which the PDS gives
+    dpop_nonce: Mutex<Option<String>>,
 }
 
 impl XrpcReqwestClient {
@@ -16,17 +75,428 @@ impl XrpcReqwestClient {
             host,
             access_jwt: None,
             access_did: None,
+            remote_content_client: client.clone(),
             client,
             dry_run,
+            remote_content_timeout: Duration::from_secs(30),
+            remote_content_retries: 0,
+            remote_content_max_bytes: usize::MAX,
+            remote_content_hosts: Default::default(),
+            rate_limit: Mutex::new(None),
+            max_rate_limit_wait: Duration::from_secs(300),
+            retries: 0,
+            session_refresh: tokio::sync::Mutex::new(None),
+            dpop_key_opt: None,
+            dpop_nonce: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn reqwest_client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Refresh the session by the refresh token when the access token expires
+    /// in the middle of a run, and retry the request. The tokens of an OAuth
+    /// session are refreshed by its authorization server.
+    pub fn set_session_refresh(
+        &mut self,
+        refresh_jwt: String,
+        oauth_opt: Option<OAuthSession>,
+        on_refreshed: OnSessionRefreshed,
+    ) {
+        self.session_refresh = tokio::sync::Mutex::new(Some(SessionRefresh {
+            access_jwt_opt: None,
+            refresh_jwt,
+            oauth_opt,
+            on_refreshed,
+        }));
+    }
+
+    /// Send the access token with DPoP proofs by the key, as the OAuth session
+    /// requires.
+    pub fn set_dpop_key(&mut self, dpop_key_opt: Option<DpopKey>) {
+        self.dpop_key_opt = dpop_key_opt;
+        *self.dpop_nonce.lock().unwrap() = None;
+    }
+
+    /// Send the bearer token as a DPoP-bound token with its proof, if the
+    /// DPoP key is set.
+    fn bind_dpop(&self, req: &mut reqwest::Request) -> Result<(), Box<dyn Error>> {
+        let Some(dpop_key) = &self.dpop_key_opt else {
+            return Ok(());
+        };
+        let access_token_opt = req
+            .headers()
+            .get(reqwest::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string);
+        let Some(access_token) = access_token_opt else {
+            return Ok(());
+        };
+        let nonce_opt = self.dpop_nonce.lock().unwrap().clone();
+        let proof = dpop_key.proof(
+            req.method().as_str(),
+            req.url().as_str(),
+            nonce_opt.as_deref(),
+            Some(&access_token),
+        )?;
+        let headers = req.headers_mut();
+        headers.insert(
+            reqwest::header::AUTHORIZATION,
+            format!("DPoP {access_token}").parse()?,
+        );
+        headers.insert("DPoP", proof.parse()?);
+        Ok(())
+    }
+
+    /// Send the request, waiting for the rate limit and retrying transient
+    /// failures.
+    async fn execute(
+        &self,
+        req: &reqwest::Request,
+    ) -> Result<xrpc::http::Response<Vec<u8>>, Box<dyn Error>> {
+        // The budget used up by the last response is waited before sending.
+        let used_up_wait_opt = self
+            .rate_limit
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(RateLimit::wait);
+        if let Some(wait) = used_up_wait_opt {
+            self.wait_rate_limit(wait).await?;
+        }
+
+        if tracing::enabled!(Level::TRACE) {
+            let body = req.body().and_then(reqwest::Body::as_bytes);
+            tracing::trace!(
+                method = %req.method(),
+                path = req.url().path(),
+                body = body.map(redacted_body).unwrap_or_default(),
+                "Request"
+            );
+        }
+        let idempotent = is_idempotent(req);
+        let mut rate_limit_attempt = 0;
+        let mut nonce_retried = false;
+        let mut attempt = 0;
+        let res = loop {
+            let mut cloned_req = req
+                .try_clone()
+                .ok_or("Failed to retry the request with a streaming body")?;
+            // The proof is made for every attempt, by the latest nonce.
+            self.bind_dpop(&mut cloned_req)?;
+            let retryable = attempt < self.retries;
+            let started = std::time::Instant::now();
+            match self.client.execute(cloned_req).await {
+                Ok(res) => {
+                    tracing::info!(
+                        method = %req.method(),
+                        path = req.url().path(),
+                        status = %res.status(),
+                        elapsed = ?started.elapsed(),
+                        "XRPC"
+                    );
+                    if let Some(rate_limit) = RateLimit::from_headers(res.headers()) {
+                        *self.rate_limit.lock().unwrap() = Some(rate_limit);
+                    }
+                    if let Some(nonce) = res
+                        .headers()
+                        .get("DPoP-Nonce")
+                        .and_then(|value| value.to_str().ok())
+                    {
+                        *self.dpop_nonce.lock().unwrap() = Some(nonce.to_string());
+                    }
+                    if !nonce_retried && requires_dpop_nonce(&res) {
+                        nonce_retried = true;
+                        continue;
+                    }
+                    if res.status() == reqwest::StatusCode::TOO_MANY_REQUESTS
+                        && rate_limit_attempt < RATE_LIMIT_RETRIES
+                    {
+                        rate_limit_attempt += 1;
+                        self.wait_rate_limit(rate_limit::retry_wait(res.headers()))
+                            .await?;
+                        continue;
+                    }
+                    if !(retryable && idempotent && res.status().is_server_error()) {
+                        break res;
+                    }
+                    Event::new(
+                        Level::WARN,
+                        Action::Retry,
+                        format!(
+                            "Retry {} ({}/{}): status={}",
+                            req.url().path(),
+                            attempt + 1,
+                            self.retries,
+                            res.status()
+                        ),
+                    )
+                    .emit();
+                }
+                // Nothing is sent when the connection fails, so the request is
+                // retried even if it is not idempotent.
+                Err(err) if retryable && (err.is_connect() || idempotent && err.is_timeout()) => {
+                    Event::new(
+                        Level::WARN,
+                        Action::Retry,
+                        format!(
+                            "Retry {} ({}/{})",
+                            req.url().path(),
+                            attempt + 1,
+                            self.retries
+                        ),
+                    )
+                    .error(err)
+                    .emit();
+                }
+                Err(err) => Err(err)?,
+            }
+            tokio::time::sleep(RETRY_BASE_DELAY * 2u32.pow(attempt)).await;
+            attempt += 1;
+        };
+        let mut builder = xrpc::http::Response::builder().status(res.status());
+        for (k, v) in res.headers() {
+            builder = builder.header(k, v);
+        }
+        let body = res.bytes().await?.to_vec();
+        tracing::trace!(
+            path = req.url().path(),
+            body = redacted_body(&body),
+            "Response"
+        );
+        builder.body(body).map_err(Into::into)
+    }
+
+    /// Whether the request is sent by the access token set by `set_session`,
+    /// not by the refresh token.
+    fn sent_by_access_token(&self, req: &reqwest::Request) -> bool {
+        let sent_authorization = req
+            .headers()
+            .get(reqwest::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        match (&self.access_jwt, sent_authorization) {
+            (Some(access_jwt), Some(sent_authorization)) => {
+                sent_authorization == format!("Bearer {access_jwt}")
+            }
+            _ => false,
+        }
+    }
+
+    /// Send the request by the access token of the last refresh if any.
+    async fn authorize(&self, req: &mut reqwest::Request) {
+        let session_refresh = self.session_refresh.lock().await;
+        let access_jwt_opt = session_refresh
+            .as_ref()
+            .and_then(|session_refresh| session_refresh.access_jwt_opt.as_deref());
+        if let Some(access_jwt) = access_jwt_opt {
+            if let Ok(value) = format!("Bearer {access_jwt}").parse() {
+                req.headers_mut()
+                    .insert(reqwest::header::AUTHORIZATION, value);
+            }
+        }
+    }
+
+    /// Refresh the session unless it has been refreshed since the request was
+    /// sent by the expired token. Whether the request can be retried.
+    async fn refresh_expired_session(&self, req: &reqwest::Request) -> Result<bool, String> {
+        let mut session_refresh_opt = self.session_refresh.lock().await;
+        let Some(session_refresh) = session_refresh_opt.as_mut() else {
+            return Ok(false);
+        };
+        let sent_authorization = req
+            .headers()
+            .get(reqwest::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        if let Some(access_jwt) = &session_refresh.access_jwt_opt {
+            if sent_authorization != Some(&format!("Bearer {access_jwt}")) {
+                return Ok(true);
+            }
+        }
+
+        let refreshed = match &session_refresh.oauth_opt {
+            Some(oauth_session) => {
+                let did = self.access_did.as_deref().unwrap_or_default();
+                let tokens = oauth::refresh(
+                    &self.client,
+                    oauth_session,
+                    did,
+                    &session_refresh.refresh_jwt,
+                )
+                .await
+                .map_err(|err| format!("Failed to refresh session: {err}"))?;
+                RefreshedSession {
+                    access_jwt: tokens.access_token,
+                    refresh_jwt: tokens.refresh_token,
+                }
+            }
+            None => {
+                self.refresh_session_by_pds(&session_refresh.refresh_jwt)
+                    .await?
+            }
+        };
+        (session_refresh.on_refreshed)(&refreshed.access_jwt, &refreshed.refresh_jwt);
+        session_refresh.access_jwt_opt = Some(refreshed.access_jwt);
+        session_refresh.refresh_jwt = refreshed.refresh_jwt;
+        Ok(true)
+    }
+
+    async fn refresh_session_by_pds(&self, refresh_jwt: &str) -> Result<RefreshedSession, String> {
+        let res = self
+            .client
+            .post(format!(
+                "{}/xrpc/com.atproto.server.refreshSession",
+                self.host
+            ))
+            .bearer_auth(refresh_jwt)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|err| format!("Failed to refresh session: {err}"))?;
+        let body = res
+            .bytes()
+            .await
+            .map_err(|err| format!("Failed to refresh session: {err}"))?;
+        serde_json::from_slice(&body).map_err(|err| format!("Failed to refresh session: {err}"))
+    }
+
+    /// Retry the requests failed by a server error or the connection, with
+    /// exponential backoff. Requests which may have taken effect are retried
+    /// only if they are idempotent.
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Wait for the rate limit up to the duration, starting with the budget
+    /// left by the last run if any.
+    pub fn with_rate_limit(
+        mut self,
+        max_wait: Duration,
+        rate_limit_opt: Option<RateLimit>,
+    ) -> Self {
+        self.max_rate_limit_wait = max_wait;
+        self.rate_limit = Mutex::new(rate_limit_opt);
+        self
+    }
+
+    /// The budget of the requests left by the last response.
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit.lock().unwrap().clone()
+    }
+
+    /// Sleep for the wait unless it is longer than the limit.
+    async fn wait_rate_limit(&self, wait: Duration) -> Result<(), String> {
+        if wait > self.max_rate_limit_wait {
+            return Err(format!(
+                "Rate limited for {}s, over the max wait {}s",
+                wait.as_secs(),
+                self.max_rate_limit_wait.as_secs()
+            ));
+        }
+        Event::new(
+            Level::WARN,
+            Action::RateLimited,
+            format!("Rate limited, waiting {}s", wait.as_secs()),
+        )
+        .emit();
+        tokio::time::sleep(wait).await;
+        Ok(())
+    }
+
+    /// Set the timeout of each attempt and the number of retries to get remote
+    /// contents such as media, independently of the XRPC requests.
+    pub fn with_remote_content_policy(mut self, timeout: Duration, retries: u32) -> Self {
+        self.remote_content_timeout = timeout;
+        self.remote_content_retries = retries;
+        self
+    }
+
+    /// Set the maximum size of remote contents, over which the download is
+    /// aborted.
+    pub fn with_remote_content_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.remote_content_max_bytes = max_bytes;
+        self
+    }
+
+    /// Get remote contents by the client, checking the URLs by the policy.
+    pub fn with_remote_content_client(
+        mut self,
+        client: reqwest::Client,
+        hosts: MediaHostPolicy,
+    ) -> Self {
+        self.remote_content_client = client;
+        self.remote_content_hosts = hosts;
+        self
+    }
+
+    async fn try_get_remote_content(&self, url: &str) -> Result<RemoteContent, Box<dyn Error>> {
+        let url = reqwest::Url::parse(url)?;
+        self.remote_content_hosts.check_url(&url)?;
+        let started = std::time::Instant::now();
+        let mut res = self
+            .remote_content_client
+            .get(url.clone())
+            .timeout(self.remote_content_timeout)
+            .send()
+            .await?;
+        let status = res.status();
+        tracing::info!(
+            url = url.as_str(),
+            status = %status,
+            elapsed = ?started.elapsed(),
+            "GET"
+        );
+        if status == 200 {
+            let max_bytes = self.remote_content_max_bytes;
+            if let Some(length) = res.content_length() {
+                if length > max_bytes as u64 {
+                    Err(format!(
+                        "Too large content: {length} bytes over {max_bytes}"
+                    ))?;
+                }
+            }
+            let content_type_opt = res
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            // The length header may be missing or wrong, so the body is
+            // checked while it is read.
+            let mut content = bytes::BytesMut::new();
+            while let Some(chunk) = res.chunk().await? {
+                if content.len() + chunk.len() > max_bytes {
+                    Err(format!("Too large content: over {max_bytes} bytes"))?;
+                }
+                content.extend_from_slice(&chunk);
+            }
+            Ok(RemoteContent {
+                content: content.freeze(),
+                content_type_opt,
+            })
+        } else {
+            let res_text = res.text().await;
+            Err(format!(
+                "Respond not ok: status={}, body={:?}",
+                status, res_text
+            ))?
         }
     }
 }
 
+/// A content downloaded from a remote server.
+pub struct RemoteContent {
+    pub content: bytes::Bytes,
+    /// The `Content-Type` header
+    pub content_type_opt: Option<String>,
+}
+
 #[async_trait]
 pub trait XrpcHttpClient: xrpc::HttpClient + xrpc::XrpcClient {
-    fn set_session(&mut self, jwt: String, did: String) -> ();
+    fn set_session(&mut self, jwt: String, did: String);
     fn current_did(&self) -> Option<&str>;
-    async fn get_remote_content(&self, url: &str) -> Result<bytes::Bytes, Box<dyn Error>>;
+    async fn get_remote_content(&self, url: &str) -> Result<RemoteContent, Box<dyn Error>>;
 }
 
 #[async_trait]
@@ -35,18 +505,21 @@ impl xrpc::HttpClient for XrpcReqwestClient {
         &self,
         req: xrpc::http::Request<Vec<u8>>,
     ) -> Result<xrpc::http::Response<Vec<u8>>, Box<dyn Error>> {
-        let res = if self.dry_run {
-            Err(format!("Enabled dry run mode."))?
-        } else {
-            self.client.execute(req.try_into()?).await?
-        };
-        let mut builder = xrpc::http::Response::builder().status(res.status());
-        for (k, v) in res.headers() {
-            builder = builder.header(k, v);
+        if self.dry_run {
+            Err("Enabled dry run mode.")?
         }
-        builder
-            .body(res.bytes().await?.to_vec())
-            .map_err(Into::into)
+        let mut req: reqwest::Request = req.try_into()?;
+
+        let by_access_token = self.sent_by_access_token(&req);
+        if by_access_token {
+            self.authorize(&mut req).await;
+        }
+        let mut res = self.execute(&req).await?;
+        if by_access_token && is_expired_token(&res) && self.refresh_expired_session(&req).await? {
+            self.authorize(&mut req).await;
+            res = self.execute(&req).await?;
+        }
+        Ok(res)
     }
 }
 
@@ -69,26 +542,182 @@ impl XrpcHttpClient for XrpcReqwestClient {
     fn set_session(&mut self, jwt: String, did: String) {
         self.access_jwt = Some(jwt);
         self.access_did = Some(did);
+        // The refresh of the previous session is not for the new one.
+        self.session_refresh = tokio::sync::Mutex::new(None);
     }
 
-    async fn get_remote_content(&self, url: &str) -> Result<bytes::Bytes, Box<dyn Error>> {
-        let res = if self.dry_run {
-            Err(format!("Enabled dry run mode."))?
-        } else {
-            let req = reqwest::Request::new(reqwest::Method::GET, reqwest::Url::parse(url)?);
-            self.client.execute(req).await?
-        };
-        let status = res.status();
-        if status == 200 {
-            res.bytes().await.map_err(|err| err.into())
-        } else {
-            let res_text = res.text().await;
-            Err(format!(
-                "Respond not ok: status={}, body={:?}",
-                status, res_text
-            ))?
+    async fn get_remote_content(&self, url: &str) -> Result<RemoteContent, Box<dyn Error>> {
+        if self.dry_run {
+            Err("Enabled dry run mode.")?
+        }
+        let mut attempt = 0;
+        loop {
+            // The error is not `Send`, so it cannot be kept over the sleep.
+            let err = match self.try_get_remote_content(url).await {
+                Ok(content) => return Ok(content),
+                Err(err) => err.to_string(),
+            };
+            if attempt >= self.remote_content_retries {
+                return Err(format!("Failed to get {url}: {err}"))?;
+            }
+            attempt += 1;
+            Event::new(
+                Level::WARN,
+                Action::Retry,
+                format!(
+                    "Retry to get {url} ({attempt}/{})",
+                    self.remote_content_retries
+                ),
+            )
+            .error(err)
+            .emit();
+            tokio::time::sleep(Duration::from_secs(u64::from(attempt))).await;
+        }
+    }
+}
+
+/// The keys of the secrets in the XRPC payloads, which are not logged.
+const SECRET_KEYS: &[&str] = &[
+    "password",
+    "accessJwt",
+    "refreshJwt",
+    "access_token",
+    "refresh_token",
+    "code",
+    "code_verifier",
+];
+
+/// The body for the logs with the secrets redacted, or only its size unless it
+/// is JSON.
+fn redacted_body(body: &[u8]) -> String {
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(mut value) => {
+            redact_secrets(&mut value);
+            value.to_string()
+        }
+        Err(_) => format!("<{} bytes>", body.len()),
+    }
+}
+
+fn redact_secrets(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if SECRET_KEYS.contains(&key.as_str()) {
+                    *value = serde_json::Value::from("<redacted>");
+                } else {
+                    redact_secrets(value);
+                }
+            }
         }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact_secrets),
+        _ => {}
+    }
+}
+
+/// Whether the request has the same effect when it is sent twice, so that it
+/// can be retried after a failure in the middle.
+fn is_idempotent(req: &reqwest::Request) -> bool {
+    if req.method() == reqwest::Method::GET {
+        return true;
+    }
+    let body_opt = req
+        .body()
+        .and_then(reqwest::Body::as_bytes)
+        .and_then(|body| serde_json::from_slice::<serde_json::Value>(body).ok());
+    let has_field = |field: &str| {
+        body_opt
+            .as_ref()
+            .is_some_and(|body| body.get(field).is_some_and(|value| !value.is_null()))
+    };
+    match req.url().path().trim_start_matches("/xrpc/") {
+        // Blobs are addressed by their content.
+        "com.atproto.repo.uploadBlob" | "com.atproto.repo.deleteRecord" => true,
+        // A record swapped once fails to be swapped again.
+        "com.atproto.repo.putRecord" => !has_field("swapRecord"),
+        // A record of the given key is not created twice.
+        "com.atproto.repo.createRecord" => has_field("rkey"),
+        _ => false,
+    }
+}
+
+/// Whether the response is the error of the expired access token, which is
+/// `invalid_token` for an OAuth session.
+fn is_expired_token(res: &xrpc::http::Response<Vec<u8>>) -> bool {
+    let error_opt = serde_json::from_slice::<xrpc::ErrorResponseBody>(res.body())
+        .ok()
+        .and_then(|body| body.error);
+    match res.status() {
+        xrpc::http::StatusCode::BAD_REQUEST => error_opt.as_deref() == Some("ExpiredToken"),
+        xrpc::http::StatusCode::UNAUTHORIZED => error_opt.as_deref() == Some("invalid_token"),
+        _ => false,
     }
 }
 
+/// Whether the PDS rejects the DPoP proof to require its nonce, which it
+/// gives in the `DPoP-Nonce` header.
+fn requires_dpop_nonce(res: &reqwest::Response) -> bool {
+    res.status() == reqwest::StatusCode::UNAUTHORIZED
+        && res
+            .headers()
+            .get(reqwest::header::WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.contains("use_dpop_nonce"))
+}
+
 atrium_api::impl_traits!(XrpcReqwestClient);
+
+impl crate::post_record::CreatePost for XrpcReqwestClient {}
+
+impl crate::post_record::PutPost for XrpcReqwestClient {}
+
+impl crate::post_record::GetRecordRef for XrpcReqwestClient {}
+
+/// `com.atproto.repo.uploadBlob` with the MIME type of the content, which
+/// `upload_blob::UploadBlob` of atrium-api always sends as `*/*`.
+#[async_trait]
+pub trait UploadTypedBlob: xrpc::XrpcClient {
+    async fn upload_typed_blob(
+        &self,
+        content: Vec<u8>,
+        mime_type: &str,
+    ) -> Result<upload_blob::Output, Box<dyn Error>> {
+        let span = tracing::info_span!("upload_blob", mime_type, bytes = content.len());
+        let body = xrpc::XrpcClient::send::<upload_blob::Error>(
+            self,
+            xrpc::http::Method::POST,
+            "com.atproto.repo.uploadBlob",
+            None,
+            Some(content),
+            Some(mime_type.to_string()),
+        )
+        .instrument(span)
+        .await?;
+        serde_json::from_slice(&body).map_err(|e| e.into())
+    }
+}
+
+impl UploadTypedBlob for XrpcReqwestClient {}
+
+/// `app.bsky.feed.getAuthorFeed` as JSON, which `get_author_feed::GetAuthorFeed`
+/// of atrium-api fails to parse for the embeds newer than it.
+#[async_trait]
+pub trait GetAuthorFeedJson: xrpc::XrpcClient {
+    async fn get_author_feed_json(
+        &self,
+        params: &get_author_feed::Parameters,
+    ) -> Result<serde_json::Value, Box<dyn Error>> {
+        let body = xrpc::XrpcClient::send::<get_author_feed::Error>(
+            self,
+            xrpc::http::Method::GET,
+            "app.bsky.feed.getAuthorFeed",
+            Some(serde_urlencoded::to_string(params)?),
+            None,
+            None,
+        )
+        .await?;
+        serde_json::from_slice(&body).map_err(|e| e.into())
+    }
+}
+
+impl GetAuthorFeedJson for XrpcReqwestClient {}
@@ -0,0 +1,46 @@
+use mstdn_rss2bsky_post::bridge;
+use mstdn_rss2bsky_post::richtext::{self, ContentFormat, HtmlOptions};
+use std::path::Path;
+
+/// Render each HTML or Markdown file in `tests/fixtures/richtext` as
+/// `render-html` does, and compare it with the `.expected.txt` file beside it.
+#[test]
+fn richtext_fixtures() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/richtext");
+    let mut paths: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
+
+    let mut checked_count = 0;
+    let mut failures = vec![];
+    for path in paths {
+        let content_format = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("html") => ContentFormat::Html,
+            Some("md") => ContentFormat::Markdown,
+            _ => continue,
+        };
+        let content = std::fs::read_to_string(&path).unwrap();
+        let expected =
+            std::fs::read_to_string(path.with_extension("expected.txt")).unwrap_or_else(|err| {
+                panic!(
+                    "Failed to read the expected output of {}: {err}",
+                    path.display()
+                )
+            });
+        let text =
+            richtext::from_content(&content, content_format, &HtmlOptions::default()).unwrap();
+        let actual = bridge::describe_richtext(&text);
+        if actual != expected {
+            failures.push(format!(
+                "{}:\n--- expected\n{expected}--- actual\n{actual}",
+                path.display()
+            ));
+        }
+        checked_count += 1;
+    }
+
+    assert!(checked_count > 0, "No fixtures in {}", dir.display());
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}